@@ -1,3 +1,4 @@
+use crate::ledger::Pruning;
 use clap::{Parser, Subcommand};
 use ruc::*;
 
@@ -66,6 +67,12 @@ pub struct DaemonCfg {
     pub tendermint_home_dir: Option<String>,
     #[clap(long, help = "A field for EIP1559")]
     pub block_base_fee_per_gas: Option<u128>,
+    #[clap(
+        long,
+        default_value_t = Pruning::Archive,
+        help = "Pruning mode: archive, keep-last:<N> or keep-every:<N>"
+    )]
+    pub pruning: Pruning,
 
     #[clap(
         short = 'A',
@@ -1,15 +1,20 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, ops::Bound};
 
 use crate::{
-    ledger::{Log, VsVersion, MAIN_BRANCH_NAME},
-    {ethvm::State as EvmState, ledger::State as LedgerState},
+    ledger::{kept_branch_name, Log, VsVersion, MAIN_BRANCH_NAME},
+    {
+        ethvm::{Hardfork, State as EvmState},
+        ledger::State as LedgerState,
+    },
 };
 use ethereum_types::{Bloom, BloomInput};
 use primitive_types::{H160, H256, U256};
 use ruc::*;
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Sha3_256};
-use vsdb::{BranchName, ParentBranchName, ValueEn, VsMgmt};
+use sha3::{Digest, Keccak256, Sha3_256};
+use vsdb::{
+    BranchName, KeyEnDe, MapxVs, OrphanVs, ParentBranchName, ValueEn, ValueEnDe, VsMgmt,
+};
 use web3_rpc_core::types::BlockNumber;
 
 pub(crate) type BlockHeight = u64;
@@ -29,6 +34,15 @@ pub fn hash_sha3_256(contents: &[&[u8]]) -> Vec<u8> {
     hasher.finalize().to_vec()
 }
 
+/// hash function of ethereum, used for the evm transactions and the block headers
+pub fn hash_keccak_256(contents: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    for c in contents {
+        hasher.update(c);
+    }
+    hasher.finalize().to_vec()
+}
+
 /// block proposer address of tendermint ==> evm coinbase address
 pub fn tm_proposer_to_evm_format(addr: TmAddressRef) -> H160 {
     const LEN: usize = H160::len_bytes();
@@ -70,6 +84,23 @@ impl InitalContract {
 pub struct InitalState {
     pub addr_to_amount: BTreeMap<H160, U256>,
     pub inital_contracts: Vec<InitalContract>,
+    // activation height => hardfork
+    #[serde(default)]
+    pub hardforks: BTreeMap<BlockHeight, Hardfork>,
+}
+
+// Ensure the state at `height` has not been pruned.
+pub fn check_pruned(height: BlockHeight, evm_state: &EvmState) -> Result<()> {
+    let earliest = evm_state.earliest_height.get_value();
+    let kept = kept_branch_name(height);
+    if height < earliest && !evm_state.branch_exists(kept.as_str().into()) {
+        return Err(eg!(
+            "The state at height {} has been pruned, the earliest available: {}",
+            height,
+            earliest
+        ));
+    }
+    Ok(())
 }
 
 pub fn rollback_to_height(
@@ -78,27 +109,47 @@ pub fn rollback_to_height(
     evm_state: Option<&EvmState>,
     prefix: &str,
 ) -> Result<String> {
+    if let Some(s) = evm_state.or_else(|| ledger_state.map(|s| &s.evm)) {
+        check_pruned(height, s).c(d!())?;
+    }
+
     let new_branch_name;
 
     if height > 0 {
         let ver = VsVersion::new(height + 1, 0);
         new_branch_name = format!("{}_{}", prefix, height + 1);
+        let base_ver = VsVersion::block_end(height).encode_value();
+        let base_ver = base_ver.as_ref().into();
+
+        // the kept states have been copied out of the 'main' branch
+        let kept = kept_branch_name(height);
+        let kept_exists = evm_state
+            .map(|s| s.branch_exists(kept.as_str().into()))
+            .or_else(|| ledger_state.map(|s| s.branch_exists(kept.as_str().into())))
+            .unwrap_or_default();
+        let base_br = alt!(kept_exists, kept.as_bytes(), MAIN_BRANCH_NAME.0);
 
         if let Some(evm_state) = evm_state {
-            evm_state.branch_create_by_base_branch(
-                BranchName::from(new_branch_name.as_str()),
-                ParentBranchName::from(MAIN_BRANCH_NAME.0),
-            )?;
+            evm_state
+                .branch_create_by_base_branch_version(
+                    BranchName::from(new_branch_name.as_str()),
+                    ParentBranchName::from(base_br),
+                    base_ver,
+                )
+                .c(d!("State not found at height {}", height))?;
 
             evm_state.version_create_by_branch(
                 ver.encode_value().as_ref().into(),
                 BranchName::from(new_branch_name.as_str()),
             )?;
         } else if let Some(ledger_state) = ledger_state {
-            ledger_state.branch_create_by_base_branch(
-                BranchName::from(new_branch_name.as_str()),
-                ParentBranchName::from(MAIN_BRANCH_NAME.0),
-            )?;
+            ledger_state
+                .branch_create_by_base_branch_version(
+                    BranchName::from(new_branch_name.as_str()),
+                    ParentBranchName::from(base_br),
+                    base_ver,
+                )
+                .c(d!("State not found at height {}", height))?;
 
             ledger_state.version_create_by_branch(
                 ver.encode_value().as_ref().into(),
@@ -112,6 +163,56 @@ pub fn rollback_to_height(
     Ok(new_branch_name)
 }
 
+// Entries visited in one round of `scan_by_branch`.
+const SCAN_BATCH: usize = 1024;
+
+// Visit all the entries of a map on the branch, a batch at a time,
+// so the map can be written in `f`.
+pub(crate) fn scan_by_branch<K, V, F>(
+    m: &MapxVs<K, V>,
+    b: BranchName,
+    mut f: F,
+) -> Result<()>
+where
+    K: KeyEnDe + Clone + 'static,
+    V: ValueEnDe,
+    F: FnMut(K, V) -> Result<()>,
+{
+    let mut start = Bound::Unbounded;
+    loop {
+        let batch = m
+            .range_by_branch((start, Bound::Unbounded), b)
+            .take(SCAN_BATCH)
+            .collect::<Vec<_>>();
+        let last = match batch.last() {
+            Some((k, _)) => k.clone(),
+            None => return Ok(()),
+        };
+        for (k, v) in batch.into_iter() {
+            f(k, v).c(d!())?;
+        }
+        start = Bound::Excluded(last);
+    }
+}
+
+// Write the entries that the branch reads from its base branch onto itself,
+// then they will not be affected by the pruning of the base branch.
+pub(crate) fn detach_map<K, V>(m: &MapxVs<K, V>, b: BranchName) -> Result<()>
+where
+    K: KeyEnDe + Clone + 'static,
+    V: ValueEnDe,
+{
+    scan_by_branch(m, b, |k, v| m.insert_by_branch(k, v, b).c(d!()).map(|_| ()))
+}
+
+// The same as `detach_map`, for a single value.
+pub(crate) fn detach_value<T: ValueEnDe>(v: &OrphanVs<T>, b: BranchName) -> Result<()> {
+    if let Some(value) = v.get_value_by_branch(b) {
+        v.set_value_by_branch(value, b).c(d!())?;
+    }
+    Ok(())
+}
+
 pub fn block_number_to_height(
     bn: Option<BlockNumber>,
     ledger_state: Option<&LedgerState>,
@@ -128,35 +229,21 @@ pub fn block_number_to_height(
             hash,
             require_canonical: _,
         } => {
-            let mut h = 0;
-            if let Some(evm_state) = evm_state {
-                for (height, block_hash) in evm_state.block_hashes.iter() {
-                    if block_hash == hash {
-                        h = height;
-                        break;
-                    }
-                }
-            } else if let Some(ledger_state) = ledger_state {
-                for (height, block) in ledger_state.blocks.iter() {
-                    if block.header_hash == hash.as_bytes() {
-                        h = height;
-                        break;
-                    }
-                }
-            }
-
-            h
+            let evm_state = evm_state.or_else(|| ledger_state.map(|s| &s.evm));
+            evm_state
+                .and_then(|s| s.block_heights.get(&hash))
+                .unwrap_or_default()
         }
         BlockNumber::Num(num) => num,
         BlockNumber::Latest => {
             let mut h = 0;
 
             if let Some(evm_state) = evm_state {
-                if let Some((height, _)) = evm_state.block_hashes.iter().last() {
+                if let Some((height, _)) = evm_state.block_hashes.last() {
                     h = height;
                 }
             } else if let Some(ledger_state) = ledger_state {
-                if let Some((height, _)) = ledger_state.blocks.iter().last() {
+                if let Some((height, _)) = ledger_state.blocks.last() {
                     h = height;
                 }
             }
@@ -4,11 +4,11 @@
 
 #![allow(warnings)]
 
-use crate::ledger::State;
+use crate::ledger::{State, MAIN_BRANCH_NAME};
 use crate::{
     cfg::DaemonCfg as Cfg,
     common::{BlockHeight, HashValue},
-    ethvm::{tx::inital_create2, OvrAccount},
+    ethvm::tx::inital_create2,
     ledger::{Ledger, Receipt},
     tx::Tx,
     InitalState,
@@ -16,11 +16,15 @@ use crate::{
 use abci::Application;
 use primitive_types::{H160, U256};
 use ruc::*;
-use std::{collections::BTreeMap, fmt::format};
-use tmtypes::abci::{
-    RequestBeginBlock, RequestCheckTx, RequestDeliverTx, RequestEndBlock, RequestInfo,
-    RequestInitChain, ResponseBeginBlock, ResponseCheckTx, ResponseCommit,
-    ResponseDeliverTx, ResponseEndBlock, ResponseInfo, ResponseInitChain,
+use std::{collections::BTreeMap, fmt::format, process};
+use tmtypes::{
+    abci::{
+        BlockParams, ConsensusParams, EvidenceType, RequestBeginBlock, RequestCheckTx,
+        RequestDeliverTx, RequestEndBlock, RequestInfo, RequestInitChain,
+        ResponseBeginBlock, ResponseCheckTx, ResponseCommit, ResponseDeliverTx,
+        ResponseEndBlock, ResponseInfo, ResponseInitChain, ValidatorUpdate,
+    },
+    crypto::{public_key::Sum, PublicKey},
 };
 use vsdb::MapxOrd;
 
@@ -47,11 +51,13 @@ impl App {
 
     pub fn load_or_create(cfg: Cfg) -> Result<Self> {
         cfg.set_vsdb_base_dir().c(d!())?;
-        if let Some(ledger) = Ledger::load_from_snapshot().c(d!())? {
-            Ok(Self { cfg, ledger })
+        let app = if let Some(ledger) = Ledger::load_from_snapshot().c(d!())? {
+            Self { cfg, ledger }
         } else {
-            Self::new(cfg).c(d!())
-        }
+            Self::new(cfg).c(d!())?
+        };
+        app.ledger.set_pruning(app.cfg.pruning);
+        Ok(app)
     }
 
     #[inline(always)]
@@ -82,7 +88,7 @@ impl Application for App {
 
         resp.last_block_height = h;
         if 0 < h {
-            resp.last_block_app_hash = b.header_hash;
+            resp.last_block_app_hash = b.header.state_root;
         }
 
         println!("\n\n");
@@ -95,28 +101,44 @@ impl Application for App {
     }
 
     fn init_chain(&self, req: RequestInitChain) -> ResponseInitChain {
+        let validators = req
+            .validators
+            .iter()
+            .filter_map(|v| {
+                let power = v.power as u64;
+                match v.pub_key.as_ref().and_then(|pk| pk.sum.as_ref()) {
+                    Some(Sum::Ed25519(pk)) => Some((pk.clone(), power)),
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        let staking = &self.ledger.state.staking;
+        let token = &self.ledger.state.evm.OFUEL;
+        pnk!(staking.init_genesis(token, validators, MAIN_BRANCH_NAME));
+
         if !req.app_state_bytes.is_empty() {
             let inital_state =
                 pnk!(serde_json::from_slice::<InitalState>(&req.app_state_bytes));
 
+            pnk!(
+                self.ledger
+                    .state
+                    .evm
+                    .hardforks
+                    .set_value_by_branch(inital_state.hardforks, MAIN_BRANCH_NAME)
+            );
+
             let token_distribution = inital_state.addr_to_amount;
 
             for (addr, am) in token_distribution.into_iter() {
-                pnk!(
-                    self.ledger
-                        .state
-                        .evm
-                        .OFUEL
-                        .accounts
-                        .insert(addr, OvrAccount::from_balance(am))
-                );
+                pnk!(token.mint(addr, am, MAIN_BRANCH_NAME));
             }
 
             let b = self.ledger.main.read().branch.clone();
             let b = b.as_slice().into();
 
             for contract in inital_state.inital_contracts {
-                pnk!(inital_create2(contract, &self.ledger.state.evm, b));
+                pnk!(inital_create2(contract, &self.ledger.state, b));
             }
         }
         ResponseInitChain::default()
@@ -129,6 +151,14 @@ impl Application for App {
         match Tx::deserialize(&req.tx) {
             Ok(tx) => {
                 if tx.valid_in_abci() {
+                    // used by tendermint to limit the `max_gas` of a block
+                    let gas_wanted = tx.gas_limit();
+                    resp.gas_wanted = alt!(
+                        gas_wanted > U256::from(i64::MAX as u64),
+                        i64::MAX,
+                        gas_wanted.as_u64() as i64
+                    );
+
                     let mut sb = self.ledger.check_tx.write();
                     if let Err(e) = info!(sb.apply_tx(tx)) {
                         resp.log = e.to_string();
@@ -156,6 +186,46 @@ impl Application for App {
 
         pnk!(self.ledger.consensus_refresh(header.proposer_address, ts));
 
+        // nothing of this block has been committed,
+        // the new binary will start from the beginning of it
+        if let Some(plan) = pnk!(self.ledger.upgrade()) {
+            // the metadata of the last block has been written in its 'Commit'
+            vsdb::vsdb_flush();
+            pd!(format!(
+                "UPGRADE NEEDED: `{}` at height {}, info: {}",
+                plan.name, height, plan.info
+            ));
+            // tendermint will stop after losing the connection,
+            // exit non-zero, so the supervisor will not take it as a normal stop
+            process::exit(1);
+        }
+
+        let double_signers = req
+            .byzantine_validators
+            .into_iter()
+            .filter_map(|e| {
+                // only the duplicate votes can be attributed to a validator,
+                // the light client attacks are ignored
+                if EvidenceType::DuplicateVote as i32 != e.r#type {
+                    println!("unhandled evidence: {:?}", e);
+                    return None;
+                }
+                e.validator.map(|v| (v.address, e.height as BlockHeight))
+            })
+            .collect();
+        let votes = req
+            .last_commit_info
+            .map(|info| {
+                info.votes
+                    .into_iter()
+                    .filter_map(|v| {
+                        v.validator.map(|i| (i.address, v.signed_last_block))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        pnk!(self.ledger.slash_and_jail(double_signers, votes));
+
         info_omit!(self.btm_snapshot(height));
 
         ResponseBeginBlock::default()
@@ -183,16 +253,40 @@ impl Application for App {
         resp
     }
 
-    // TODO: staking related logic
     fn end_block(&self, _req: RequestEndBlock) -> ResponseEndBlock {
-        ResponseEndBlock::default()
+        let mut resp = ResponseEndBlock::default();
+
+        let ret = pnk!(self.ledger.end_block());
+
+        resp.validator_updates = ret
+            .validator_updates
+            .into_iter()
+            .map(|v| ValidatorUpdate {
+                pub_key: Some(PublicKey {
+                    sum: Some(Sum::Ed25519(v.pubkey)),
+                }),
+                power: v.power as i64,
+            })
+            .collect();
+
+        if let Some(bp) = ret.block_params {
+            resp.consensus_param_updates = Some(ConsensusParams {
+                block: Some(BlockParams {
+                    max_bytes: bp.max_bytes,
+                    max_gas: bp.max_gas,
+                }),
+                ..Default::default()
+            });
+        }
+
+        resp
     }
 
     fn commit(&self) -> ResponseCommit {
         pnk!(self.ledger.commit());
 
         let mut r = ResponseCommit::default();
-        r.data = self.ledger.main.read().last_block_hash();
+        r.data = self.ledger.main.read().last_state_root();
         r
     }
 }
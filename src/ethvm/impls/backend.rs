@@ -7,10 +7,12 @@
 use crate::{
     common::BlockHeight,
     ethvm::{OvrAccount, OvrVicinity},
+    ledger::smt::{self, ChangeSet},
 };
 use evm::backend::{Apply, ApplyBackend, Backend, Basic, Log};
 use primitive_types::{H160, H256, U256};
 use ruc::*;
+use std::collections::BTreeMap;
 use vsdb::{BranchName, MapxDkVs, MapxOrd, MapxVs};
 
 // Ovr backend, storing all state values in vsdb.
@@ -19,15 +21,82 @@ pub struct OvrBackend<'a> {
     pub(crate) branch: BranchName<'a>,
     pub(crate) state: MapxVs<H160, OvrAccount>,
     pub(crate) storages: MapxDkVs<H160, H256, H256>,
+    pub(crate) storage_slots: MapxVs<(H160, H256), ()>,
+    // (owner addr, spender addr) => amount
+    pub(crate) allowances: MapxVs<(H160, H160), U256>,
     pub(crate) block_hashes: MapxOrd<BlockHeight, H256>,
     pub(crate) vicinity: OvrVicinity,
+    // the changed accounts, storages and allowances, for the state root
+    pub(crate) changes: ChangeSet,
 }
 
 impl<'a> OvrBackend<'a> {
-    #[inline(always)]
     fn reset_storage(&self, target: H160, b: BranchName) {
+        // the hex encoded keys have the same lengths,
+        // so all the slots of `target` are in this range
+        let slots = self
+            .storage_slots
+            .range_by_branch(
+                (target, H256::zero())..=(target, H256::repeat_byte(u8::MAX)),
+                b,
+            )
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        for (addr, index) in slots.into_iter() {
+            self.storage_remove(addr, index, b);
+        }
         pnk!(self.storages.remove_by_branch(&(&target, None), b));
     }
+
+    // A cleared slot is the same as a never set one.
+    #[inline(always)]
+    fn storage_remove(&self, address: H160, index: H256, b: BranchName) {
+        let k = smt::storage_key(&address, &index);
+        pnk!(self.changes.stage(k, H256::zero(), b));
+        pnk!(self.storages.remove_by_branch(&(&address, Some(&index)), b));
+        pnk!(self.storage_slots.remove_by_branch(&(address, index), b));
+    }
+
+    #[inline(always)]
+    fn account_set(&self, address: H160, account: OvrAccount) {
+        let leaf = smt::account_leaf(&account);
+        pnk!(
+            self.changes
+                .stage(smt::account_key(&address), leaf, self.branch)
+        );
+        pnk!(self.state.insert_by_branch(address, account, self.branch));
+    }
+
+    #[inline(always)]
+    fn account_remove(&self, address: H160) {
+        let k = smt::account_key(&address);
+        pnk!(self.changes.stage(k, H256::zero(), self.branch));
+        pnk!(self.state.remove_by_branch(&address, self.branch));
+    }
+
+    #[inline(always)]
+    pub(crate) fn allowance(&self, owner: H160, spender: H160) -> U256 {
+        self.allowances
+            .get_by_branch(&(owner, spender), self.branch)
+            .unwrap_or_default()
+    }
+
+    // Apply the allowances changed by the OFUEL precompile.
+    pub(crate) fn apply_allowances(&mut self, allowances: BTreeMap<(H160, H160), U256>) {
+        for (k, v) in allowances.into_iter() {
+            let leaf = alt!(v.is_zero(), H256::zero(), smt::record_leaf(&v));
+            pnk!(self.changes.stage(
+                smt::record_key(b"allowance", &k),
+                leaf,
+                self.branch
+            ));
+            if v.is_zero() {
+                pnk!(self.allowances.remove_by_branch(&k, self.branch));
+            } else {
+                pnk!(self.allowances.insert_by_branch(k, v, self.branch));
+            }
+        }
+    }
 }
 
 impl<'a> Backend for OvrBackend<'a> {
@@ -150,8 +219,18 @@ impl<'a> ApplyBackend for OvrBackend<'a> {
                         }
 
                         for (index, value) in storage {
-                            if value != H256::default() {
+                            if value == H256::default() {
+                                self.storage_remove(address, index, self.branch);
+                            } else {
+                                let k = smt::storage_key(&address, &index);
+                                let leaf = smt::storage_leaf(&value);
+                                pnk!(self.changes.stage(k, leaf, self.branch));
                                 self.storages.insert((address, index), value).unwrap();
+                                pnk!(self.storage_slots.insert_by_branch(
+                                    (address, index),
+                                    (),
+                                    self.branch
+                                ));
                             }
                         }
 
@@ -159,19 +238,18 @@ impl<'a> ApplyBackend for OvrBackend<'a> {
                             && account.nonce == U256::zero()
                             && account.code.is_empty();
 
-                        self.state
-                            .insert_by_branch(address, account, self.branch)
-                            .unwrap();
+                        self.account_set(address, account);
 
                         ret
                     };
 
                     if is_empty && delete_empty {
-                        self.state.remove_by_branch(&address, self.branch).unwrap();
+                        self.account_remove(address);
                     }
                 }
                 Apply::Delete { address } => {
-                    self.state.remove_by_branch(&address, self.branch).unwrap();
+                    self.reset_storage(address, self.branch);
+                    self.account_remove(address);
                 }
             }
         }
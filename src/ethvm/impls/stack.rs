@@ -4,6 +4,7 @@
 //! Ported from [evm](evm::executor::stack::memory).
 //!
 
+use crate::ledger::staking::EvmOp as StakingOp;
 use evm::{
     backend::{Apply, Backend, Basic, Log},
     executor::stack::{Accessed, StackState, StackSubstateMetadata},
@@ -11,10 +12,30 @@ use evm::{
 };
 use primitive_types::{H160, H256, U256};
 use std::{
+    cell::RefCell,
     collections::{BTreeMap, BTreeSet},
-    mem,
+    iter, mem,
+    rc::Rc,
 };
 
+// Changes made by the stateful precompiles,
+// they will be applied after a successful execution.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PrecompileChanges {
+    // (owner addr, spender addr) => amount, by the OFUEL precompile
+    pub(crate) allowances: BTreeMap<(H160, H160), U256>,
+    // by the staking precompile, in the order of execution
+    pub(crate) staking_ops: Vec<StakingOp>,
+}
+
+impl PrecompileChanges {
+    #[inline(always)]
+    fn append(&mut self, other: &mut Self) {
+        self.allowances.append(&mut other.allowances);
+        self.staking_ops.append(&mut other.staking_ops);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct OvrStackAccount {
     pub(crate) basic: Basic,
@@ -22,27 +43,22 @@ pub(crate) struct OvrStackAccount {
     pub(crate) reset: bool,
 }
 
-#[derive(Clone, Debug)]
-pub(crate) struct OvrStackSubstate<'config> {
-    metadata: StackSubstateMetadata<'config>,
-    parent: Option<Box<OvrStackSubstate<'config>>>,
+// The metadata is not kept here,
+// so this can be shared with the stateful precompiles.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OvrStackSubstate {
+    parent: Option<Box<OvrStackSubstate>>,
     accounts: BTreeMap<H160, OvrStackAccount>,
     storages: BTreeMap<(H160, H256), H256>,
     deletes: BTreeSet<H160>,
     logs: Vec<Log>,
+    precompile_changes: PrecompileChanges,
 }
 
-impl<'config> OvrStackSubstate<'config> {
+impl OvrStackSubstate {
     #[inline(always)]
-    pub(crate) fn new(metadata: StackSubstateMetadata<'config>) -> Self {
-        Self {
-            metadata,
-            parent: None,
-            logs: Vec::new(),
-            accounts: BTreeMap::new(),
-            storages: BTreeMap::new(),
-            deletes: BTreeSet::new(),
-        }
+    pub(crate) fn new() -> Self {
+        Self::default()
     }
 
     // pub(crate) fn logs(&self) -> &[Log] {
@@ -53,23 +69,17 @@ impl<'config> OvrStackSubstate<'config> {
     //     &mut self.logs
     // }
 
-    #[inline(always)]
-    pub(crate) fn metadata(&self) -> &StackSubstateMetadata<'config> {
-        &self.metadata
-    }
-
-    #[inline(always)]
-    pub(crate) fn metadata_mut(&mut self) -> &mut StackSubstateMetadata<'config> {
-        &mut self.metadata
-    }
-
     // Deconstruct the executor, return state to be applied. Panic if the
     // executor is not in the top-level substate.
     #[must_use]
     pub(crate) fn deconstruct<B: Backend>(
-        mut self,
+        &mut self,
         backend: &B,
-    ) -> (Vec<Apply<BTreeMap<H256, H256>>>, Vec<Log>) {
+    ) -> (
+        Vec<Apply<BTreeMap<H256, H256>>>,
+        Vec<Log>,
+        PrecompileChanges,
+    ) {
         assert!(self.parent.is_none());
 
         let mut applies = Vec::<Apply<BTreeMap<H256, H256>>>::new();
@@ -112,33 +122,29 @@ impl<'config> OvrStackSubstate<'config> {
             applies.push(apply);
         }
 
-        for address in self.deletes {
+        for address in mem::take(&mut self.deletes) {
             applies.push(Apply::Delete { address });
         }
 
-        (applies, self.logs)
+        (
+            applies,
+            mem::take(&mut self.logs),
+            mem::take(&mut self.precompile_changes),
+        )
     }
 
     #[inline(always)]
-    pub(crate) fn enter(&mut self, gas_limit: u64, is_static: bool) {
-        let mut entering = Self {
-            metadata: self.metadata.spit_child(gas_limit, is_static),
-            parent: None,
-            logs: Vec::new(),
-            accounts: BTreeMap::new(),
-            storages: BTreeMap::new(),
-            deletes: BTreeSet::new(),
-        };
+    pub(crate) fn enter(&mut self) {
+        let mut entering = Self::new();
         mem::swap(&mut entering, self);
 
         self.parent = Some(Box::new(entering));
     }
 
-    pub(crate) fn exit_commit(&mut self) -> Result<(), ExitError> {
+    pub(crate) fn exit_commit(&mut self) {
         let mut exited = *self.parent.take().expect("Cannot commit on root substate");
         mem::swap(&mut exited, self);
 
-        self.metadata.swallow_commit(exited.metadata)?;
         self.logs.append(&mut exited.logs);
 
         let mut resets = BTreeSet::new();
@@ -160,22 +166,15 @@ impl<'config> OvrStackSubstate<'config> {
         self.accounts.append(&mut exited.accounts);
         self.storages.append(&mut exited.storages);
         self.deletes.append(&mut exited.deletes);
-
-        Ok(())
+        self.precompile_changes
+            .append(&mut exited.precompile_changes);
     }
 
+    // Used by both the reverting and the discarding.
     #[inline(always)]
-    pub(crate) fn exit_revert(&mut self) -> Result<(), ExitError> {
+    pub(crate) fn exit_discard(&mut self) {
         let mut exited = *self.parent.take().expect("Cannot discard on root substate");
         mem::swap(&mut exited, self);
-        self.metadata.swallow_revert(exited.metadata)
-    }
-
-    #[inline(always)]
-    pub(crate) fn exit_discard(&mut self) -> Result<(), ExitError> {
-        let mut exited = *self.parent.take().expect("Cannot discard on root substate");
-        mem::swap(&mut exited, self);
-        self.metadata.swallow_discard(exited.metadata)
     }
 
     #[inline(always)]
@@ -253,29 +252,38 @@ impl<'config> OvrStackSubstate<'config> {
     }
 
     #[inline(always)]
-    pub(crate) fn is_cold(&self, address: H160) -> bool {
-        self.recursive_is_cold(&|a| a.accessed_addresses.contains(&address))
+    pub(crate) fn known_allowance(&self, owner: H160, spender: H160) -> Option<U256> {
+        if let Some(amount) = self.precompile_changes.allowances.get(&(owner, spender)) {
+            Some(*amount)
+        } else if let Some(parent) = self.parent.as_ref() {
+            parent.known_allowance(owner, spender)
+        } else {
+            None
+        }
     }
 
     #[inline(always)]
-    pub(crate) fn is_storage_cold(&self, address: H160, key: H256) -> bool {
-        self.recursive_is_cold(&|a: &Accessed| {
-            a.accessed_storage.contains(&(address, key))
-        })
+    pub(crate) fn set_allowance(&mut self, owner: H160, spender: H160, amount: U256) {
+        self.precompile_changes
+            .allowances
+            .insert((owner, spender), amount);
+    }
+
+    // All the staking operations that have not been applied,
+    // from the oldest one to the newest one.
+    pub(crate) fn known_staking_ops(&self) -> Vec<&StakingOp> {
+        let mut ops = self
+            .parent
+            .as_ref()
+            .map(|p| p.known_staking_ops())
+            .unwrap_or_default();
+        ops.extend(self.precompile_changes.staking_ops.iter());
+        ops
     }
 
     #[inline(always)]
-    fn recursive_is_cold<F: Fn(&Accessed) -> bool>(&self, f: &F) -> bool {
-        let local_is_accessed =
-            self.metadata.accessed().as_ref().map(f).unwrap_or(false);
-        if local_is_accessed {
-            false
-        } else {
-            self.parent
-                .as_ref()
-                .map(|p| p.recursive_is_cold(f))
-                .unwrap_or(true)
-        }
+    pub(crate) fn push_staking_op(&mut self, op: StakingOp) {
+        self.precompile_changes.staking_ops.push(op);
     }
 
     #[inline(always)]
@@ -376,31 +384,31 @@ impl<'config> OvrStackSubstate<'config> {
         Ok(())
     }
 
-    // // Only needed for jsontests.
-    // pub(crate) fn withdraw<B: Backend>(
-    //     &mut self,
-    //     address: H160,
-    //     value: U256,
-    //     backend: &B,
-    // ) -> Result<(), ExitError> {
-    //     let source = self.account_mut(address, backend);
-    //     if source.basic.balance < value {
-    //         return Err(ExitError::OutOfFund);
-    //     }
-    //     source.basic.balance -= value;
-    //     Ok(())
-    // }
+    // Used by the staking precompile.
+    pub(crate) fn withdraw<B: Backend>(
+        &mut self,
+        address: H160,
+        value: U256,
+        backend: &B,
+    ) -> Result<(), ExitError> {
+        let source = self.account_mut(address, backend);
+        if source.basic.balance < value {
+            return Err(ExitError::OutOfFund);
+        }
+        source.basic.balance -= value;
+        Ok(())
+    }
 
-    // // Only needed for jsontests.
-    // pub(crate) fn deposit<B: Backend>(
-    //     &mut self,
-    //     address: H160,
-    //     value: U256,
-    //     backend: &B,
-    // ) {
-    //     let target = self.account_mut(address, backend);
-    //     target.basic.balance = target.basic.balance.saturating_add(value);
-    // }
+    // Used by the staking precompile.
+    pub(crate) fn deposit<B: Backend>(
+        &mut self,
+        address: H160,
+        value: U256,
+        backend: &B,
+    ) {
+        let target = self.account_mut(address, backend);
+        target.basic.balance = target.basic.balance.saturating_add(value);
+    }
 
     #[inline(always)]
     pub(crate) fn reset_balance<B: Backend>(&mut self, address: H160, backend: &B) {
@@ -413,10 +421,14 @@ impl<'config> OvrStackSubstate<'config> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub(crate) struct OvrStackState<'backend, 'config, B> {
     backend: &'backend B,
-    substate: OvrStackSubstate<'config>,
+    metadata: StackSubstateMetadata<'config>,
+    // metadata of the parent substates, the nearest one is the last one
+    parent_metadatas: Vec<StackSubstateMetadata<'config>>,
+    // shared with the stateful precompiles
+    substate: Rc<RefCell<OvrStackSubstate>>,
 }
 
 impl<'backend, 'config, B: Backend> Backend for OvrStackState<'backend, 'config, B> {
@@ -472,12 +484,14 @@ impl<'backend, 'config, B: Backend> Backend for OvrStackState<'backend, 'config,
 
     #[inline(always)]
     fn exists(&self, address: H160) -> bool {
-        self.substate.known_account(address).is_some() || self.backend.exists(address)
+        self.substate.borrow().known_account(address).is_some()
+            || self.backend.exists(address)
     }
 
     #[inline(always)]
     fn basic(&self, address: H160) -> Basic {
         self.substate
+            .borrow()
             .known_basic(address)
             .unwrap_or_else(|| self.backend.basic(address))
     }
@@ -485,6 +499,7 @@ impl<'backend, 'config, B: Backend> Backend for OvrStackState<'backend, 'config,
     #[inline(always)]
     fn code(&self, address: H160) -> Vec<u8> {
         self.substate
+            .borrow()
             .known_code(address)
             .unwrap_or_else(|| self.backend.code(address))
     }
@@ -492,13 +507,15 @@ impl<'backend, 'config, B: Backend> Backend for OvrStackState<'backend, 'config,
     #[inline(always)]
     fn storage(&self, address: H160, key: H256) -> H256 {
         self.substate
+            .borrow()
             .known_storage(address, key)
             .unwrap_or_else(|| self.backend.storage(address, key))
     }
 
     #[inline(always)]
     fn original_storage(&self, address: H160, key: H256) -> Option<H256> {
-        if let Some(value) = self.substate.known_original_storage(address, key) {
+        if let Some(value) = self.substate.borrow().known_original_storage(address, key)
+        {
             return Some(value);
         }
         self.backend.original_storage(address, key)
@@ -510,37 +527,54 @@ impl<'backend, 'config, B: Backend> StackState<'config>
 {
     #[inline(always)]
     fn metadata(&self) -> &StackSubstateMetadata<'config> {
-        self.substate.metadata()
+        &self.metadata
     }
 
     #[inline(always)]
     fn metadata_mut(&mut self) -> &mut StackSubstateMetadata<'config> {
-        self.substate.metadata_mut()
+        &mut self.metadata
     }
 
     #[inline(always)]
     fn enter(&mut self, gas_limit: u64, is_static: bool) {
-        self.substate.enter(gas_limit, is_static)
+        let entering = self.metadata.spit_child(gas_limit, is_static);
+        self.parent_metadatas
+            .push(mem::replace(&mut self.metadata, entering));
+        self.substate.borrow_mut().enter();
     }
 
-    #[inline(always)]
     fn exit_commit(&mut self) -> Result<(), ExitError> {
-        self.substate.exit_commit()
+        let exited = self.exit_metadata();
+        let ret = self.metadata.swallow_commit(exited);
+
+        // the changes will be discarded if the gas can not be swallowed
+        let mut substate = self.substate.borrow_mut();
+        if ret.is_ok() {
+            substate.exit_commit();
+        } else {
+            substate.exit_discard();
+        }
+
+        ret
     }
 
     #[inline(always)]
     fn exit_revert(&mut self) -> Result<(), ExitError> {
-        self.substate.exit_revert()
+        let exited = self.exit_metadata();
+        self.substate.borrow_mut().exit_discard();
+        self.metadata.swallow_revert(exited)
     }
 
     #[inline(always)]
     fn exit_discard(&mut self) -> Result<(), ExitError> {
-        self.substate.exit_discard()
+        let exited = self.exit_metadata();
+        self.substate.borrow_mut().exit_discard();
+        self.metadata.swallow_discard(exited)
     }
 
     #[inline(always)]
     fn is_empty(&self, address: H160) -> bool {
-        if let Some(known_empty) = self.substate.known_empty(address) {
+        if let Some(known_empty) = self.substate.borrow().known_empty(address) {
             return known_empty;
         }
         self.backend.basic(address).balance == U256::zero()
@@ -550,62 +584,70 @@ impl<'backend, 'config, B: Backend> StackState<'config>
 
     #[inline(always)]
     fn deleted(&self, address: H160) -> bool {
-        self.substate.deleted(address)
+        self.substate.borrow().deleted(address)
     }
 
     #[inline(always)]
     fn is_cold(&self, address: H160) -> bool {
-        self.substate.is_cold(address)
+        self.recursive_is_cold(&|a| a.accessed_addresses.contains(&address))
     }
 
     #[inline(always)]
     fn is_storage_cold(&self, address: H160, key: H256) -> bool {
-        self.substate.is_storage_cold(address, key)
+        self.recursive_is_cold(&|a: &Accessed| {
+            a.accessed_storage.contains(&(address, key))
+        })
     }
 
     #[inline(always)]
     fn inc_nonce(&mut self, address: H160) {
-        self.substate.inc_nonce(address, self.backend);
+        self.substate.borrow_mut().inc_nonce(address, self.backend);
     }
 
     #[inline(always)]
     fn set_storage(&mut self, address: H160, key: H256, value: H256) {
-        self.substate.set_storage(address, key, value)
+        self.substate.borrow_mut().set_storage(address, key, value)
     }
 
     #[inline(always)]
     fn reset_storage(&mut self, address: H160) {
-        self.substate.reset_storage(address, self.backend);
+        self.substate
+            .borrow_mut()
+            .reset_storage(address, self.backend);
     }
 
     #[inline(always)]
     fn log(&mut self, address: H160, topics: Vec<H256>, data: Vec<u8>) {
-        self.substate.log(address, topics, data);
+        self.substate.borrow_mut().log(address, topics, data);
     }
 
     #[inline(always)]
     fn set_deleted(&mut self, address: H160) {
-        self.substate.set_deleted(address)
+        self.substate.borrow_mut().set_deleted(address)
     }
 
     #[inline(always)]
     fn set_code(&mut self, address: H160, code: Vec<u8>) {
-        self.substate.set_code(address, code, self.backend)
+        self.substate
+            .borrow_mut()
+            .set_code(address, code, self.backend)
     }
 
     #[inline(always)]
     fn transfer(&mut self, transfer: Transfer) -> Result<(), ExitError> {
-        self.substate.transfer(transfer, self.backend)
+        self.substate.borrow_mut().transfer(transfer, self.backend)
     }
 
     #[inline(always)]
     fn reset_balance(&mut self, address: H160) {
-        self.substate.reset_balance(address, self.backend)
+        self.substate
+            .borrow_mut()
+            .reset_balance(address, self.backend)
     }
 
     #[inline(always)]
     fn touch(&mut self, address: H160) {
-        self.substate.touch(address, self.backend)
+        self.substate.borrow_mut().touch(address, self.backend)
     }
 }
 
@@ -617,14 +659,49 @@ impl<'backend, 'config, B: Backend> OvrStackState<'backend, 'config, B> {
     ) -> Self {
         Self {
             backend,
-            substate: OvrStackSubstate::new(metadata),
+            metadata,
+            parent_metadatas: vec![],
+            substate: Rc::new(RefCell::new(OvrStackSubstate::new())),
         }
     }
 
+    #[inline(always)]
+    pub(crate) fn backend(&self) -> &'backend B {
+        self.backend
+    }
+
+    // Share the substate with the stateful precompiles.
+    #[inline(always)]
+    pub(crate) fn substate(&self) -> Rc<RefCell<OvrStackSubstate>> {
+        Rc::clone(&self.substate)
+    }
+
     #[must_use]
     #[inline(always)]
-    pub(crate) fn deconstruct(self) -> (Vec<Apply<BTreeMap<H256, H256>>>, Vec<Log>) {
-        self.substate.deconstruct(self.backend)
+    pub(crate) fn deconstruct(
+        self,
+    ) -> (
+        Vec<Apply<BTreeMap<H256, H256>>>,
+        Vec<Log>,
+        PrecompileChanges,
+    ) {
+        self.substate.borrow_mut().deconstruct(self.backend)
+    }
+
+    #[inline(always)]
+    fn exit_metadata(&mut self) -> StackSubstateMetadata<'config> {
+        let parent = self
+            .parent_metadatas
+            .pop()
+            .expect("Cannot exit on root substate");
+        mem::replace(&mut self.metadata, parent)
+    }
+
+    #[inline(always)]
+    fn recursive_is_cold<F: Fn(&Accessed) -> bool>(&self, f: &F) -> bool {
+        !iter::once(&self.metadata)
+            .chain(self.parent_metadatas.iter().rev())
+            .any(|m| m.accessed().as_ref().map(f).unwrap_or(false))
     }
 
     // pub(crate) fn withdraw(
@@ -3,40 +3,62 @@ mod precompile;
 pub mod tx;
 
 use crate::{
-    common::{block_number_to_height, rollback_to_height, BlockHeight},
-    ethvm::{impls::stack::OvrStackState, precompile::PRECOMPILE_SET},
+    common::{block_number_to_height, detach_value, rollback_to_height, BlockHeight},
+    ethvm::{impls::stack::OvrStackState, precompile::OvrPrecompileSet},
+    ledger::staking::State as StakingState,
 };
 use evm::{
-    executor::stack::{StackExecutor, StackSubstateMetadata},
+    executor::stack::{StackExecutor, StackState, StackSubstateMetadata},
     ExitReason,
 };
 use impls::backend::OvrBackend;
 use primitive_types::{H160, H256, U256};
 use ruc::*;
 use serde::{Deserialize, Serialize};
-use tx::token::Erc20Like;
-use vsdb::{BranchName, MapxOrd, OrphanVs, Vs, VsMgmt};
+use std::{cmp::Ordering, collections::BTreeMap};
+use tx::{token::Erc20Like, GAS_PRICE_MIN};
+use vsdb::{basic::orphan::Orphan, BranchName, Mapx, MapxOrd, OrphanVs, Vs, VsMgmt};
 use web3_rpc_core::types::{BlockNumber, CallRequest};
 
+// The base fee can change at most `1 / 8` between two blocks.
+const BASE_FEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+
+// The gas target of a block is `block_gas_limit / ELASTICITY_MULTIPLIER`.
+const ELASTICITY_MULTIPLIER: u64 = 2;
+
 #[allow(non_snake_case)]
 #[derive(Vs, Clone, Debug, Deserialize, Serialize)]
 pub struct State {
+    // the min gas price, also the lower limit of the base fee
     pub gas_price: OrphanVs<U256>,
     pub block_gas_limit: OrphanVs<U256>,
     pub block_base_fee_per_gas: OrphanVs<U256>,
 
     pub OFUEL: Erc20Like,
 
+    // activation height => hardfork,
+    // `Hardfork::Istanbul` will be used before the first one
+    pub hardforks: OrphanVs<BTreeMap<BlockHeight, Hardfork>>,
+
+    // reject the legacy transactions that are signed without a chain id(pre EIP-155)
+    pub reject_unprotected_txs: OrphanVs<bool>,
+
     // Environmental block hashes.
     pub block_hashes: MapxOrd<BlockHeight, H256>,
+    // block hash => height, the reverse index of `block_hashes`
+    pub block_heights: Mapx<H256, BlockHeight>,
+    // the state before this height has been pruned
+    pub earliest_height: Orphan<BlockHeight>,
 
     // Oneshot values for each evm transaction.
     pub vicinity: OvrVicinity,
 }
 
 impl State {
+    // The staking state is used by the staking precompile.
     pub fn call_contract(
         &self,
+        staking: &StakingState,
         branch_name: BranchName,
         req: CallRequest,
         bn: Option<BlockNumber>,
@@ -45,14 +67,10 @@ impl State {
         let address = req.to.unwrap_or_default();
         let value = req.value.unwrap_or_default();
         let data = req.data.unwrap_or_default();
-        // This parameter is used as the divisor and cannot be 0
-        let gas_price = req.gas_price.unwrap_or_else(U256::one).as_u64();
-        let gas = if let Some(gas) = req.gas {
-            gas.as_u64()
-        } else {
-            u64::MAX
-        };
-        let gas_limit = gas.checked_div(gas_price).unwrap();
+        let gas_limit = req
+            .gas
+            .map(|gas| gas.try_into().unwrap_or(u64::MAX))
+            .unwrap_or(u64::MAX);
 
         let height = block_number_to_height(bn, None, Some(self));
 
@@ -63,15 +81,18 @@ impl State {
             branch: branch_name,
             state: self.OFUEL.accounts.clone(),
             storages: self.OFUEL.storages.clone(),
+            storage_slots: self.OFUEL.storage_slots.clone(),
+            allowances: self.OFUEL.allowances.clone(),
             block_hashes: self.block_hashes,
             vicinity: self.vicinity.clone(),
+            changes: self.OFUEL.changes.clone(),
         };
 
-        let cfg = evm::Config::istanbul();
-        let metadata = StackSubstateMetadata::new(u64::MAX, &cfg);
+        let cfg = self.hardfork(height, branch_name).config();
+        let metadata = StackSubstateMetadata::new(gas_limit, &cfg);
 
         let ovr_stack_state = OvrStackState::new(metadata, &backend);
-        let precompiles = PRECOMPILE_SET.clone();
+        let precompiles = OvrPrecompileSet::new(&self.OFUEL, staking, &ovr_stack_state);
         let mut executor =
             StackExecutor::new_with_precompiles(ovr_stack_state, &cfg, &precompiles);
 
@@ -83,21 +104,88 @@ impl State {
         let cc_resp = CallContractResp {
             evm_resp: resp.0,
             data: resp.1,
-            gas_used: executor.used_gas(),
+            gas_used: executor.state().metadata().gasometer().total_used_gas(),
         };
 
         self.branch_remove(BranchName::from(new_branch_name.as_str()))?;
         Ok(cc_resp)
     }
 
+    // See `ledger::State::keep`.
+    pub(crate) fn detach(&self, b: BranchName) -> Result<()> {
+        detach_value(&self.gas_price, b).c(d!())?;
+        detach_value(&self.block_gas_limit, b).c(d!())?;
+        detach_value(&self.block_base_fee_per_gas, b).c(d!())?;
+        detach_value(&self.hardforks, b).c(d!())?;
+        detach_value(&self.reject_unprotected_txs, b).c(d!())?;
+        self.OFUEL.detach(b).c(d!())
+    }
+
+    // The hardfork that is active at the given height.
+    #[inline(always)]
+    pub fn hardfork(&self, height: BlockHeight, b: BranchName) -> Hardfork {
+        self.hardforks
+            .get_value_by_branch(b)
+            .unwrap_or_default()
+            .range(..=height)
+            .next_back()
+            .map(|(_, fork)| *fork)
+            .unwrap_or_default()
+    }
+
+    // The base fee of the current block.
+    #[inline(always)]
+    pub fn base_fee(&self, b: BranchName) -> U256 {
+        let gas_price_min = self
+            .gas_price
+            .get_value_by_branch(b)
+            .unwrap_or(*GAS_PRICE_MIN);
+        self.block_base_fee_per_gas
+            .get_value_by_branch(b)
+            .unwrap_or_default()
+            .max(gas_price_min)
+    }
+
+    // Calculate the base fee of the next block according to EIP-1559,
+    // by comparing the gas used in the current block with the gas target.
+    pub(crate) fn update_base_fee(&self, gas_used: U256, b: BranchName) -> Result<()> {
+        let base_fee = self.base_fee(b);
+        let target = self
+            .block_gas_limit
+            .get_value_by_branch(b)
+            .unwrap_or_default()
+            / ELASTICITY_MULTIPLIER;
+        alt!(target.is_zero(), return Ok(()));
+
+        let denominator = target.saturating_mul(BASE_FEE_MAX_CHANGE_DENOMINATOR.into());
+        let next = match gas_used.cmp(&target) {
+            Ordering::Equal => base_fee,
+            Ordering::Greater => {
+                let delta = base_fee.saturating_mul(gas_used - target) / denominator;
+                base_fee.saturating_add(delta.max(U256::one()))
+            }
+            Ordering::Less => {
+                base_fee - base_fee.saturating_mul(target - gas_used) / denominator
+            }
+        };
+
+        self.block_base_fee_per_gas
+            .set_value_by_branch(next, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
     #[inline(always)]
     fn get_backend_hdr<'a>(&self, branch: BranchName<'a>) -> OvrBackend<'a> {
         OvrBackend {
             branch,
             state: self.OFUEL.accounts.clone(),
             storages: self.OFUEL.storages.clone(),
+            storage_slots: self.OFUEL.storage_slots.clone(),
+            allowances: self.OFUEL.allowances.clone(),
             block_hashes: self.block_hashes,
             vicinity: self.vicinity.clone(),
+            changes: self.OFUEL.changes.clone(),
         }
     }
 
@@ -120,7 +208,10 @@ impl State {
             block_timestamp,
             block_difficulty: U256::zero(),
             block_gas_limit: self.block_gas_limit.get_value(),
-            block_base_fee_per_gas: self.block_base_fee_per_gas.get_value(),
+            block_base_fee_per_gas: self
+                .block_base_fee_per_gas
+                .get_value()
+                .max(self.gas_price.get_value()),
         };
     }
 }
@@ -135,12 +226,43 @@ impl Default for State {
             block_gas_limit: OrphanVs::default(),
             block_base_fee_per_gas: OrphanVs::default(),
             OFUEL: Erc20Like::ofuel_token(),
+            hardforks: OrphanVs::default(),
+            reject_unprotected_txs: OrphanVs::default(),
             block_hashes: MapxOrd::new(),
+            block_heights: Mapx::new(),
+            earliest_height: Orphan::new(0),
             vicinity: OvrVicinity::default(),
         }
     }
 }
 
+/// The rules of evm, switched at the heights in `State::hardforks`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Hardfork {
+    Istanbul,
+    // EIP-2929, EIP-2930
+    Berlin,
+    // EIP-1559(the `BASEFEE` opcode), EIP-3529, EIP-3541
+    London,
+}
+
+impl Default for Hardfork {
+    fn default() -> Self {
+        Hardfork::Istanbul
+    }
+}
+
+impl Hardfork {
+    #[inline(always)]
+    pub fn config(self) -> evm::Config {
+        match self {
+            Hardfork::Istanbul => evm::Config::istanbul(),
+            Hardfork::Berlin => evm::Config::berlin(),
+            Hardfork::London => evm::Config::london(),
+        }
+    }
+}
+
 // Account information of a vsdb backend.
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct OvrAccount {
@@ -182,5 +304,7 @@ pub struct OvrVicinity {
 pub struct CallContractResp {
     pub evm_resp: ExitReason,
     pub data: Vec<u8>,
+    // including the intrinsic gas and the refunded part,
+    // so it can be used as the gas limit of the transaction
     pub gas_used: u64,
 }
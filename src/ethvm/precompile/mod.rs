@@ -2,7 +2,23 @@
 //! # Precompiled components(contracts)
 //!
 
-use evm::executor::stack::PrecompileFn;
+mod ofuel;
+mod staking;
+
+use crate::{
+    ethvm::{
+        impls::{backend::OvrBackend, stack::OvrStackState},
+        tx::token::Erc20Like,
+    },
+    ledger::staking::State as StakingState,
+};
+use evm::{
+    backend::Log,
+    executor::stack::{
+        PrecompileFailure, PrecompileFn, PrecompileOutput, PrecompileSet,
+    },
+    Context, ExitError, ExitRevert, ExitSucceed,
+};
 use fevm::Precompile;
 use fevm_precompile_blake2::Blake2F;
 use fevm_precompile_bn128::{Bn128Add, Bn128Mul, Bn128Pairing};
@@ -13,12 +29,23 @@ use fevm_precompile_sha3fips::Sha3FIPS256;
 use fevm_precompile_simple::{
     ECRecover, ECRecoverPublicKey, Identity, Ripemd160, Sha256,
 };
+use ofuel::Ofuel;
 use once_cell::sync::Lazy;
-use primitive_types::H160;
+use primitive_types::{H160, H256, U256};
 use ruc::*;
-use std::collections::BTreeMap;
+use sha3::{Digest, Keccak256};
+use staking::Staking;
+use std::{collections::BTreeMap, result::Result as StdResult};
+
+// `Error(string)`, the standard revert reason of solidity
+const SELECTOR_ERROR: u32 = 0x08c379a0;
 
-pub(crate) static PRECOMPILE_SET: Lazy<BTreeMap<H160, PrecompileFn>> = Lazy::new(|| {
+// The cost of the read-only functions of the stateful precompiles.
+const GAS_QUERY: u64 = 1_000;
+
+type PrecompileResult = StdResult<PrecompileOutput, PrecompileFailure>;
+
+static PRECOMPILE_SET: Lazy<BTreeMap<H160, PrecompileFn>> = Lazy::new(|| {
     map! {B
         idx_to_h160(1) => ECRecover::execute as PrecompileFn,
         idx_to_h160(2) => Sha256::execute,
@@ -41,3 +68,153 @@ pub(crate) static PRECOMPILE_SET: Lazy<BTreeMap<H160, PrecompileFn>> = Lazy::new
 pub(crate) fn idx_to_h160(i: u64) -> H160 {
     H160::from_low_u64_be(i)
 }
+
+// The stateless precompiles above,
+// and the stateful ones that share the substate with the executor.
+pub(crate) struct OvrPrecompileSet<'backend, 'a> {
+    ofuel: Ofuel<'backend, 'a>,
+    staking: Staking<'backend, 'a>,
+}
+
+impl<'backend, 'a> OvrPrecompileSet<'backend, 'a> {
+    #[inline(always)]
+    pub(crate) fn new(
+        token: &Erc20Like,
+        staking: &StakingState,
+        state: &OvrStackState<'backend, '_, OvrBackend<'a>>,
+    ) -> Self {
+        Self {
+            ofuel: Ofuel::new(token, state.backend(), state.substate()),
+            staking: Staking::new(staking, state.backend(), state.substate()),
+        }
+    }
+}
+
+impl<'backend, 'a> PrecompileSet for OvrPrecompileSet<'backend, 'a> {
+    fn execute(
+        &self,
+        address: H160,
+        input: &[u8],
+        gas_limit: Option<u64>,
+        context: &Context,
+        is_static: bool,
+    ) -> Option<PrecompileResult> {
+        if address == self.ofuel.address() {
+            Some(self.ofuel.execute(input, gas_limit, context, is_static))
+        } else if address == self.staking.address() {
+            Some(self.staking.execute(input, gas_limit, context, is_static))
+        } else {
+            PRECOMPILE_SET.execute(address, input, gas_limit, context, is_static)
+        }
+    }
+
+    #[inline(always)]
+    fn is_precompile(&self, address: H160) -> bool {
+        address == self.ofuel.address()
+            || address == self.staking.address()
+            || PRECOMPILE_SET.is_precompile(address)
+    }
+}
+
+// The selector and the ABI-encoded arguments.
+#[inline(always)]
+fn split_input(input: &[u8]) -> StdResult<(u32, &[u8]), PrecompileFailure> {
+    if input.len() < 4 {
+        return Err(error("Invalid input"));
+    }
+    let selector = u32::from_be_bytes([input[0], input[1], input[2], input[3]]);
+    Ok((selector, &input[4..]))
+}
+
+// Common checks for the stateful precompiles, none of them is payable.
+fn check_call(
+    cost: u64,
+    is_update: bool,
+    gas_limit: Option<u64>,
+    context: &Context,
+    is_static: bool,
+) -> StdResult<(), PrecompileFailure> {
+    if gas_limit.map(|limit| limit < cost).unwrap_or(false) {
+        return Err(PrecompileFailure::Error {
+            exit_status: ExitError::OutOfGas,
+        });
+    }
+    if is_update && is_static {
+        return Err(error("Can not change the state in a static call"));
+    }
+    if !context.apparent_value.is_zero() {
+        return Err(revert(cost, "Not payable"));
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn output(cost: u64, output: Vec<u8>, logs: Vec<Log>) -> PrecompileOutput {
+    PrecompileOutput {
+        exit_status: ExitSucceed::Returned,
+        cost,
+        output,
+        logs,
+    }
+}
+
+#[inline(always)]
+fn error(msg: &'static str) -> PrecompileFailure {
+    PrecompileFailure::Error {
+        exit_status: ExitError::Other(msg.into()),
+    }
+}
+
+// Encoded as `Error(string)`, so the reason can be recognized by wallets.
+fn revert(cost: u64, msg: &str) -> PrecompileFailure {
+    let mut output = SELECTOR_ERROR.to_be_bytes().to_vec();
+    output.extend_from_slice(&u256_to_word(U256::from(32u8)));
+    output.extend_from_slice(&u256_to_word(U256::from(msg.len())));
+    let mut data = msg.as_bytes().to_vec();
+    data.resize((msg.len() + 31) / 32 * 32, 0);
+    output.append(&mut data);
+
+    PrecompileFailure::Revert {
+        exit_status: ExitRevert::Reverted,
+        output,
+        cost,
+    }
+}
+
+#[inline(always)]
+fn read_word(args: &[u8], idx: usize) -> StdResult<&[u8], PrecompileFailure> {
+    args.get(idx * 32..(idx + 1) * 32)
+        .ok_or_else(|| error("Invalid arguments"))
+}
+
+// Revert like the solidity decoder if the 12 padding bytes are not zero,
+// or a dirty word would be silently truncated into another address.
+#[inline(always)]
+fn read_address(
+    args: &[u8],
+    idx: usize,
+    cost: u64,
+) -> StdResult<H160, PrecompileFailure> {
+    let w = read_word(args, idx)?;
+    if w[..12].iter().any(|b| *b != 0) {
+        return Err(revert(cost, "Invalid address"));
+    }
+    Ok(H160::from_slice(&w[12..]))
+}
+
+#[inline(always)]
+fn read_u256(args: &[u8], idx: usize) -> StdResult<U256, PrecompileFailure> {
+    read_word(args, idx).map(U256::from_big_endian)
+}
+
+#[inline(always)]
+fn u256_to_word(n: U256) -> Vec<u8> {
+    let mut word = vec![0; 32];
+    n.to_big_endian(&mut word);
+    word
+}
+
+#[inline(always)]
+fn keccak256(data: &[u8]) -> H256 {
+    H256::from_slice(Keccak256::digest(data).as_slice())
+}
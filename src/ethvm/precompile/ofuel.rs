@@ -0,0 +1,193 @@
+//!
+//! # OFUEL as a standard ERC-20 contract
+//!
+//! The balances are the native balances of evm accounts,
+//! all changes are recorded in the shared stack substate,
+//! so they will be reverted along with the failed calls.
+//!
+
+use super::{
+    check_call, error, keccak256, output, read_address, read_u256, revert, split_input,
+    u256_to_word, PrecompileResult, GAS_QUERY,
+};
+use crate::ethvm::{
+    impls::{backend::OvrBackend, stack::OvrStackSubstate},
+    tx::token::Erc20Like,
+};
+use evm::{
+    backend::{Backend, Log},
+    Context, Transfer,
+};
+use once_cell::sync::Lazy;
+use primitive_types::{H160, H256, U256};
+use std::{cell::RefCell, rc::Rc};
+
+// Function selectors of the ERC-20 standard.
+const SELECTOR_NAME: u32 = 0x06fdde03; // name()
+const SELECTOR_SYMBOL: u32 = 0x95d89b41; // symbol()
+const SELECTOR_DECIMALS: u32 = 0x313ce567; // decimals()
+const SELECTOR_TOTAL_SUPPLY: u32 = 0x18160ddd; // totalSupply()
+const SELECTOR_BALANCE_OF: u32 = 0x70a08231; // balanceOf(address)
+const SELECTOR_ALLOWANCE: u32 = 0xdd62ed3e; // allowance(address,address)
+const SELECTOR_TRANSFER: u32 = 0xa9059cbb; // transfer(address,uint256)
+const SELECTOR_APPROVE: u32 = 0x095ea7b3; // approve(address,uint256)
+const SELECTOR_TRANSFER_FROM: u32 = 0x23b872dd; // transferFrom(address,address,uint256)
+
+const GAS_UPDATE: u64 = 25_000;
+
+static EVENT_TRANSFER: Lazy<H256> =
+    Lazy::new(|| keccak256(b"Transfer(address,address,uint256)"));
+static EVENT_APPROVAL: Lazy<H256> =
+    Lazy::new(|| keccak256(b"Approval(address,address,uint256)"));
+
+pub(crate) struct Ofuel<'backend, 'a> {
+    token: Erc20Like,
+    backend: &'backend OvrBackend<'a>,
+    substate: Rc<RefCell<OvrStackSubstate>>,
+}
+
+impl<'backend, 'a> Ofuel<'backend, 'a> {
+    #[inline(always)]
+    pub(crate) fn new(
+        token: &Erc20Like,
+        backend: &'backend OvrBackend<'a>,
+        substate: Rc<RefCell<OvrStackSubstate>>,
+    ) -> Self {
+        Self {
+            token: token.clone(),
+            backend,
+            substate,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn address(&self) -> H160 {
+        self.token.contract_addr
+    }
+
+    pub(crate) fn execute(
+        &self,
+        input: &[u8],
+        gas_limit: Option<u64>,
+        context: &Context,
+        is_static: bool,
+    ) -> PrecompileResult {
+        let (selector, args) = split_input(input)?;
+
+        let is_update = matches!(
+            selector,
+            SELECTOR_TRANSFER | SELECTOR_APPROVE | SELECTOR_TRANSFER_FROM
+        );
+        let cost = if is_update { GAS_UPDATE } else { GAS_QUERY };
+        check_call(cost, is_update, gas_limit, context, is_static)?;
+
+        let caller = context.caller;
+
+        match selector {
+            SELECTOR_NAME => Ok(output(cost, self.token.name.clone(), vec![])),
+            SELECTOR_SYMBOL => Ok(output(cost, self.token.symbol.clone(), vec![])),
+            SELECTOR_DECIMALS => Ok(output(
+                cost,
+                u256_to_word(self.token.decimal.into()),
+                vec![],
+            )),
+            SELECTOR_TOTAL_SUPPLY => {
+                let supply = self.token.total_supply(self.backend.branch);
+                Ok(output(cost, u256_to_word(supply), vec![]))
+            }
+            SELECTOR_BALANCE_OF => {
+                let owner = read_address(args, 0, cost)?;
+                Ok(output(cost, u256_to_word(self.balance(owner)), vec![]))
+            }
+            SELECTOR_ALLOWANCE => {
+                let owner = read_address(args, 0, cost)?;
+                let spender = read_address(args, 1, cost)?;
+                let amount = self.allowance(owner, spender);
+                Ok(output(cost, u256_to_word(amount), vec![]))
+            }
+            SELECTOR_TRANSFER => {
+                let to = read_address(args, 0, cost)?;
+                let amount = read_u256(args, 1)?;
+                self.transfer(cost, caller, to, amount)
+            }
+            SELECTOR_APPROVE => {
+                let spender = read_address(args, 0, cost)?;
+                let amount = read_u256(args, 1)?;
+                self.substate
+                    .borrow_mut()
+                    .set_allowance(caller, spender, amount);
+                let log = self.log(*EVENT_APPROVAL, caller, spender, amount);
+                Ok(output(cost, u256_to_word(U256::one()), vec![log]))
+            }
+            SELECTOR_TRANSFER_FROM => {
+                let from = read_address(args, 0, cost)?;
+                let to = read_address(args, 1, cost)?;
+                let amount = read_u256(args, 2)?;
+                if from != caller {
+                    let allowance = self.allowance(from, caller);
+                    if allowance < amount {
+                        return Err(revert(cost, "Insufficient allowance"));
+                    }
+                    // an unlimited approval will never be consumed
+                    if allowance != U256::MAX {
+                        self.substate.borrow_mut().set_allowance(
+                            from,
+                            caller,
+                            allowance - amount,
+                        );
+                    }
+                }
+                self.transfer(cost, from, to, amount)
+            }
+            _ => Err(error("Unknown function")),
+        }
+    }
+
+    fn transfer(
+        &self,
+        cost: u64,
+        from: H160,
+        to: H160,
+        amount: U256,
+    ) -> PrecompileResult {
+        let transfer = Transfer {
+            source: from,
+            target: to,
+            value: amount,
+        };
+        self.substate
+            .borrow_mut()
+            .transfer(transfer, self.backend)
+            .map_err(|_| revert(cost, "Insufficient balance"))?;
+
+        let log = self.log(*EVENT_TRANSFER, from, to, amount);
+        Ok(output(cost, u256_to_word(U256::one()), vec![log]))
+    }
+
+    #[inline(always)]
+    fn balance(&self, addr: H160) -> U256 {
+        self.substate
+            .borrow()
+            .known_basic(addr)
+            .unwrap_or_else(|| self.backend.basic(addr))
+            .balance
+    }
+
+    #[inline(always)]
+    fn allowance(&self, owner: H160, spender: H160) -> U256 {
+        self.substate
+            .borrow()
+            .known_allowance(owner, spender)
+            .unwrap_or_else(|| self.backend.allowance(owner, spender))
+    }
+
+    // Both `Transfer` and `Approval` have the same layout.
+    #[inline(always)]
+    fn log(&self, event: H256, a: H160, b: H160, amount: U256) -> Log {
+        Log {
+            address: self.token.contract_addr,
+            topics: vec![event, H256::from(a), H256::from(b)],
+            data: u256_to_word(amount),
+        }
+    }
+}
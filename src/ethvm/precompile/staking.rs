@@ -0,0 +1,257 @@
+//!
+//! # Staking functions for contracts
+//!
+//! All calls are made on behalf of `msg.sender`,
+//! the OFUEL is moved in the shared stack substate immediately,
+//! and the staking operations are applied after the execution succeeds.
+//!
+
+use super::{
+    check_call, error, idx_to_h160, output, read_address, read_u256, revert,
+    split_input, u256_to_word, PrecompileResult, GAS_QUERY,
+};
+use crate::{
+    ethvm::impls::{backend::OvrBackend, stack::OvrStackSubstate},
+    ledger::staking::{EvmOp, State as StakingState, ValidatorId},
+};
+use evm::{executor::stack::PrecompileFailure, Context, ExitError};
+use primitive_types::{H160, U256};
+use std::{cell::RefCell, rc::Rc};
+
+// Function selectors.
+const SELECTOR_DELEGATE: u32 = 0x026e402b; // delegate(address,uint256)
+const SELECTOR_UNDELEGATE: u32 = 0x4d99dd16; // undelegate(address,uint256)
+const SELECTOR_CLAIM_REWARDS: u32 = 0x372500ab; // claimRewards()
+const SELECTOR_GET_VALIDATORS: u32 = 0xb7ab4db5; // getValidators()
+const SELECTOR_GET_VALIDATOR: u32 = 0x1904bb2e; // getValidator(address)
+const SELECTOR_GET_DELEGATION: u32 = 0x15049a5a; // getDelegation(address,address)
+const SELECTOR_PENDING_REWARDS: u32 = 0x31d7a262; // pendingRewards(address)
+
+// Keep it the same as `tx::native::GAS_STAKING`.
+const GAS_UPDATE: u64 = 50_000;
+
+// Charged for every returned validator of `getValidators`,
+// the scan stops as soon as the gas limit is reached.
+const GAS_QUERY_ITEM: u64 = 500;
+
+pub(crate) struct Staking<'backend, 'a> {
+    address: H160,
+    staking: StakingState,
+    backend: &'backend OvrBackend<'a>,
+    substate: Rc<RefCell<OvrStackSubstate>>,
+}
+
+impl<'backend, 'a> Staking<'backend, 'a> {
+    #[inline(always)]
+    pub(crate) fn new(
+        staking: &StakingState,
+        backend: &'backend OvrBackend<'a>,
+        substate: Rc<RefCell<OvrStackSubstate>>,
+    ) -> Self {
+        Self {
+            address: idx_to_h160(0x1001),
+            staking: staking.clone(),
+            backend,
+            substate,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn address(&self) -> H160 {
+        self.address
+    }
+
+    pub(crate) fn execute(
+        &self,
+        input: &[u8],
+        gas_limit: Option<u64>,
+        context: &Context,
+        is_static: bool,
+    ) -> PrecompileResult {
+        let (selector, args) = split_input(input)?;
+
+        let is_update = matches!(
+            selector,
+            SELECTOR_DELEGATE | SELECTOR_UNDELEGATE | SELECTOR_CLAIM_REWARDS
+        );
+        let cost = if is_update { GAS_UPDATE } else { GAS_QUERY };
+        check_call(cost, is_update, gas_limit, context, is_static)?;
+
+        let caller = context.caller;
+        let b = self.backend.branch;
+
+        match selector {
+            SELECTOR_DELEGATE => {
+                let validator = read_validator(args, 0, cost)?;
+                let amount = read_u256(args, 1)?;
+                if amount.is_zero() {
+                    return Err(revert(cost, "Amount can not be zero"));
+                }
+                if self.staking.validator_get(&validator, b).is_none() {
+                    return Err(revert(cost, "Validator not found"));
+                }
+
+                let mut substate = self.substate.borrow_mut();
+                substate
+                    .withdraw(caller, amount, self.backend)
+                    .map_err(|_| revert(cost, "Insufficient balance"))?;
+                substate.push_staking_op(EvmOp::Delegate {
+                    delegator: caller,
+                    validator,
+                    amount,
+                });
+
+                Ok(output(cost, u256_to_word(U256::one()), vec![]))
+            }
+            SELECTOR_UNDELEGATE => {
+                let validator = read_validator(args, 0, cost)?;
+                let amount = read_u256(args, 1)?;
+                if amount.is_zero() {
+                    return Err(revert(cost, "Amount can not be zero"));
+                }
+                if self.delegation(caller, &validator) < amount {
+                    return Err(revert(cost, "Insufficient delegation"));
+                }
+
+                self.substate
+                    .borrow_mut()
+                    .push_staking_op(EvmOp::Undelegate {
+                        delegator: caller,
+                        validator,
+                        amount,
+                    });
+
+                Ok(output(cost, u256_to_word(U256::one()), vec![]))
+            }
+            SELECTOR_CLAIM_REWARDS => {
+                let amount = self.rewards(caller);
+                if amount.is_zero() {
+                    return Err(revert(cost, "No rewards"));
+                }
+
+                let mut substate = self.substate.borrow_mut();
+                substate.deposit(caller, amount, self.backend);
+                substate.push_staking_op(EvmOp::WithdrawRewards {
+                    addr: caller,
+                    amount,
+                });
+
+                Ok(output(cost, u256_to_word(amount), vec![]))
+            }
+            SELECTOR_GET_VALIDATORS => {
+                let max = gas_limit
+                    .map(|limit| (limit - cost) / GAS_QUERY_ITEM)
+                    .unwrap_or(u64::MAX);
+                let ids = self
+                    .staking
+                    .validators
+                    .iter_by_branch(b)
+                    .map(|(id, _)| id)
+                    .take(max.saturating_add(1).try_into().unwrap_or(usize::MAX))
+                    .collect::<Vec<_>>();
+                if ids.len() as u64 > max {
+                    return Err(PrecompileFailure::Error {
+                        exit_status: ExitError::OutOfGas,
+                    });
+                }
+                let cost = cost + GAS_QUERY_ITEM * ids.len() as u64;
+
+                // a dynamic array: offset + length + items
+                let mut ret = u256_to_word(U256::from(32u8));
+                ret.extend_from_slice(&u256_to_word(U256::from(ids.len())));
+                for id in ids.iter() {
+                    ret.extend_from_slice(&validator_to_word(id));
+                }
+
+                Ok(output(cost, ret, vec![]))
+            }
+            SELECTOR_GET_VALIDATOR => {
+                let id = read_validator(args, 0, cost)?;
+                let v = self
+                    .staking
+                    .validator_get(&id, b)
+                    .ok_or_else(|| revert(cost, "Validator not found"))?;
+
+                // (address operator, uint256 selfBond, uint256 delegated,
+                //  uint256 commission, bool jailed)
+                let mut ret = u256_to_word(U256::from_big_endian(v.operator.as_bytes()));
+                ret.extend_from_slice(&u256_to_word(v.self_bond));
+                ret.extend_from_slice(&u256_to_word(v.delegated));
+                ret.extend_from_slice(&u256_to_word(v.commission.into()));
+                ret.extend_from_slice(&u256_to_word((v.jailed as u8).into()));
+
+                Ok(output(cost, ret, vec![]))
+            }
+            SELECTOR_GET_DELEGATION => {
+                let delegator = read_address(args, 0, cost)?;
+                let validator = read_validator(args, 1, cost)?;
+                let amount = self.delegation(delegator, &validator);
+                Ok(output(cost, u256_to_word(amount), vec![]))
+            }
+            SELECTOR_PENDING_REWARDS => {
+                let addr = read_address(args, 0, cost)?;
+                Ok(output(cost, u256_to_word(self.rewards(addr)), vec![]))
+            }
+            _ => Err(error("Unknown function")),
+        }
+    }
+
+    // The delegated amount, including the operations in the current execution.
+    fn delegation(&self, delegator: H160, validator: &ValidatorId) -> U256 {
+        let b = self.backend.branch;
+        let base = self
+            .staking
+            .delegation_get(delegator, validator, b)
+            .map(|d| d.amount)
+            .unwrap_or_default();
+
+        self.substate
+            .borrow()
+            .known_staking_ops()
+            .into_iter()
+            .fold(base, |acc, op| match op {
+                EvmOp::Delegate {
+                    delegator: d,
+                    validator: v,
+                    amount,
+                } if *d == delegator && v == validator => acc.saturating_add(*amount),
+                EvmOp::Undelegate {
+                    delegator: d,
+                    validator: v,
+                    amount,
+                } if *d == delegator && v == validator => acc.saturating_sub(*amount),
+                _ => acc,
+            })
+    }
+
+    // The rewards that can be claimed,
+    // zero if they have been claimed in the current execution.
+    fn rewards(&self, addr: H160) -> U256 {
+        let claimed = self.substate.borrow().known_staking_ops().into_iter().any(
+            |op| matches!(op, EvmOp::WithdrawRewards { addr: a, .. } if *a == addr),
+        );
+        if claimed {
+            U256::zero()
+        } else {
+            self.staking.rewards_get(&addr, self.backend.branch)
+        }
+    }
+}
+
+// The 20-bytes tendermint address of a validator is encoded as an `address`.
+#[inline(always)]
+fn read_validator(
+    args: &[u8],
+    idx: usize,
+    cost: u64,
+) -> Result<ValidatorId, PrecompileFailure> {
+    read_address(args, idx, cost).map(|addr| addr.as_bytes().to_vec())
+}
+
+#[inline(always)]
+fn validator_to_word(id: &[u8]) -> Vec<u8> {
+    let mut word = vec![0; 32];
+    let len = id.len().min(32);
+    word[32 - len..].copy_from_slice(&id[id.len() - len..]);
+    word
+}
@@ -2,15 +2,15 @@ pub mod token;
 
 use crate::{
     common::HashValueRef,
-    ethvm::{impls::stack::OvrStackState, precompile::PRECOMPILE_SET, OvrAccount},
-    ledger::{Log as LedgerLog, Receipt, StateBranch},
+    ethvm::{impls::stack::OvrStackState, precompile::OvrPrecompileSet, OvrAccount},
+    ledger::{Log as LedgerLog, Receipt, State as LedgerState, StateBranch},
     InitalContract,
 };
-use ethereum::{Log, TransactionAction, TransactionAny};
+use ethereum::{AccessListItem, Log, TransactionAction, TransactionAny};
 use evm::{
     backend::{Apply, ApplyBackend},
     executor::stack::{StackExecutor, StackSubstateMetadata},
-    Config as EvmCfg, CreateScheme, ExitReason,
+    Config as EvmCfg, CreateScheme, ExitError, ExitReason,
 };
 use once_cell::sync::Lazy;
 use primitive_types::{H160, H256, U256};
@@ -37,33 +37,45 @@ impl Tx {
         b: BranchName,
         estimate: bool,
     ) -> StdResult<(ExecRet, Receipt), Option<ExecRet>> {
-        if let Ok((addr, _, gas_price)) = info!(self.pre_exec(sb, b)) {
+        let evm_cfg = sb.state.evm.hardfork(sb.height_in_process(), b).config();
+        if let Ok((addr, _, gas_price)) = info!(self.pre_exec(sb, b, &evm_cfg, estimate))
+        {
             let (from, to) = self.get_from_to();
-            let ret = self.exec(addr, sb, b, gas_price, estimate);
-            let r = ret.gen_receipt(from, to);
+            let tx_type = self.tx_type();
+            let ret = self.exec(addr, sb, b, evm_cfg, gas_price, estimate);
+            let r = ret.gen_receipt(from, to, tx_type);
             alt!(ret.success, Ok((ret, r)), Err(Some(ret)))
         } else {
             Err(None)
         }
     }
 
-    // 0. ensure the given gas price is big enough
-    // 1. verify the transaction signature
-    // 2. ensure the transaction nonce is bigger than the last nonce
-    // 3. ensure the balance of OFUEL is bigger than `spent_amount + gas_limit`
-    // 4. deducte `gas_limit` from the balance of OFUEL
+    // 0. ensure the chain id is correct(EIP-155)
+    // 1. ensure the given gas price is not lower than the base fee
+    // 2. verify the transaction signature
+    // 3. ensure the transaction nonce is bigger than the last nonce
+    // 4. ensure the gas limit is enough to pay the intrinsic gas
+    // 5. ensure the balance of OFUEL is bigger than `value + max_fee * gas_limit`
+    // 6. deduct `gas_price * gas_limit` from the balance of OFUEL,
+    // the unused part will be refunded after the execution,
+    // nothing will be charged in the estimate mode
     fn pre_exec(
         &self,
         sb: &mut StateBranch,
         b: BranchName,
+        evm_cfg: &EvmCfg,
+        estimate: bool,
     ) -> Result<(H160, OvrAccount, U256)> {
         // {0.}
+        self.check_chain_id(sb, b).c(d!())?;
+
+        // {1.}
         let gas_price = self.check_gas_price(sb, b).c(d!())?;
 
-        // {1.} if success, then the transaction signature is valid.
+        // {2.} if success, then the transaction signature is valid.
         let addr = self.recover_signer().c(d!())?;
 
-        // {2.}
+        // {3.}
         if let Err((tx_nonce, system_nonce)) = self.check_nonce(&addr, sb, b) {
             return Err(eg!(
                 "Invalid nonce: {}, should be: {}",
@@ -72,16 +84,40 @@ impl Tx {
             ));
         }
 
-        // {3.}{4.}
-        match self.check_balance(&addr, gas_price, sb, b) {
-            Ok((account, _)) => Ok((addr, account, gas_price)),
-            Err(Some((account, needed_amount))) => Err(eg!(
-                "Insufficient balance, needed: {}, total: {}",
-                needed_amount,
-                account.balance
-            )),
-            Err(_) => Err(eg!()),
+        // {4.}
+        let gas_limit = self.gas_limit();
+        let intrinsic_gas = self.intrinsic_gas(evm_cfg);
+        if gas_limit < U256::from(intrinsic_gas) {
+            return Err(eg!(
+                "Intrinsic gas too low, needed: {}, gas limit: {}",
+                intrinsic_gas,
+                gas_limit
+            ));
         }
+
+        // {5.}
+        let account = match self.check_balance(&addr, self.max_fee_per_gas(), sb, b) {
+            Ok((account, _)) => account,
+            Err(Some((account, needed_amount))) => {
+                return Err(eg!(
+                    "Insufficient balance, needed: {}, total: {}",
+                    needed_amount,
+                    account.balance
+                ));
+            }
+            Err(_) => return Err(eg!()),
+        };
+
+        // {6.}
+        if !estimate {
+            sb.state
+                .evm
+                .OFUEL
+                .debit(addr, gas_price.saturating_mul(gas_limit), b)
+                .c(d!())?;
+        }
+
+        Ok((addr, account, gas_price))
     }
 
     // Support:
@@ -93,22 +129,25 @@ impl Tx {
         addr: H160,
         sb: &mut StateBranch,
         b: BranchName,
+        mut evm_cfg: EvmCfg,
         gas_price: U256,
         estimate: bool,
     ) -> ExecRet {
-        let mut evm_cfg = EvmCfg::istanbul();
         alt!(estimate, evm_cfg.estimate = true);
 
-        let metadata = StackSubstateMetadata::new(u64::MAX, &evm_cfg);
+        // the intrinsic gas is also recorded in this gasometer
+        let tx_gas_limit = self.gas_limit().try_into().unwrap_or(u64::MAX);
+        let metadata = StackSubstateMetadata::new(tx_gas_limit, &evm_cfg);
         let mut backend = sb.state.evm.get_backend_hdr(b);
         let state = OvrStackState::new(metadata, &backend);
 
-        let precompiles = PRECOMPILE_SET.clone();
+        let precompiles =
+            OvrPrecompileSet::new(&sb.state.evm.OFUEL, &sb.state.staking, &state);
         let mut executor =
             StackExecutor::new_with_precompiles(state, &evm_cfg, &precompiles);
 
         let contract_addr;
-        let (exit_reason, extra_data) = match self.tx {
+        let (mut exit_reason, extra_data) = match self.tx {
             TransactionAny::Legacy(tx) => {
                 let gas_limit = tx.gas_limit.try_into().unwrap_or(u64::MAX);
                 match tx.action {
@@ -193,11 +232,33 @@ impl Tx {
             }
         };
 
+        // the refunded gas has been deducted
         let gas_used = U256::from(executor.used_gas());
-        let success = matches!(exit_reason, ExitReason::Succeed(_));
-        let (changes, logs) = executor.into_state().deconstruct();
+        let base_fee = sb.state.evm.base_fee(b);
+        let refund = alt!(
+            estimate,
+            U256::zero(),
+            U256::from(tx_gas_limit)
+                .saturating_sub(gas_used)
+                .saturating_mul(gas_price)
+        );
+        let mut success = matches!(exit_reason, ExitReason::Succeed(_));
+        let (changes, logs, precompile_changes) = executor.into_state().deconstruct();
         if success {
             backend.apply(changes, logs.clone(), false);
+            backend.apply_allowances(precompile_changes.allowances);
+
+            // all the changes of this transaction will be
+            // discarded along with the failed one
+            if let Err(e) = precompile_changes
+                .staking_ops
+                .into_iter()
+                .try_for_each(|op| op.apply(sb, b).c(d!()))
+                .and_then(|_| sb.state.evm.OFUEL.credit(addr, refund, b).c(d!()))
+            {
+                success = false;
+                exit_reason = ExitReason::Error(ExitError::Other(e.to_string().into()));
+            }
         } else {
             backend.apply(
                 Vec::<Apply<BTreeMap<H256, H256>>>::new(),
@@ -210,7 +271,9 @@ impl Tx {
             success,
             exit_reason,
             gas_used,
+            gas_price,
             fee_used: gas_used * gas_price,
+            fee_burned: gas_used * base_fee,
             extra_data,
             caller: addr,
             contract_addr,
@@ -218,25 +281,116 @@ impl Tx {
         }
     }
 
-    #[inline(always)]
-    fn check_gas_price(&self, sb: &StateBranch, b: BranchName) -> Result<U256> {
-        let gas_price_min = sb
+    // A transaction signed for another chain must not be replayed here.
+    fn check_chain_id(&self, sb: &StateBranch, b: BranchName) -> Result<()> {
+        let chain_id = sb.state.chain_id.get_value_by_branch(b).c(d!())?;
+
+        let tx_chain_id = match &self.tx {
+            TransactionAny::Legacy(tx) => tx.signature.chain_id(),
+            TransactionAny::EIP2930(tx) => Some(tx.chain_id),
+            TransactionAny::EIP1559(tx) => Some(tx.chain_id),
+        };
+        let reject_unprotected = sb
             .state
             .evm
-            .gas_price
+            .reject_unprotected_txs
             .get_value_by_branch(b)
-            .unwrap_or(*GAS_PRICE_MIN);
+            .unwrap_or_default();
 
-        let gas_price = match &self.tx {
-            TransactionAny::Legacy(tx) => tx.gas_price,
-            TransactionAny::EIP2930(tx) => tx.gas_price,
-            TransactionAny::EIP1559(tx) => tx.max_fee_per_gas,
+        match tx_chain_id {
+            Some(id) if id != chain_id => {
+                Err(eg!("Invalid chain id: {}, should be: {}", id, chain_id))
+            }
+            None if reject_unprotected => Err(eg!(
+                "Unprotected transactions(without a chain id) are not allowed"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Return the effective gas price:
+    // - Legacy and EIP2930 transactions: `gas_price`
+    // - EIP1559 transactions: `min(max_fee, base_fee + max_priority_fee)`
+    #[inline(always)]
+    fn check_gas_price(&self, sb: &StateBranch, b: BranchName) -> Result<U256> {
+        let base_fee = sb.state.evm.base_fee(b);
+
+        let (max_fee, priority_fee) = match &self.tx {
+            TransactionAny::Legacy(tx) => (tx.gas_price, tx.gas_price),
+            TransactionAny::EIP2930(tx) => (tx.gas_price, tx.gas_price),
+            TransactionAny::EIP1559(tx) => {
+                if tx.max_fee_per_gas < tx.max_priority_fee_per_gas {
+                    return Err(eg!("Priority fee is bigger than the max fee"));
+                }
+                (tx.max_fee_per_gas, tx.max_priority_fee_per_gas)
+            }
         };
 
-        if gas_price_min <= gas_price {
-            Ok(gas_price)
+        if base_fee <= max_fee {
+            Ok(min!(max_fee, base_fee.saturating_add(priority_fee)))
         } else {
-            Err(eg!("Gas price is too low"))
+            Err(eg!("Gas price is too low, base fee: {}", base_fee))
+        }
+    }
+
+    // EIP-2718 transaction type
+    #[inline(always)]
+    fn tx_type(&self) -> u8 {
+        match &self.tx {
+            TransactionAny::Legacy(_) => 0,
+            TransactionAny::EIP2930(_) => 1,
+            TransactionAny::EIP1559(_) => 2,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn gas_limit(&self) -> U256 {
+        match &self.tx {
+            TransactionAny::Legacy(tx) => tx.gas_limit,
+            TransactionAny::EIP2930(tx) => tx.gas_limit,
+            TransactionAny::EIP1559(tx) => tx.gas_limit,
+        }
+    }
+
+    // The gas charged before the execution, eg. the cost of the input data,
+    // keep it the same as the gasometer of `evm`.
+    fn intrinsic_gas(&self, cfg: &EvmCfg) -> u64 {
+        let (action, input, access_list): (_, _, &[AccessListItem]) = match &self.tx {
+            TransactionAny::Legacy(tx) => (tx.action, &tx.input, &[][..]),
+            TransactionAny::EIP2930(tx) => {
+                (tx.action, &tx.input, tx.access_list.as_slice())
+            }
+            TransactionAny::EIP1559(tx) => {
+                (tx.action, &tx.input, tx.access_list.as_slice())
+            }
+        };
+
+        let base = match action {
+            TransactionAction::Call(_) => cfg.gas_transaction_call,
+            TransactionAction::Create => cfg.gas_transaction_create,
+        };
+        let zeros = input.iter().filter(|b| 0 == **b).count() as u64;
+        let non_zeros = input.len() as u64 - zeros;
+        let keys = access_list
+            .iter()
+            .map(|al| al.slots.len() as u64)
+            .sum::<u64>();
+
+        base.saturating_add(zeros.saturating_mul(cfg.gas_transaction_zero_data))
+            .saturating_add(non_zeros.saturating_mul(cfg.gas_transaction_non_zero_data))
+            .saturating_add(
+                (access_list.len() as u64).saturating_mul(cfg.gas_access_list_address),
+            )
+            .saturating_add(keys.saturating_mul(cfg.gas_access_list_storage_key))
+    }
+
+    // The balance must be enough to pay the max fee.
+    #[inline(always)]
+    fn max_fee_per_gas(&self) -> U256 {
+        match &self.tx {
+            TransactionAny::Legacy(tx) => tx.gas_price,
+            TransactionAny::EIP2930(tx) => tx.gas_price,
+            TransactionAny::EIP1559(tx) => tx.max_fee_per_gas,
         }
     }
 
@@ -432,7 +586,11 @@ impl Tx {
 pub struct ExecRet {
     pub success: bool,
     pub gas_used: U256,
+    // the effective gas price, `fee_used / gas_used`
+    pub gas_price: U256,
     pub fee_used: U256,
+    // the base fee part of `fee_used`, will be burned
+    pub fee_burned: U256,
     pub exit_reason: ExitReason,
     pub extra_data: Vec<u8>,
     pub caller: H160,
@@ -441,7 +599,7 @@ pub struct ExecRet {
 }
 
 impl ExecRet {
-    fn gen_receipt(&self, from: Option<H160>, to: Option<H160>) -> Receipt {
+    fn gen_receipt(&self, from: Option<H160>, to: Option<H160>, tx_type: u8) -> Receipt {
         let contract_addr = if to.is_none() {
             Some(self.contract_addr)
         } else {
@@ -453,12 +611,15 @@ impl ExecRet {
             tx_index: 0,
             from,
             to,
-            block_gas_used: Default::default(),
-            tx_gas_used: self.fee_used,
+            cumulative_gas_used: Default::default(),
+            tx_gas_used: self.gas_used,
+            effective_gas_price: self.gas_price,
+            tx_type,
             contract_addr,
             state_root: None,
             status_code: self.success,
             logs: vec![],
+            logs_bloom: vec![],
         }
     }
 
@@ -492,18 +653,20 @@ pub struct TxCommonProperties {
 
 pub fn inital_create2(
     contract: InitalContract,
-    state: &super::State,
+    state: &LedgerState,
     b: BranchName<'_>,
 ) -> Result<()> {
-    let evm_cfg = EvmCfg::istanbul();
+    // only used in the genesis
+    let evm_cfg = state.evm.hardfork(0, b).config();
 
     let metadata = StackSubstateMetadata::new(u64::MAX, &evm_cfg);
-    let mut backend = state.get_backend_hdr(b);
-    let state = OvrStackState::new(metadata, &backend);
+    let mut backend = state.evm.get_backend_hdr(b);
+    let stack_state = OvrStackState::new(metadata, &backend);
 
-    let precompiles = PRECOMPILE_SET.clone();
+    let precompiles =
+        OvrPrecompileSet::new(&state.evm.OFUEL, &state.staking, &stack_state);
     let mut executor =
-        StackExecutor::new_with_precompiles(state, &evm_cfg, &precompiles);
+        StackExecutor::new_with_precompiles(stack_state, &evm_cfg, &precompiles);
 
     let bytecode_hex = &contract.bytecode[2..].trim();
 
@@ -533,8 +696,9 @@ pub fn inital_create2(
 
     let success = matches!(exit_reason, ExitReason::Succeed(_));
     if success {
-        let (changes, logs) = executor.into_state().deconstruct();
+        let (changes, logs, precompile_changes) = executor.into_state().deconstruct();
         backend.apply(changes, logs, false);
+        backend.apply_allowances(precompile_changes.allowances);
     } else {
         return Err(eg!("inital create false."));
     }
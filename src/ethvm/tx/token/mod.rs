@@ -1,9 +1,13 @@
-use crate::ethvm::{precompile::idx_to_h160, OvrAccount};
+use crate::{
+    common::{detach_map, detach_value, scan_by_branch},
+    ethvm::{precompile::idx_to_h160, OvrAccount},
+    ledger::smt::{self, ChangeSet},
+};
 use primitive_types::{H160, H256, U256};
 use ruc::*;
 use serde::{Deserialize, Serialize};
 use slices::u8_slice;
-use vsdb::{MapxDkVs, MapxVs, OrphanVs, Vs};
+use vsdb::{BranchName, MapxDkVs, MapxVs, OrphanVs, Vs};
 
 pub const DECIMAL: u32 = 18;
 
@@ -23,8 +27,15 @@ pub struct Erc20Like {
     // (addr, key) => value
     pub storages: MapxDkVs<H160, H256, H256>,
 
+    // all the storage keys ever set, ordered by the contract addresses,
+    // for resetting the storages of a contract in the state root
+    pub storage_slots: MapxVs<(H160, H256), ()>,
+
     // (owner addr, spender addr) => amount
     pub allowances: MapxVs<(H160, H160), U256>,
+
+    // the changed accounts, storages and allowances, for the state root
+    pub changes: ChangeSet,
 }
 
 impl Erc20Like {
@@ -44,7 +55,9 @@ impl Erc20Like {
             total_supply: OrphanVs::new(0u8.into()),
             accounts: MapxVs::new(),
             storages: MapxDkVs::new(),
+            storage_slots: MapxVs::new(),
             allowances: MapxVs::new(),
+            changes: ChangeSet::default(),
             contract_addr,
         }
     }
@@ -67,4 +80,129 @@ impl Erc20Like {
             contract_addr,
         )
     }
+
+    #[inline(always)]
+    pub fn total_supply(&self, b: BranchName) -> U256 {
+        self.total_supply.get_value_by_branch(b).unwrap_or_default()
+    }
+
+    /// How many OFUEL can still be issued before reaching the `issue_cap`.
+    #[inline(always)]
+    pub fn issuable(&self, b: BranchName) -> U256 {
+        self.issue_cap
+            .map(|cap| cap.saturating_sub(self.total_supply(b)))
+            .unwrap_or(U256::MAX)
+    }
+
+    /// Increase the total supply without crediting any account,
+    /// the new OFUEL should be held by the staking module, eg. genesis self-bonds.
+    pub(crate) fn issue(&self, amount: U256, b: BranchName) -> Result<()> {
+        alt!(amount.is_zero(), return Ok(()));
+        if self.issuable(b) < amount {
+            return Err(eg!(
+                "Exceed the issue cap, issuable: {}, needed: {}",
+                self.issuable(b),
+                amount
+            ));
+        }
+        let supply = self
+            .total_supply(b)
+            .checked_add(amount)
+            .c(d!("Total supply overflow"))?;
+        self.total_supply
+            .set_value_by_branch(supply, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    /// Decrease the total supply without debiting any account,
+    /// the destroyed OFUEL should be held by the staking module, eg. slashed bonds.
+    pub(crate) fn destroy(&self, amount: U256, b: BranchName) -> Result<()> {
+        alt!(amount.is_zero(), return Ok(()));
+        let supply = self
+            .total_supply(b)
+            .checked_sub(amount)
+            .c(d!("Total supply underflow"))?;
+        self.total_supply
+            .set_value_by_branch(supply, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    /// Create some new OFUEL for an account,
+    /// fail if the `issue_cap` would be exceeded.
+    pub fn mint(&self, addr: H160, amount: U256, b: BranchName) -> Result<()> {
+        self.issue(amount, b)
+            .c(d!())
+            .and_then(|_| self.credit(addr, amount, b).c(d!()))
+    }
+
+    /// Destroy some OFUEL of an account.
+    pub(crate) fn burn(&self, addr: H160, amount: U256, b: BranchName) -> Result<()> {
+        self.debit(addr, amount, b)
+            .c(d!())
+            .and_then(|_| self.destroy(amount, b).c(d!()))
+    }
+
+    // See `ledger::State::keep`.
+    pub(crate) fn detach(&self, b: BranchName) -> Result<()> {
+        detach_value(&self.total_supply, b).c(d!())?;
+        detach_map(&self.accounts, b).c(d!())?;
+        detach_map(&self.allowances, b).c(d!())?;
+
+        // the storages can not be iterated, the slots are used instead
+        scan_by_branch(&self.storage_slots, b, |(addr, idx), _| {
+            if let Some(v) = self.storages.get_by_branch(&(&addr, &idx), b) {
+                self.storages.insert_by_branch((addr, idx), v, b).c(d!())?;
+            }
+            self.storage_slots
+                .insert_by_branch((addr, idx), (), b)
+                .c(d!())
+                .map(|_| ())
+        })
+    }
+
+    /// The sum of the balances of all accounts.
+    pub fn balances_sum(&self, b: BranchName) -> U256 {
+        self.accounts
+            .iter_by_branch(b)
+            .fold(U256::zero(), |acc, (_, a)| acc.saturating_add(a.balance))
+    }
+
+    /// Add some OFUEL to the balance of an account.
+    pub(crate) fn credit(&self, addr: H160, amount: U256, b: BranchName) -> Result<()> {
+        alt!(amount.is_zero(), return Ok(()));
+        let mut account = self.accounts.get_by_branch(&addr, b).unwrap_or_default();
+        account.balance = account.balance.checked_add(amount).c(d!())?;
+        self.account_set(addr, account, b).c(d!())
+    }
+
+    /// Remove some OFUEL from the balance of an account.
+    pub(crate) fn debit(&self, addr: H160, amount: U256, b: BranchName) -> Result<()> {
+        alt!(amount.is_zero(), return Ok(()));
+        let mut account = self.accounts.get_by_branch(&addr, b).unwrap_or_default();
+        account.balance = account
+            .balance
+            .checked_sub(amount)
+            .c(d!("Insufficient balance"))?;
+        self.account_set(addr, account, b).c(d!())
+    }
+
+    /// Write an account, all writes should go through this function,
+    /// or the state root will not be updated.
+    #[inline(always)]
+    pub(crate) fn account_set(
+        &self,
+        addr: H160,
+        account: OvrAccount,
+        b: BranchName,
+    ) -> Result<()> {
+        self.changes
+            .stage(smt::account_key(&addr), smt::account_leaf(&account), b)
+            .c(d!())?;
+        self.accounts
+            .insert_by_branch(addr, account, b)
+            .c(d!())
+            .map(|_| ())
+    }
 }
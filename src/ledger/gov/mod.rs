@@ -0,0 +1,502 @@
+//!
+//! # On-chain governance of chain parameters
+//!
+//! Anyone who has bonded enough OFUEL can submit proposals,
+//! and anyone who has bonded some can vote on them,
+//! the votes are weighted by the bonded amount at the time of tallying.
+//!
+
+use crate::{
+    common::{detach_map, detach_value, BlockHeight},
+    ethvm::Hardfork,
+    ledger::{
+        smt::{self, ChangeSet},
+        staking::{self, calculate_fraction, COMMISSION_RATE_MAX, POWER_UNIT},
+        upgrade::UpgradePlan,
+        State as LedgerState, StateBranch,
+    },
+};
+use primitive_types::{H160, H256, U256};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use vsdb::{BranchName, MapxVs, OrphanVs, Vs};
+
+pub type ProposalId = u64;
+
+#[derive(Vs, Default, Clone, Debug, Deserialize, Serialize)]
+pub struct State {
+    pub proposals: MapxVs<ProposalId, Proposal>,
+    // proposal id => (voter => the latest vote of the voter)
+    pub votes: MapxVs<ProposalId, BTreeMap<H160, VoteOption>>,
+
+    // voting end height => proposals to be tallied
+    pub tally_queue: MapxVs<BlockHeight, Vec<ProposalId>>,
+    // apply height => proposals to be applied if passed
+    pub apply_queue: MapxVs<BlockHeight, Vec<ProposalId>>,
+
+    // the id of the next proposal
+    pub next_id: OrphanVs<ProposalId>,
+
+    pub params: OrphanVs<Params>,
+
+    // the changed proposals and votes, for the state root
+    pub changes: ChangeSet,
+}
+
+impl State {
+    #[inline(always)]
+    pub fn params(&self, b: BranchName) -> Params {
+        self.params.get_value_by_branch(b).unwrap_or_default()
+    }
+
+    // See `ledger::State::keep`.
+    pub(crate) fn detach(&self, b: BranchName) -> Result<()> {
+        detach_map(&self.proposals, b).c(d!())?;
+        detach_map(&self.votes, b).c(d!())?;
+        detach_map(&self.tally_queue, b).c(d!())?;
+        detach_map(&self.apply_queue, b).c(d!())?;
+        detach_value(&self.next_id, b).c(d!())?;
+        detach_value(&self.params, b).c(d!())
+    }
+
+    #[inline(always)]
+    pub fn proposal_get(&self, id: ProposalId, b: BranchName) -> Option<Proposal> {
+        self.proposals.get_by_branch(&id, b)
+    }
+
+    #[inline(always)]
+    fn proposal_set(&self, id: ProposalId, p: Proposal, b: BranchName) -> Result<()> {
+        self.changes
+            .stage(smt::record_key(b"proposal", &id), smt::record_leaf(&p), b)
+            .c(d!())?;
+        self.proposals
+            .insert_by_branch(id, p, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    fn submit(
+        &self,
+        proposer: H160,
+        changes: Vec<ParamChange>,
+        memo: String,
+        apply_height: BlockHeight,
+        height: BlockHeight,
+        b: BranchName,
+    ) -> Result<ProposalId> {
+        if changes.is_empty() {
+            return Err(eg!("No parameter changes"));
+        }
+
+        let voting_end = height + self.params(b).voting_blocks;
+        if apply_height <= voting_end {
+            return Err(eg!(
+                "Invalid apply height: {}, should be bigger than {}",
+                apply_height,
+                voting_end
+            ));
+        }
+
+        for c in changes.iter() {
+            c.check(apply_height).c(d!())?;
+        }
+
+        let id = self.next_id.get_value_by_branch(b).unwrap_or_default();
+        self.next_id.set_value_by_branch(id + 1, b).c(d!())?;
+
+        let p = Proposal {
+            proposer,
+            changes,
+            memo,
+            voting_end,
+            apply_height,
+            status: ProposalStatus::Voting,
+            tally: TallyResult::default(),
+        };
+        self.proposal_set(id, p, b).c(d!())?;
+
+        queue_push(&self.tally_queue, voting_end, id, b).c(d!())?;
+        queue_push(&self.apply_queue, apply_height, id, b).c(d!())?;
+
+        Ok(id)
+    }
+
+    fn vote(
+        &self,
+        voter: H160,
+        id: ProposalId,
+        option: VoteOption,
+        height: BlockHeight,
+        b: BranchName,
+    ) -> Result<()> {
+        let p = self.proposal_get(id, b).c(d!("Proposal not found"))?;
+        if ProposalStatus::Voting != p.status || p.voting_end < height {
+            return Err(eg!("Voting period has ended"));
+        }
+        let mut votes = self.votes.get_by_branch(&id, b).unwrap_or_default();
+        votes.insert(voter, option);
+        self.changes
+            .stage(smt::record_key(b"votes", &id), smt::record_leaf(&votes), b)
+            .c(d!())?;
+        self.votes
+            .insert_by_branch(id, votes, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    // Count the votes of all the proposals whose voting period ends at this height.
+    fn tally(
+        &self,
+        staking: &staking::State,
+        height: BlockHeight,
+        b: BranchName,
+    ) -> Result<()> {
+        let ids = if let Some(ids) = self.tally_queue.get_by_branch(&height, b) {
+            ids
+        } else {
+            return Ok(());
+        };
+
+        let params = self.params(b);
+        let total = staking.total_bonded(b);
+
+        for id in ids.into_iter() {
+            let mut p = self.proposal_get(id, b).c(d!())?;
+
+            let votes = self.votes.get_by_branch(&id, b).unwrap_or_default();
+            for (voter, option) in votes.into_iter() {
+                let stake = staking.stake_of(voter, b);
+                match option {
+                    VoteOption::Yes => p.tally.yes = p.tally.yes.saturating_add(stake),
+                    VoteOption::No => p.tally.no = p.tally.no.saturating_add(stake),
+                    VoteOption::Abstain => {
+                        p.tally.abstain = p.tally.abstain.saturating_add(stake)
+                    }
+                }
+            }
+            self.changes
+                .stage(smt::record_key(b"votes", &id), H256::zero(), b)
+                .c(d!())?;
+            self.votes.remove_by_branch(&id, b).c(d!())?;
+
+            let voted = p.tally.yes + p.tally.no + p.tally.abstain;
+            let decided = p.tally.yes + p.tally.no;
+            let passed = !total.is_zero()
+                && calculate_fraction(total, params.quorum) <= voted
+                && calculate_fraction(decided, params.threshold) < p.tally.yes;
+            p.status = alt!(passed, ProposalStatus::Passed, ProposalStatus::Rejected);
+
+            self.proposal_set(id, p, b).c(d!())?;
+        }
+
+        self.tally_queue
+            .remove_by_branch(&height, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    // Apply the passed proposals that are scheduled at this height,
+    // in the order of their ids.
+    fn apply(
+        &self,
+        state: &LedgerState,
+        height: BlockHeight,
+        b: BranchName,
+    ) -> Result<()> {
+        let ids = if let Some(ids) = self.apply_queue.get_by_branch(&height, b) {
+            ids
+        } else {
+            return Ok(());
+        };
+
+        for id in ids.into_iter() {
+            let mut p = self.proposal_get(id, b).c(d!())?;
+            alt!(ProposalStatus::Passed != p.status, continue);
+            for c in p.changes.iter() {
+                c.apply(state, b).c(d!())?;
+            }
+            p.status = ProposalStatus::Applied;
+            self.proposal_set(id, p, b).c(d!())?;
+        }
+
+        self.apply_queue
+            .remove_by_branch(&height, b)
+            .c(d!())
+            .map(|_| ())
+    }
+}
+
+// NOTE:
+// - Call this in the 'EndBlock' field of ABCI,
+// before the block params are synced to tendermint
+pub(crate) fn end_block(sb: &StateBranch, b: BranchName) -> Result<()> {
+    let gov = &sb.state.gov;
+    let h = sb.block_in_process.header.height;
+    gov.tally(&sb.state.staking, h, b).c(d!())?;
+    gov.apply(&sb.state, h, b).c(d!())
+}
+
+#[inline(always)]
+fn queue_push(
+    queue: &MapxVs<BlockHeight, Vec<ProposalId>>,
+    height: BlockHeight,
+    id: ProposalId,
+    b: BranchName,
+) -> Result<()> {
+    let mut ids = queue.get_by_branch(&height, b).unwrap_or_default();
+    ids.push(id);
+    queue.insert_by_branch(height, ids, b).c(d!()).map(|_| ())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Params {
+    // how many blocks the voting period lasts for
+    pub voting_blocks: u64,
+    // the min share of the total bonded OFUEL that has voted,
+    // in the unit of `1 / COMMISSION_RATE_MAX`
+    pub quorum: u16,
+    // the share of `Yes` in the non-abstain votes must be bigger than this,
+    // in the unit of `1 / COMMISSION_RATE_MAX`
+    pub threshold: u16,
+    // the min bonded OFUEL of a proposer, to keep the proposals from spamming
+    pub min_proposer_stake: U256,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            voting_blocks: 50_000,
+            quorum: 3_340,
+            threshold: 5_000,
+            min_proposer_stake: POWER_UNIT.saturating_mul(100u8.into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Proposal {
+    pub proposer: H160,
+    pub changes: Vec<ParamChange>,
+    pub memo: String,
+    // the last height that accepts votes
+    pub voting_end: BlockHeight,
+    // the changes will be applied in the 'EndBlock' of this height
+    pub apply_height: BlockHeight,
+    pub status: ProposalStatus,
+    // filled after the voting period ends
+    pub tally: TallyResult,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ProposalStatus {
+    Voting,
+    Passed,
+    Rejected,
+    Applied,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TallyResult {
+    pub yes: U256,
+    pub no: U256,
+    pub abstain: U256,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum VoteOption {
+    Yes,
+    No,
+    Abstain,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ParamChange {
+    GasPrice(U256),
+    BlockGasLimit(U256),
+    BlockBaseFeePerGas(U256),
+    BlockMaxBytes(u64),
+    Staking(staking::Params),
+    Gov(Params),
+    // switch the evm rules at the given height
+    ScheduleHardfork { height: BlockHeight, fork: Hardfork },
+    // accept or reject the legacy transactions without a chain id
+    RejectUnprotectedTxs(bool),
+    // replace the pending upgrade plan, if any
+    Upgrade(UpgradePlan),
+    CancelUpgrade,
+}
+
+impl ParamChange {
+    fn check(&self, apply_height: BlockHeight) -> Result<()> {
+        let check_fraction = |name, v| {
+            if COMMISSION_RATE_MAX < v {
+                Err(eg!("Invalid {}: {}", name, v))
+            } else {
+                Ok(())
+            }
+        };
+
+        match self {
+            ParamChange::BlockGasLimit(v) if v.is_zero() => {
+                Err(eg!("Block gas limit can not be zero"))
+            }
+            ParamChange::BlockMaxBytes(v) if 0 == *v || *v > i64::MAX as u64 => {
+                Err(eg!("Invalid block max bytes: {}", v))
+            }
+            ParamChange::Staking(p) => {
+                check_fraction("slash fraction", p.slash_fraction_double_sign)
+                    .c(d!())?;
+                check_fraction("proposer bonus", p.proposer_bonus).c(d!())?;
+                if p.signed_blocks_window < p.min_signed_per_window {
+                    return Err(eg!("Invalid min signed blocks per window"));
+                }
+                Ok(())
+            }
+            ParamChange::Gov(p) => {
+                check_fraction("quorum", p.quorum).c(d!())?;
+                check_fraction("threshold", p.threshold).c(d!())?;
+                if 0 == p.voting_blocks {
+                    return Err(eg!("Voting blocks can not be zero"));
+                }
+                Ok(())
+            }
+            ParamChange::ScheduleHardfork { height, .. } if *height <= apply_height => {
+                Err(eg!(
+                    "Invalid hardfork height: {}, should be bigger than {}",
+                    height,
+                    apply_height
+                ))
+            }
+            ParamChange::Upgrade(plan) => {
+                plan.check().c(d!())?;
+                if plan.height <= apply_height {
+                    return Err(eg!(
+                        "Invalid upgrade height: {}, should be bigger than {}",
+                        plan.height,
+                        apply_height
+                    ));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply(&self, state: &LedgerState, b: BranchName) -> Result<()> {
+        match self.clone() {
+            ParamChange::GasPrice(v) => {
+                state.evm.gas_price.set_value_by_branch(v, b).c(d!())?;
+            }
+            ParamChange::BlockGasLimit(v) => {
+                state
+                    .evm
+                    .block_gas_limit
+                    .set_value_by_branch(v, b)
+                    .c(d!())?;
+            }
+            ParamChange::BlockBaseFeePerGas(v) => {
+                state
+                    .evm
+                    .block_base_fee_per_gas
+                    .set_value_by_branch(v, b)
+                    .c(d!())?;
+            }
+            ParamChange::BlockMaxBytes(v) => {
+                state.block_max_bytes.set_value_by_branch(v, b).c(d!())?;
+            }
+            ParamChange::Staking(p) => {
+                state.staking.params.set_value_by_branch(p, b).c(d!())?;
+            }
+            ParamChange::Gov(p) => {
+                state.gov.params.set_value_by_branch(p, b).c(d!())?;
+            }
+            ParamChange::ScheduleHardfork { height, fork } => {
+                let mut forks = state
+                    .evm
+                    .hardforks
+                    .get_value_by_branch(b)
+                    .unwrap_or_default();
+                forks.insert(height, fork);
+                state.evm.hardforks.set_value_by_branch(forks, b).c(d!())?;
+            }
+            ParamChange::RejectUnprotectedTxs(v) => {
+                state
+                    .evm
+                    .reject_unprotected_txs
+                    .set_value_by_branch(v, b)
+                    .c(d!())?;
+            }
+            ParamChange::Upgrade(plan) => {
+                state
+                    .upgrade_plan
+                    .set_value_by_branch(Some(plan), b)
+                    .c(d!())?;
+            }
+            ParamChange::CancelUpgrade => {
+                state.upgrade_plan.set_value_by_branch(None, b).c(d!())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Op {
+    // the signer of the transaction must have bonded at least `min_proposer_stake`
+    SubmitProposal {
+        changes: Vec<ParamChange>,
+        memo: String,
+        // must be bigger than the end of the voting period
+        apply_height: BlockHeight,
+    },
+    // a later vote overwrites the former one of the same voter
+    Vote {
+        proposal_id: ProposalId,
+        option: VoteOption,
+    },
+}
+
+impl Op {
+    pub(crate) fn exec(
+        self,
+        caller: H160,
+        sb: &StateBranch,
+        b: BranchName,
+    ) -> Result<String> {
+        let gov = &sb.state.gov;
+        let h = sb.block_in_process.header.height;
+
+        let stake = sb.state.staking.stake_of(caller, b);
+        if stake.is_zero() {
+            return Err(eg!("No bonded OFUEL"));
+        }
+
+        match self {
+            Op::SubmitProposal {
+                changes,
+                memo,
+                apply_height,
+            } => {
+                let min_stake = gov.params(b).min_proposer_stake;
+                if stake < min_stake {
+                    return Err(eg!(
+                        "Not enough bonded OFUEL to submit a proposal: {}, needed: {}",
+                        stake,
+                        min_stake
+                    ));
+                }
+                gov.submit(caller, changes, memo, apply_height, h, b)
+                    .c(d!())
+                    .map(|id| format!("proposal submitted: {}", id))
+            }
+            Op::Vote {
+                proposal_id,
+                option,
+            } => gov
+                .vote(caller, proposal_id, option, h, b)
+                .c(d!())
+                .map(|_| format!("voted {:?} on proposal {}", option, proposal_id)),
+        }
+    }
+}
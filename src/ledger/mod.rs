@@ -2,28 +2,48 @@
 //! # Ledger, world state
 //!
 
+pub mod gov;
+pub mod smt;
 pub mod staking;
+pub mod upgrade;
 
 use crate::common::handle_bloom;
 use crate::{
     common::{
-        block_hash_to_evm_format, hash_sha3_256, tm_proposer_to_evm_format, BlockHeight,
-        HashValue, HashValueRef, TmAddress, TmAddressRef,
+        block_hash_to_evm_format, detach_value, hash_keccak_256, hash_sha3_256,
+        tm_proposer_to_evm_format, BlockHeight, HashValue, HashValueRef, TmAddress,
     },
-    ethvm::{self, tx::GAS_PRICE_MIN},
-    tx::Tx,
+    ethvm::{
+        self,
+        tx::{ExecRet as EvmExecRet, GAS_PRICE_MIN},
+    },
+    ledger::{
+        staking::{ValidatorId, ValidatorUpdate},
+        upgrade::UpgradePlan,
+    },
+    tx::{native::ExecRet as NativeExecRet, Tx},
 };
 use ethereum::Log as EthLog;
 use ethereum_types::Bloom;
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
-use primitive_types::{H160, H256, U256};
+use primitive_types::{H160, H256, H64, U256};
+use rlp::RlpStream;
 use ruc::*;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, fs, io::ErrorKind, mem, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{ErrorKind, Write},
+    mem,
+    path::Path,
+    result,
+    str::FromStr,
+    sync::Arc,
+};
 use vsdb::{
     merkle::{MerkleTree, MerkleTreeStore},
-    BranchName, MapxOrd, OrphanVs, ParentBranchName, ValueEn, ValueEnDe, Vecx, Vs,
+    BranchName, Mapx, MapxOrd, OrphanVs, ParentBranchName, ValueEn, ValueEnDe, Vecx, Vs,
     VsMgmt, INITIAL_VERSION,
 };
 
@@ -31,6 +51,9 @@ pub const MAIN_BRANCH_NAME: BranchName = BranchName(b"Main");
 const DELIVER_TX_BRANCH_NAME: BranchName = BranchName(b"DeliverTx");
 const CHECK_TX_BRANCH_NAME: BranchName = BranchName(b"CheckTx");
 
+/// The default `max_bytes` of a block in tendermint.
+pub const BLOCK_MAX_BYTES_DEFAULT: u64 = 22020096;
+
 static LEDGER_SNAPSHOT_PATH: Lazy<String> = Lazy::new(|| {
     let dir = format!("{}/overeality/ledger", vsdb::vsdb_get_custom_dir());
     pnk!(fs::create_dir_all(&dir));
@@ -99,6 +122,12 @@ impl Ledger {
         })
     }
 
+    // Node-local, not a part of the consensus.
+    #[inline(always)]
+    pub fn set_pruning(&self, pruning: Pruning) {
+        self.main.write().pruning = pruning;
+    }
+
     #[inline(always)]
     pub fn consensus_refresh(&self, proposer: TmAddress, timestamp: u64) -> Result<()> {
         self.refresh_inner(proposer, timestamp, false).c(d!())
@@ -152,6 +181,38 @@ impl Ledger {
         Ok(())
     }
 
+    // Return the upgrade plan if this binary can not handle it,
+    // the node must be halted and replaced by a new binary in this case.
+    //
+    // NOTE:
+    // - Call this after `consensus_refresh` in the 'BeginBlock' field of ABCI,
+    // before any other operations
+    #[inline(always)]
+    pub fn upgrade(&self) -> Result<Option<UpgradePlan>> {
+        self.deliver_tx.read().upgrade().c(d!())
+    }
+
+    // The double signers come with the heights of their infractions.
+    //
+    // NOTE:
+    // - Call this after `consensus_refresh` in the 'BeginBlock' field of ABCI
+    #[inline(always)]
+    pub fn slash_and_jail(
+        &self,
+        double_signers: Vec<(ValidatorId, BlockHeight)>,
+        votes: Vec<(ValidatorId, bool)>,
+    ) -> Result<()> {
+        self.deliver_tx
+            .read()
+            .slash_and_jail(double_signers, votes)
+            .c(d!())
+    }
+
+    #[inline(always)]
+    pub fn end_block(&self) -> Result<EndBlockResp> {
+        self.deliver_tx.write().end_block().c(d!())
+    }
+
     #[inline(always)]
     pub fn commit(&self) -> Result<HashValue> {
         let mut main = self.main.write();
@@ -162,12 +223,13 @@ impl Ledger {
                 &mut main.tx_hashes_in_process,
                 &mut deliver_tx.tx_hashes_in_process,
             );
+            main.versions_in_process += deliver_tx.versions_in_process;
             // The `DELIVER_TX` branch will be deleted automatically.
             self.state
                 .branch_merge_to_parent(DELIVER_TX_BRANCH_NAME)
                 .c(d!())?;
         }
-        main.commit().c(d!()).map(|_| main.last_block_hash())
+        main.commit().c(d!()).map(|_| main.last_state_root())
     }
 
     #[inline(always)]
@@ -198,7 +260,14 @@ pub struct StateBranch {
     pub state: State,
     pub branch: Vec<u8>,
     tx_hashes_in_process: Vec<HashValue>,
+    // gas consumed by the transactions in the current block
+    gas_used_in_process: U256,
     block_in_process: Block,
+    // versions created on this branch in the current block,
+    // the merged ones are added to the 'main' branch in the 'Commit'
+    versions_in_process: u64,
+    // only used by the 'main' branch
+    pruning: Pruning,
 }
 
 impl StateBranch {
@@ -211,7 +280,10 @@ impl StateBranch {
             state: s,
             branch: branch.0.to_owned(),
             tx_hashes_in_process: vec![],
+            gas_used_in_process: U256::zero(),
             block_in_process: Block::default(),
+            versions_in_process: 0,
+            pruning: Pruning::default(),
         })
     }
 
@@ -220,20 +292,29 @@ impl StateBranch {
     // - Call this in the 'BeginBlock' field of ABCI
     fn prepare_next_block(&mut self, proposer: TmAddress, timestamp: u64) -> Result<()> {
         self.tx_hashes_in_process.clear();
+        self.gas_used_in_process = U256::zero();
 
         let (h, prev_hash) = self
             .last_block()
             .map(|b| (b.header.height, b.header_hash))
             .unwrap_or_default();
         self.block_in_process = Block::new(1 + h, proposer, timestamp, prev_hash);
+        self.versions_in_process = 0;
 
         let b = self.branch.clone();
         let b = b.as_slice().into();
 
         let ver = VsVersion::new(self.block_in_process.header.height, 0);
-        self.state
-            .version_create_by_branch(ver.encode_value().as_ref().into(), b)
-            .c(d!())?;
+        self.version_create(ver, b).c(d!())?;
+
+        // the values at the beginning of the block
+        self.block_in_process.header.gas_limit = self
+            .state
+            .evm
+            .block_gas_limit
+            .get_value_by_branch(b)
+            .unwrap_or_default();
+        self.block_in_process.header.base_fee_per_gas = self.state.evm.base_fee(b);
 
         if b == MAIN_BRANCH_NAME {
             self.update_evm_aux(b);
@@ -262,25 +343,45 @@ impl StateBranch {
         let b = self.branch.clone();
         let b = b.as_slice().into();
 
+        let gas_limit = tx.gas_limit();
+        let block_gas_limit = self
+            .state
+            .evm
+            .block_gas_limit
+            .get_value_by_branch(b)
+            .unwrap_or(U256::MAX);
+        if block_gas_limit < gas_limit {
+            return Err(eg!(
+                "Gas limit {} exceeds the block gas limit {}",
+                gas_limit,
+                block_gas_limit
+            ));
+        }
+
+        // the 'check_tx' branch is not limited,
+        // there may be transactions of many blocks in the mempool
+        if b == DELIVER_TX_BRANCH_NAME
+            && block_gas_limit < self.gas_used_in_process.saturating_add(gas_limit)
+        {
+            return Err(eg!(
+                "Block gas limit exceeded, used: {}, needed: {}",
+                self.gas_used_in_process,
+                gas_limit
+            ));
+        }
+
         let ver = VsVersion::new(
             self.block_in_process.header.height,
             1 + self.tx_hashes_in_process.len() as u64,
         );
-        self.state
-            .version_create_by_branch(ver.encode_value().as_ref().into(), b)
-            .c(d!())?;
+        self.version_create(ver, b).c(d!())?;
 
         let tx_hash = tx.hash();
 
         macro_rules! create_version_if_first_tx_failed {
             () => {
                 if !self.state.branch_has_versions(b) {
-                    self.state
-                        .version_create_by_branch(
-                            VsVersion::default().encode_value().as_ref().into(),
-                            b,
-                        )
-                        .c(d!())?;
+                    self.version_create(VsVersion::default(), b).c(d!())?;
                 }
             };
         }
@@ -289,39 +390,46 @@ impl StateBranch {
             Tx::Evm(evm_tx) => evm_tx
                 .apply(self, b, false)
                 .map(|(ret, mut receipt)| {
-                    self.charge_fee(ret.caller, ret.fee_used, b);
+                    self.charge_evm_fee(&ret, b);
+                    self.add_gas_used(ret.gas_used);
                     self.tx_hashes_in_process.push(tx_hash.clone());
                     self.block_in_process.txs.push(tx);
 
-                    let mut logs = ret.gen_logs(&tx_hash);
-                    receipt.add_logs(logs.as_mut_slice());
+                    // the logs take the index of the transaction from the receipt
                     receipt.tx_hash = tx_hash.clone();
                     receipt.tx_index = self.tx_hashes_in_process.len() as u64 - 1;
+                    let mut logs = ret.gen_logs(&tx_hash);
+                    receipt.add_logs(logs.as_mut_slice());
                     self.block_in_process
                         .header
                         .receipts
                         .insert(tx_hash, receipt);
                 })
                 .or_else(|e| {
-                    pnk!(self.state.version_pop_by_branch(b));
+                    pnk!(self.version_pop(b));
                     if let Some(ret) = e.as_ref() {
                         create_version_if_first_tx_failed!();
-                        self.charge_fee(ret.caller, ret.fee_used, b);
+                        self.charge_evm_fee(ret, b);
+                        self.bump_nonce(ret.caller, b);
+                        self.add_gas_used(ret.gas_used);
                     }
                     Err(eg!(e.map(|e| e.to_string()).unwrap_or_default()))
                 })?,
             Tx::Native(native_tx) => native_tx
                 .apply(self, b)
                 .map(|ret| {
-                    self.charge_fee(ret.caller, ret.fee_used, b);
+                    self.charge_fee(&ret, b);
+                    self.add_gas_used(gas_limit);
                     self.tx_hashes_in_process.push(tx_hash);
                     self.block_in_process.txs.push(tx);
                 })
                 .or_else(|e| {
-                    pnk!(self.state.version_pop_by_branch(b));
+                    pnk!(self.version_pop(b));
                     if let Some(ret) = e.as_ref() {
                         create_version_if_first_tx_failed!();
-                        self.charge_fee(ret.caller, ret.fee_used, b);
+                        self.charge_fee(ret, b);
+                        self.bump_nonce(ret.caller, b);
+                        self.add_gas_used(gas_limit);
                     }
                     Err(eg!(e.map(|e| e.to_string()).unwrap_or_default()))
                 })?,
@@ -330,6 +438,175 @@ impl StateBranch {
         Ok(())
     }
 
+    // All versions of the blocks are created and popped by these two,
+    // so the pruning knows the exact number of versions of each block.
+    #[inline(always)]
+    fn version_create(&mut self, ver: VsVersion, b: BranchName) -> Result<()> {
+        self.state
+            .version_create_by_branch(ver.encode_value().as_ref().into(), b)
+            .c(d!())?;
+        self.versions_in_process += 1;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn version_pop(&mut self, b: BranchName) -> Result<()> {
+        self.state.version_pop_by_branch(b).c(d!())?;
+        self.versions_in_process -= 1;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn add_gas_used(&mut self, gas: U256) {
+        self.gas_used_in_process = self.gas_used_in_process.saturating_add(gas);
+    }
+
+    // NOTE:
+    // - Only triggered by the 'deliver_tx' branch of `Ledger`
+    fn upgrade(&self) -> Result<Option<UpgradePlan>> {
+        let b = self.branch.clone();
+        let b = b.as_slice().into();
+
+        let plan = match self.state.upgrade_plan.get_value_by_branch(b).flatten() {
+            Some(plan) if plan.height == self.block_in_process.header.height => plan,
+            _ => return Ok(None),
+        };
+
+        let migrate = if let Some(m) = upgrade::migration(&plan.name) {
+            m
+        } else {
+            return Ok(Some(plan));
+        };
+
+        migrate(&self.state, b).c(d!("Migration `{}` failed", plan.name))?;
+        self.state
+            .chain_version
+            .set_value_by_branch(plan.name, b)
+            .c(d!())?;
+        self.state
+            .upgrade_plan
+            .set_value_by_branch(None, b)
+            .c(d!())
+            .map(|_| None)
+    }
+
+    // NOTE:
+    // - Only triggered by the 'deliver_tx' branch of `Ledger`
+    fn slash_and_jail(
+        &self,
+        double_signers: Vec<(ValidatorId, BlockHeight)>,
+        votes: Vec<(ValidatorId, bool)>,
+    ) -> Result<()> {
+        let b = self.branch.clone();
+        let b = b.as_slice().into();
+        let h = self.block_in_process.header.height;
+
+        let unbonding_blocks = self.state.staking.params(b).unbonding_blocks;
+        for (id, infraction_height) in double_signers.iter() {
+            // the stake bonded at that time may have been released
+            if unbonding_blocks <= h.saturating_sub(*infraction_height) {
+                continue;
+            }
+            self.state
+                .staking
+                .slash_double_sign(&self.state.evm.OFUEL, id, b)
+                .c(d!())?;
+        }
+
+        for (id, signed) in votes.iter() {
+            self.state.staking.handle_vote(id, *signed, h, b).c(d!())?;
+        }
+
+        Ok(())
+    }
+
+    // NOTE:
+    // - Only triggered by the 'deliver_tx' branch of `Ledger`
+    // - Call this in the 'EndBlock' field of ABCI
+    fn end_block(&mut self) -> Result<EndBlockResp> {
+        let b = self.branch.clone();
+        let b = b.as_slice().into();
+
+        let ver = VsVersion::new(
+            self.block_in_process.header.height,
+            1 + self.tx_hashes_in_process.len() as u64,
+        );
+        self.version_create(ver, b).c(d!())?;
+
+        self.state
+            .staking
+            .release_unbonding(
+                &self.state.evm.OFUEL,
+                self.block_in_process.header.height,
+                b,
+            )
+            .c(d!())?;
+
+        self.block_in_process.header.gas_used = self.gas_used_in_process;
+        self.state
+            .evm
+            .update_base_fee(self.gas_used_in_process, b)
+            .c(d!())?;
+
+        // NOTE: must be done before syncing the params to tendermint,
+        // and after updating the base fee, so a governance change will take effect
+        gov::end_block(self, b).c(d!())?;
+
+        // NOTE: must be done before updating the consensus set
+        self.state
+            .staking
+            .distribute_rewards(
+                &self.state.evm.OFUEL,
+                &self.block_in_process.header.proposer,
+                b,
+            )
+            .c(d!())?;
+
+        let validator_updates = self.state.staking.update_consensus_set(b).c(d!())?;
+        let block_params = self.update_block_params(b).c(d!())?;
+
+        Ok(EndBlockResp {
+            validator_updates,
+            block_params,
+        })
+    }
+
+    // Return the new block params if they are different from
+    // the ones that tendermint currently uses.
+    fn update_block_params(&self, b: BranchName) -> Result<Option<BlockParams>> {
+        let max_gas = self
+            .state
+            .evm
+            .block_gas_limit
+            .get_value_by_branch(b)
+            .unwrap_or_default();
+        let params = BlockParams {
+            max_bytes: self
+                .state
+                .block_max_bytes
+                .get_value_by_branch(b)
+                .unwrap_or(BLOCK_MAX_BYTES_DEFAULT)
+                .try_into()
+                .c(d!())?,
+            // `-1` means no limitation in tendermint
+            max_gas: alt!(
+                max_gas > U256::from(i64::MAX as u64),
+                -1,
+                max_gas.as_u64() as i64
+            ),
+        };
+
+        if Some(params) == self.state.synced_block_params.get_value_by_branch(b) {
+            Ok(None)
+        } else {
+            self.state
+                .synced_block_params
+                .set_value_by_branch(params, b)
+                .c(d!())?;
+            Ok(Some(params))
+        }
+    }
+
     // NOTE:
     // - Only triggered by the 'main' branch of the `Ledger`
     fn commit(&mut self) -> Result<()> {
@@ -347,40 +624,143 @@ impl StateBranch {
 
         self.block_in_process.header.tx_merkle.tree = mt.into();
         self.block_in_process.header.tx_merkle.root_hash = root;
-
-        // Calculate the total amount of block gas to be used
-        let mut block_gas_used: U256 = U256::zero();
-        self.block_in_process
-            .header
-            .receipts
-            .iter()
-            .for_each(|(_, r)| {
-                block_gas_used += r.tx_gas_used;
-            });
-        let mut b = Bloom::from_slice(self.block_in_process.bloom.as_slice());
+        self.block_in_process.header.tx_merkle.leaves =
+            self.tx_hashes_in_process.clone();
+
+        // The gas used, the logs bloom and the log indexes of each receipt,
+        // accumulated in the order of transactions
+        let mut cumulative_gas_used = U256::zero();
+        let mut log_index = 0;
+        let header = &mut self.block_in_process.header;
+        let mut b = Bloom::from_slice(header.logs_bloom.as_slice());
         for hash in self.tx_hashes_in_process.iter() {
-            if let Some(mut r) = self.block_in_process.header.receipts.get_mut(hash) {
-                r.block_gas_used = block_gas_used;
-                handle_bloom(&mut b, r.logs.as_slice());
+            if let Some(mut r) = header.receipts.get_mut(hash) {
+                cumulative_gas_used = cumulative_gas_used.saturating_add(r.tx_gas_used);
+                r.cumulative_gas_used = cumulative_gas_used;
+
+                for l in r.logs.iter_mut() {
+                    l.log_index_in_block = log_index;
+                    log_index += 1;
+                }
+
+                let mut rb = Bloom::default();
+                handle_bloom(&mut rb, r.logs.as_slice());
+                r.logs_bloom = rb.as_bytes().to_vec();
+                b.accrue_bloom(&rb);
             }
         }
+        header.logs_bloom = b.as_bytes().to_vec();
+
+        let mut receipt_hashes = self
+            .tx_hashes_in_process
+            .iter()
+            .filter_map(|h| header.receipts.get(h))
+            .map(|r| hash_sha3_256(&[&r.encode_value()]))
+            .collect::<Vec<_>>();
+        // Make it never empty, the same as the transaction merkle tree
+        receipt_hashes.push(hash_sha3_256(&[&[]]));
+        let receipt_hashes = receipt_hashes
+            .iter()
+            .map(|h| h.as_slice())
+            .collect::<Vec<_>>();
+        header.receipts_root = MerkleTree::new(&receipt_hashes)
+            .get_root()
+            .unwrap()
+            .to_vec();
+
+        let root = self
+            .state
+            .update_state_root(self.branch.as_slice().into())
+            .c(d!())?;
+        self.block_in_process.header.state_root = root.as_bytes().to_vec();
 
-        self.block_in_process.bloom = b.as_bytes().to_vec();
         self.block_in_process.header_hash = self.block_in_process.header.hash();
 
         let block = mem::take(&mut self.block_in_process);
 
-        self.state.evm.block_hashes.insert(
-            block.header.height,
-            block_hash_to_evm_format(&block.header_hash),
-        );
+        let block_hash = block_hash_to_evm_format(&block.header_hash);
+        self.state
+            .evm
+            .block_hashes
+            .insert(block.header.height, block_hash);
+        self.state
+            .evm
+            .block_heights
+            .insert(block_hash, block.header.height);
 
-        self.state.blocks.insert(block.header.height, block);
+        // the last one is the sentinel hash of the merkle tree
+        for (idx, hash) in self.tx_hashes_in_process[..block.txs.len()]
+            .iter()
+            .enumerate()
+        {
+            self.state
+                .tx_positions
+                .insert(hash.clone(), (block.header.height, idx as u64));
+        }
+
+        // A checkpoint named by the height, the historical queries fork from it,
+        // the names of the merged versions are bound to the `DELIVER_TX` branch.
+        let height = block.header.height;
+        let b = self.branch.clone();
+        self.version_create(VsVersion::block_end(height), b.as_slice().into())
+            .c(d!())?;
+
+        // Versions of this block on the 'main' branch: the ones created by itself,
+        // and the merged ones of the `DELIVER_TX` branch.
+        let versions = self.versions_in_process;
+        let versions = height
+            .checked_sub(1)
+            .and_then(|h| self.state.version_counts.get(&h))
+            .unwrap_or_default()
+            + versions;
+        self.state.version_counts.insert(height, versions);
+
+        self.state.blocks.insert(height, block);
+
+        self.prune().c(d!())?;
 
         vsdb::vsdb_flush();
         self.write_snapshot().c(d!())
     }
 
+    // Squash the versions older than the retention window into the oldest kept one,
+    // the `blocks` and the indexes are not affected.
+    fn prune(&self) -> Result<()> {
+        let last = self.last_block_height();
+        let keep = match self.pruning {
+            Pruning::Archive => return Ok(()),
+            Pruning::KeepLast(n) => n,
+            Pruning::KeepEvery(n) => {
+                if 0 == last % n {
+                    self.state.keep(last).c(d!())?;
+                }
+                1
+            }
+        };
+
+        if last <= keep {
+            return Ok(());
+        }
+        let earliest = last - keep + 1;
+
+        // not enough records, eg. the pruning has just been enabled
+        let (total, before) = match (
+            self.state.version_counts.get(&last),
+            self.state.version_counts.get(&(earliest - 1)),
+        ) {
+            (Some(t), Some(b)) => (t, b),
+            _ => return Ok(()),
+        };
+
+        let b = self.branch.clone();
+        self.state
+            .prune_by_branch(b.as_slice().into(), Some((total - before) as usize))
+            .c(d!())?;
+        *self.state.evm.earliest_height.get_mut() = earliest;
+
+        Ok(())
+    }
+
     fn update_evm_aux(&mut self, b: BranchName) {
         self.state.evm.update_vicinity(
             U256::from(self.state.chain_id.get_value_by_branch(b).unwrap()),
@@ -399,18 +779,56 @@ impl StateBranch {
         &mut self.state.evm
     }
 
+    // The fee of a successful transaction has been deducted before the execution,
+    // the failed ones must be charged here, for their changes have been discarded.
     #[inline(always)]
-    fn charge_fee(&self, caller: H160, amount: U256, b: BranchName) {
-        alt!(amount.is_zero(), return);
-        let mut account = pnk!(self.state.evm.OFUEL.accounts.get_by_branch(&caller, b));
-        account.balance = account.balance.saturating_sub(amount);
-        pnk!(
-            self.state
-                .evm
-                .OFUEL
-                .accounts
-                .insert_by_branch(caller, account, b)
-        );
+    fn charge_fee(&self, ret: &NativeExecRet, b: BranchName) {
+        if !ret.success {
+            self.debit_fee(ret.caller, ret.fee_used, b);
+        }
+
+        // will be distributed in the 'EndBlock'
+        pnk!(self.state.staking.add_fee(ret.fee_used, b));
+    }
+
+    // The base fee part is burned, and the tip goes to the proposer.
+    //
+    // The fee of a successful transaction has been deducted during the execution,
+    // the failed ones must be charged here, for their changes have been discarded.
+    fn charge_evm_fee(&self, ret: &EvmExecRet, b: BranchName) {
+        let token = &self.state.evm.OFUEL;
+        if !ret.success {
+            self.debit_fee(ret.caller, ret.fee_used, b);
+        }
+        let burned = min!(ret.fee_used, ret.fee_burned);
+        pnk!(token.destroy(burned, b));
+
+        let tip = ret.fee_used - burned;
+        let proposer = &self.block_in_process.header.proposer;
+        if let Some(v) = self.state.staking.validator_get(proposer, b) {
+            pnk!(token.credit(v.operator, tip, b));
+        } else {
+            // will be distributed in the 'EndBlock'
+            pnk!(self.state.staking.add_fee(tip, b));
+        }
+    }
+
+    // The nonce of a failed transaction must also be consumed,
+    // or it can be replayed.
+    #[inline(always)]
+    fn bump_nonce(&self, caller: H160, b: BranchName) {
+        let token = &self.state.evm.OFUEL;
+        let mut account = token.accounts.get_by_branch(&caller, b).unwrap_or_default();
+        account.nonce = account.nonce.saturating_add(U256::one());
+        pnk!(token.account_set(caller, account, b));
+    }
+
+    // The balance has been checked in the `pre_exec`,
+    // and the changes of the failed transaction have been discarded,
+    // so this will never underflow.
+    #[inline(always)]
+    fn debit_fee(&self, caller: H160, amount: U256, b: BranchName) {
+        pnk!(self.state.evm.OFUEL.debit(caller, amount, b));
     }
 
     // #[inline(always)]
@@ -418,6 +836,12 @@ impl StateBranch {
     //     self.branch.as_slice().into()
     // }
 
+    // The height of the block being processed.
+    #[inline(always)]
+    pub(crate) fn height_in_process(&self) -> BlockHeight {
+        self.block_in_process.header.height
+    }
+
     #[inline(always)]
     pub fn last_block(&self) -> Option<Block> {
         self.state.blocks.last().map(|(_, b)| b)
@@ -433,24 +857,100 @@ impl StateBranch {
         self.last_block().unwrap_or_default().header_hash
     }
 
+    // Used as the app hash of tendermint.
     #[inline(always)]
+    pub fn last_state_root(&self) -> HashValue {
+        self.last_block().unwrap_or_default().header.state_root
+    }
+
     fn load_from_snapshot() -> Result<Option<Self>> {
-        match fs::read(&*LEDGER_SNAPSHOT_PATH) {
-            Ok(c) => StateBranch::decode(c.as_slice()).c(d!()).map(Some),
-            Err(e) => {
-                if let ErrorKind::NotFound = e.kind() {
-                    Ok(None)
-                } else {
-                    Err(e).c(d!())
-                }
-            }
-        }
+        let meta = match fs::read(&*LEDGER_SNAPSHOT_PATH) {
+            Ok(c) => LedgerMeta::decode(c.as_slice()).c(d!())?,
+            Err(e) if ErrorKind::NotFound == e.kind() => return Ok(None),
+            Err(e) => return Err(e).c(d!()),
+        };
+
+        meta.check().c(d!())?;
+        StateBranch::new(&meta.state, meta.branch.as_slice().into())
+            .c(d!())
+            .map(Some)
     }
 
-    #[inline(always)]
+    // Only the metadata is written here, the data has been flushed into vsdb.
+    //
+    // NOTE:
+    // - Write to a temporary file and rename it,
+    // so a crash will never leave a partially written one
     fn write_snapshot(&self) -> Result<()> {
-        let contents = self.encode();
-        fs::write(&*LEDGER_SNAPSHOT_PATH, &contents).c(d!())
+        let meta = LedgerMeta {
+            version: LEDGER_META_VERSION,
+            branch: self.branch.clone(),
+            last_height: self.last_block_height(),
+            last_hash: self.last_block_hash(),
+            state: self.state.clone(),
+        };
+
+        let path = Path::new(&*LEDGER_SNAPSHOT_PATH);
+        let tmp = path.with_extension("tmp");
+
+        let mut f = fs::File::create(&tmp).c(d!())?;
+        f.write_all(&meta.encode()).c(d!())?;
+        f.sync_all().c(d!())?;
+
+        fs::rename(&tmp, path).c(d!())?;
+
+        // persist the renaming
+        let dir = path.parent().c(d!())?;
+        fs::File::open(dir).c(d!())?.sync_all().c(d!())
+    }
+}
+
+// Bump it if the layout of `LedgerMeta` changes.
+const LEDGER_META_VERSION: u32 = 1;
+
+// Everything needed to reopen the ledger,
+// `State` only contains the handles of the vsdb instances, not the data.
+#[derive(Deserialize, Serialize)]
+struct LedgerMeta {
+    version: u32,
+    branch: Vec<u8>,
+    last_height: BlockHeight,
+    last_hash: HashValue,
+    state: State,
+}
+
+impl LedgerMeta {
+    // Ensure the metadata matches the data in vsdb.
+    fn check(&self) -> Result<()> {
+        if LEDGER_META_VERSION != self.version {
+            return Err(eg!(
+                "Unsupported ledger metadata version: {}, should be: {}",
+                self.version,
+                LEDGER_META_VERSION
+            ));
+        }
+
+        if MAIN_BRANCH_NAME.0 != self.branch.as_slice()
+            || !self.state.branch_exists(MAIN_BRANCH_NAME)
+        {
+            return Err(eg!("The main branch is missing"));
+        }
+
+        let (height, hash) = self
+            .state
+            .blocks
+            .last()
+            .map(|(h, b)| (h, b.header_hash))
+            .unwrap_or_default();
+        if height != self.last_height || hash != self.last_hash {
+            return Err(eg!(
+                "Ledger metadata mismatch, expected block: {}, found: {}",
+                self.last_height,
+                height
+            ));
+        }
+
+        Ok(())
     }
 }
 
@@ -459,15 +959,141 @@ pub struct State {
     pub chain_id: OrphanVs<u64>,
     pub chain_name: OrphanVs<String>,
     pub chain_version: OrphanVs<String>,
+    // approved by governance, cleared after the upgrade is done
+    pub upgrade_plan: OrphanVs<Option<UpgradePlan>>,
+
+    // the `max_bytes` field of the block params of tendermint
+    pub block_max_bytes: OrphanVs<u64>,
+    // the block params that have been sent to tendermint
+    pub synced_block_params: OrphanVs<BlockParams>,
 
     pub evm: ethvm::State,
     pub staking: staking::State,
+    pub gov: gov::State,
+
+    // sparse merkle tree of the world state
+    pub smt: smt::State,
 
     // maintained by the 'main' branch only
     pub blocks: MapxOrd<BlockHeight, Block>,
+    // tx hash => (block height, index in the block)
+    pub tx_positions: Mapx<HashValue, (BlockHeight, u64)>,
+    // height => the number of versions on the 'main' branch
+    // that have been created until the end of this block, used by the pruning
+    pub version_counts: MapxOrd<BlockHeight, u64>,
 }
 
 impl State {
+    /// Ensure that all the OFUEL, in evm balances or held by the staking module,
+    /// sum up to the total supply of the token.
+    ///
+    /// It scans the whole state, only for tests and offline checks.
+    pub fn check_supply(&self, b: BranchName) -> Result<()> {
+        let supply = self.evm.OFUEL.total_supply(b);
+        let balances = self.evm.OFUEL.balances_sum(b);
+        let locked = self.staking.locked_sum(b);
+        if balances.saturating_add(locked) != supply {
+            return Err(eg!(
+                "Total supply mismatch, supply: {}, balances: {}, locked: {}",
+                supply,
+                balances,
+                locked
+            ));
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn block_by_hash(&self, hash: &H256) -> Option<(BlockHeight, Block)> {
+        let height = self.evm.block_heights.get(hash)?;
+        self.blocks.get(&height).map(|b| (height, b))
+    }
+
+    /// The block containing the transaction, and the index of it in the block.
+    #[inline(always)]
+    pub fn block_by_tx_hash(&self, tx_hash: &[u8]) -> Option<(BlockHeight, Block, u64)> {
+        let (height, idx) = self.tx_positions.get(&tx_hash.to_vec())?;
+        self.blocks.get(&height).map(|b| (height, b, idx))
+    }
+
+    // Copy the state at the end of this height from the 'main' branch to its own one,
+    // then the 'main' branch can be pruned without affecting it.
+    //
+    // NOTE:
+    // - vsdb can only squash the oldest versions of a branch,
+    // so the states in between can not be dropped by other means
+    fn keep(&self, height: BlockHeight) -> Result<()> {
+        let name = kept_branch_name(height);
+        let b = BranchName::from(name.as_str());
+        self.branch_create_by_base_branch(b, ParentBranchName::from(MAIN_BRANCH_NAME.0))
+            .c(d!())?;
+        self.version_create_by_branch(
+            VsVersion::block_end(height).encode_value().as_ref().into(),
+            b,
+        )
+        .c(d!())?;
+
+        // the staged leaves have been applied in the 'Commit'
+        detach_value(&self.chain_id, b).c(d!())?;
+        detach_value(&self.chain_name, b).c(d!())?;
+        detach_value(&self.chain_version, b).c(d!())?;
+        detach_value(&self.upgrade_plan, b).c(d!())?;
+        detach_value(&self.block_max_bytes, b).c(d!())?;
+        detach_value(&self.synced_block_params, b).c(d!())?;
+        self.evm.detach(b).c(d!())?;
+        self.staking.detach(b).c(d!())?;
+        self.gov.detach(b).c(d!())?;
+        self.smt.detach(b).c(d!())
+    }
+
+    // Apply the leaves changed since the last commit to the state tree,
+    // and return the new root.
+    fn update_state_root(&self, b: BranchName) -> Result<H256> {
+        for changes in [
+            &self.evm.OFUEL.changes,
+            &self.staking.changes,
+            &self.gov.changes,
+        ] {
+            for (key, leaf) in changes.take(b).c(d!())?.into_iter() {
+                self.smt.update(key, leaf, b).c(d!())?;
+            }
+        }
+        for (key, leaf) in self.param_leaves(b).into_iter() {
+            if self.smt.leaf(key, b) != leaf {
+                self.smt.update(key, leaf, b).c(d!())?;
+            }
+        }
+        Ok(self.smt.root(b))
+    }
+
+    // The parameters are few and rarely changed,
+    // so they are compared with the tree instead of being staged on each write.
+    fn param_leaves(&self, b: BranchName) -> Vec<(H256, H256)> {
+        macro_rules! leaf {
+            ($name: expr, $value: expr) => {
+                (
+                    smt::record_key(b"param", &$name),
+                    smt::record_leaf(&$value.get_value_by_branch(b)),
+                )
+            };
+        }
+
+        let evm = &self.evm;
+        vec![
+            leaf!("chain_version", self.chain_version),
+            leaf!("upgrade_plan", self.upgrade_plan),
+            leaf!("block_max_bytes", self.block_max_bytes),
+            leaf!("total_supply", evm.OFUEL.total_supply),
+            leaf!("gas_price", evm.gas_price),
+            leaf!("block_gas_limit", evm.block_gas_limit),
+            leaf!("block_base_fee_per_gas", evm.block_base_fee_per_gas),
+            leaf!("hardforks", evm.hardforks),
+            leaf!("reject_unprotected_txs", evm.reject_unprotected_txs),
+            leaf!("staking_params", self.staking.params),
+            leaf!("gov_params", self.gov.params),
+        ]
+    }
+
     fn refresh_branches(&self) -> Result<()> {
         self.branch_remove(CHECK_TX_BRANCH_NAME).c(d!())?;
 
@@ -491,14 +1117,72 @@ impl State {
     }
 }
 
+/// The branch holding the state of a height kept by `Pruning::KeepEvery`.
+#[inline(always)]
+pub fn kept_branch_name(height: BlockHeight) -> String {
+    format!("Kept_{}", height)
+}
+
+/// How long the historical state is kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Pruning {
+    // keep all the versions
+    Archive,
+    // keep the state of the last N blocks
+    KeepLast(u64),
+    // keep the state of every Nth block and the latest one,
+    // each of the kept states is a full copy, see `State::keep`
+    KeepEvery(u64),
+}
+
+impl Default for Pruning {
+    fn default() -> Self {
+        Pruning::Archive
+    }
+}
+
+impl fmt::Display for Pruning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pruning::Archive => write!(f, "archive"),
+            Pruning::KeepLast(n) => write!(f, "keep-last:{}", n),
+            Pruning::KeepEvery(n) => write!(f, "keep-every:{}", n),
+        }
+    }
+}
+
+impl FromStr for Pruning {
+    type Err = String;
+
+    // "archive", "keep-last:<N>" or "keep-every:<N>", N > 0
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        if "archive" == s {
+            return Ok(Pruning::Archive);
+        }
+
+        let (mode, n) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid pruning mode: {}", s))?;
+        let n = n
+            .parse::<u64>()
+            .ok()
+            .filter(|n| 0 < *n)
+            .ok_or_else(|| format!("Invalid number of blocks: {}", n))?;
+
+        match mode {
+            "keep-last" => Ok(Pruning::KeepLast(n)),
+            "keep-every" => Ok(Pruning::KeepEvery(n)),
+            _ => Err(format!("Invalid pruning mode: {}", s)),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Block {
     pub header: BlockHeader,
     pub header_hash: HashValue,
     // transaction vec
     pub txs: Vecx<Tx>,
-    // bloom
-    pub bloom: Vec<u8>,
 }
 
 impl Block {
@@ -514,15 +1198,54 @@ impl Block {
             proposer,
             timestamp,
             prev_hash,
+            logs_bloom: Bloom::default().as_bytes().to_vec(),
             ..Default::default()
         };
         Self {
             header,
             txs: Vecx::new(),
-            bloom: Bloom::default().as_bytes().to_vec(),
             ..Default::default()
         }
     }
+
+    /// The merkle path of the transaction at `tx_index` in `header.tx_merkle`,
+    /// the index can be found by `State::block_by_tx_hash`.
+    pub fn tx_proof(&self, tx_index: u64) -> Option<TxProof> {
+        let leaves = &self.header.tx_merkle.leaves;
+
+        // the last leaf is the sentinel hash
+        let tx_hash = leaves[..leaves.len().saturating_sub(1)]
+            .get(tx_index as usize)?
+            .clone();
+
+        let mut level = leaves
+            .iter()
+            .map(|h| hash_sha3_256(&[MERKLE_LEAF_PREFIX, h]))
+            .collect::<Vec<_>>();
+
+        let mut idx = tx_index as usize;
+        let mut path = vec![];
+        while 1 < level.len() {
+            // the last node is duplicated if the level length is odd
+            let sibling = idx ^ 1;
+            path.push(level.get(sibling).unwrap_or(&level[idx]).clone());
+
+            level = level
+                .chunks(2)
+                .map(|pair| {
+                    let r = pair.get(1).unwrap_or(&pair[0]);
+                    hash_sha3_256(&[MERKLE_INTERMEDIATE_PREFIX, &pair[0], r])
+                })
+                .collect();
+            idx /= 2;
+        }
+
+        Some(TxProof {
+            tx_hash,
+            tx_index,
+            path,
+        })
+    }
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -537,41 +1260,94 @@ pub struct BlockHeader {
     pub tx_merkle: TxMerkle,
     // hash of the previous block header
     pub prev_hash: HashValue,
+    // root of the world state after the current block,
+    // also the app hash of tendermint
+    pub state_root: HashValue,
+    // merkle root of the receipts, in the order of transactions
+    pub receipts_root: HashValue,
+    // bloom of all the logs in the current block
+    pub logs_bloom: Vec<u8>,
+    // gas consumed by all the transactions, including the failed ones
+    pub gas_used: U256,
+    pub gas_limit: U256,
+    pub base_fee_per_gas: U256,
     // execution results for each transaction
     pub receipts: BTreeMap<HashValue, Receipt>,
 }
 
 impl BlockHeader {
-    #[inline(always)]
+    // Keccak256 of the RLP encoding of an ethereum(London) header,
+    // so the web3 tools can verify it by the fields in `eth_getBlockBy*`.
+    //
+    // NOTE:
+    // - Unlike ethereum, `transactionsRoot` and `receiptsRoot` are not the roots
+    // of Patricia tries, but the sha3 roots of the `vsdb` merkle trees over
+    // the transaction hashes and the msgpack-encoded receipts, so they can not be
+    // recomputed from the block body, but the hash is still reproducible
     fn hash(&self) -> HashValue {
-        #[derive(Serialize)]
-        struct Contents<'a> {
-            height: BlockHeight,
-            proposer: TmAddressRef<'a>,
-            timestamp: u64,
-            merkle_root: HashValueRef<'a>,
-            prev_hash: HashValueRef<'a>,
-            receipts: &'a BTreeMap<HashValue, Receipt>,
-        }
-
-        let contents = Contents {
-            height: self.height,
-            proposer: &self.proposer,
-            timestamp: self.timestamp,
-            merkle_root: &self.tx_merkle.root_hash,
-            prev_hash: &self.prev_hash,
-            receipts: &self.receipts,
-        }
-        .encode_value();
-
-        hash_sha3_256(&[&contents])
+        let mut s = RlpStream::new_list(16);
+        s.append(&block_hash_to_evm_format(&self.prev_hash));
+        s.append(&*EMPTY_OMMERS_HASH);
+        s.append(&tm_proposer_to_evm_format(&self.proposer));
+        s.append(&block_hash_to_evm_format(&self.state_root));
+        s.append(&block_hash_to_evm_format(&self.tx_merkle.root_hash));
+        s.append(&block_hash_to_evm_format(&self.receipts_root));
+        s.append(&Bloom::from_slice(&self.logs_bloom));
+        s.append(&U256::zero()); // difficulty
+        s.append(&self.height);
+        s.append(&self.gas_limit);
+        s.append(&self.gas_used);
+        s.append(&self.timestamp);
+        s.append_empty_data(); // extra data
+        s.append(&H256::zero()); // mix hash
+        s.append(&H64::zero()); // nonce
+        s.append(&self.base_fee_per_gas);
+
+        hash_keccak_256(&[&s.out()])
     }
 }
 
+/// There are no ommers(uncles) in this chain,
+/// the `ommersHash` of all headers is the hash of an empty RLP list.
+pub static EMPTY_OMMERS_HASH: Lazy<H256> =
+    Lazy::new(|| H256::from_slice(&hash_keccak_256(&[&rlp::EMPTY_LIST_RLP])));
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct TxMerkle {
     pub root_hash: HashValue,
     pub tree: MerkleTreeStore,
+    // the hashes of the transactions and the sentinel,
+    // the nodes of `tree` are not accessible for building proofs
+    pub leaves: Vec<HashValue>,
+}
+
+// Node prefixes used by `vsdb::merkle`.
+const MERKLE_LEAF_PREFIX: &[u8] = &[0];
+const MERKLE_INTERMEDIATE_PREFIX: &[u8] = &[1];
+
+/// Proof of a transaction included in a block, see `Block::tx_proof`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TxProof {
+    pub tx_hash: HashValue,
+    // position in the block, decides the side of each sibling
+    pub tx_index: u64,
+    // siblings from the leaf up to the root
+    pub path: Vec<HashValue>,
+}
+
+/// Check a `TxProof` against the `tx_merkle.root_hash` of a block header.
+pub fn verify_tx_proof(root: &[u8], proof: &TxProof) -> bool {
+    let mut idx = proof.tx_index;
+    let mut hash = hash_sha3_256(&[MERKLE_LEAF_PREFIX, &proof.tx_hash]);
+    for sibling in proof.path.iter() {
+        hash = if 0 == idx % 2 {
+            hash_sha3_256(&[MERKLE_INTERMEDIATE_PREFIX, &hash, sibling])
+        } else {
+            hash_sha3_256(&[MERKLE_INTERMEDIATE_PREFIX, sibling, &hash])
+        };
+        idx /= 2;
+    }
+    0 == idx && hash == root
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -580,6 +1356,7 @@ pub struct VsVersion {
     // NOTE:
     // - starting from 1
     // - 0 is reserved for the block itself
+    // - `u64::MAX` is reserved for the end of the block
     tx_position: u64,
 }
 
@@ -590,6 +1367,12 @@ impl VsVersion {
             tx_position,
         }
     }
+
+    // The state after the whole block, created on the 'main' branch in the 'Commit'.
+    #[inline(always)]
+    pub fn block_end(block_height: BlockHeight) -> Self {
+        Self::new(block_height, u64::MAX)
+    }
 }
 
 impl Default for VsVersion {
@@ -598,6 +1381,17 @@ impl Default for VsVersion {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BlockParams {
+    pub max_bytes: i64,
+    pub max_gas: i64,
+}
+
+pub struct EndBlockResp {
+    pub validator_updates: Vec<ValidatorUpdate>,
+    pub block_params: Option<BlockParams>,
+}
+
 pub struct ApplyResp {
     pub receipt: Option<Receipt>,
     pub logs: Option<Vec<Log>>,
@@ -613,10 +1407,15 @@ pub struct Receipt {
     pub from: Option<H160>,
     // transaction recipients
     pub to: Option<H160>,
-    // the total amount of gas used for all transactions in this block
-    pub block_gas_used: U256,
+    // the total amount of gas used by this transaction
+    // and all the ones before it in this block
+    pub cumulative_gas_used: U256,
     // gas used for transaction
     pub tx_gas_used: U256,
+    // the price per gas actually paid
+    pub effective_gas_price: U256,
+    // EIP-2718 transaction type, 0 for the legacy ones
+    pub tx_type: u8,
     // here is contract address if recipients is none
     pub contract_addr: Option<H160>,
     // TODO: to be filled
@@ -625,6 +1424,8 @@ pub struct Receipt {
     pub status_code: bool,
     // logs
     pub logs: Vec<Log>,
+    // bloom of the logs above
+    pub logs_bloom: Vec<u8>,
 }
 
 impl Receipt {
@@ -0,0 +1,254 @@
+//!
+//! # World state root
+//!
+//! A sparse merkle tree with 256-bit keys, its leaves are the evm accounts,
+//! the contract storages, the OFUEL allowances, the staking and governance records,
+//! and the chain parameters.
+//!
+//! Each write to them stages the new leaf in a `ChangeSet`,
+//! and only the staged leaves are applied to the tree in the 'Commit' of ABCI,
+//! the root is stored in the block header and used as the app hash.
+//!
+
+use crate::{
+    common::{detach_map, hash_keccak_256},
+    ethvm::OvrAccount,
+};
+use once_cell::sync::Lazy;
+use primitive_types::{H160, H256, U256};
+use rlp::RlpStream;
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use vsdb::{BranchName, MapxVs, ValueEn, Vs};
+
+/// The height of the tree, also the bit length of the keys.
+pub const DEPTH: usize = 256;
+
+// Hashes of the empty subtrees, indexed by their heights,
+// an empty leaf is `H256::zero()`.
+static EMPTY_HASHES: Lazy<Vec<H256>> = Lazy::new(|| {
+    let mut hashes = vec![H256::zero()];
+    for h in 0..DEPTH {
+        hashes.push(merge(&hashes[h], &hashes[h]));
+    }
+    hashes
+});
+
+#[derive(Vs, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    // (height, path) => hash, the path is the key with the lowest `height` bits cleared,
+    // the leaves are at height 0 and the root is at height 256.
+    //
+    // The empty nodes are not stored.
+    nodes: MapxVs<(u16, H256), H256>,
+}
+
+impl State {
+    #[inline(always)]
+    pub fn root(&self, b: BranchName) -> H256 {
+        self.node(DEPTH, U256::zero(), b)
+    }
+
+    // Set a leaf, and update the hashes along the path to the root.
+    pub(crate) fn update(&self, key: H256, leaf: H256, b: BranchName) -> Result<()> {
+        let key = U256::from_big_endian(key.as_bytes());
+
+        let mut hash = leaf;
+        for height in 0..DEPTH {
+            self.set_node(height, path(&key, height), hash, b).c(d!())?;
+
+            let sibling = self.sibling(height, &key, b);
+            hash = if key.bit(height) {
+                merge(&sibling, &hash)
+            } else {
+                merge(&hash, &sibling)
+            };
+        }
+
+        self.set_node(DEPTH, U256::zero(), hash, b).c(d!())
+    }
+
+    // See `ledger::State::keep`.
+    #[inline(always)]
+    pub(crate) fn detach(&self, b: BranchName) -> Result<()> {
+        detach_map(&self.nodes, b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn leaf(&self, key: H256, b: BranchName) -> H256 {
+        self.node(0, U256::from_big_endian(key.as_bytes()), b)
+    }
+
+    /// The merkle proof of a leaf, can be checked by `verify`.
+    ///
+    /// The first item is a bitmap of the non-empty siblings from the bottom up,
+    /// and only the non-empty ones are followed in the same order.
+    pub fn proof(&self, key: H256, b: BranchName) -> Vec<H256> {
+        let key = U256::from_big_endian(key.as_bytes());
+
+        let mut bitmap = U256::zero();
+        let mut siblings = vec![];
+        for height in 0..DEPTH {
+            let sibling = self.sibling(height, &key, b);
+            if sibling != EMPTY_HASHES[height] {
+                bitmap = bitmap | (U256::one() << height);
+                siblings.push(sibling);
+            }
+        }
+
+        let mut proof = vec![u256_to_h256(bitmap)];
+        proof.append(&mut siblings);
+        proof
+    }
+
+    #[inline(always)]
+    fn sibling(&self, height: usize, key: &U256, b: BranchName) -> H256 {
+        self.node(height, path(key, height) ^ (U256::one() << height), b)
+    }
+
+    #[inline(always)]
+    fn node(&self, height: usize, path: U256, b: BranchName) -> H256 {
+        self.nodes
+            .get_by_branch(&(height as u16, u256_to_h256(path)), b)
+            .unwrap_or(EMPTY_HASHES[height])
+    }
+
+    #[inline(always)]
+    fn set_node(
+        &self,
+        height: usize,
+        path: U256,
+        hash: H256,
+        b: BranchName,
+    ) -> Result<()> {
+        let k = (height as u16, u256_to_h256(path));
+        if hash == EMPTY_HASHES[height] {
+            self.nodes.remove_by_branch(&k, b).c(d!()).map(|_| ())
+        } else {
+            self.nodes.insert_by_branch(k, hash, b).c(d!()).map(|_| ())
+        }
+    }
+}
+
+/// Check a proof generated by `State::proof`,
+/// use `H256::zero()` as the leaf to prove that a key does not exist.
+pub fn verify(root: &H256, key: &H256, leaf: &H256, proof: &[H256]) -> bool {
+    let (bitmap, siblings) = if let Some(p) = proof.split_first() {
+        p
+    } else {
+        return false;
+    };
+    let bitmap = U256::from_big_endian(bitmap.as_bytes());
+    let key = U256::from_big_endian(key.as_bytes());
+
+    let mut siblings = siblings.iter();
+    let mut hash = *leaf;
+    for height in 0..DEPTH {
+        let sibling = if bitmap.bit(height) {
+            if let Some(s) = siblings.next() {
+                *s
+            } else {
+                return false;
+            }
+        } else {
+            EMPTY_HASHES[height]
+        };
+        hash = if key.bit(height) {
+            merge(&sibling, &hash)
+        } else {
+            merge(&hash, &sibling)
+        };
+    }
+
+    siblings.next().is_none() && hash == *root
+}
+
+/// Leaves changed since the last commit.
+#[derive(Vs, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ChangeSet {
+    // key => leaf, zero if the leaf has been removed
+    leaves: MapxVs<H256, H256>,
+}
+
+impl ChangeSet {
+    #[inline(always)]
+    pub(crate) fn stage(&self, key: H256, leaf: H256, b: BranchName) -> Result<()> {
+        self.leaves
+            .insert_by_branch(key, leaf, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    // Return all the staged leaves, and clear them.
+    pub(crate) fn take(&self, b: BranchName) -> Result<Vec<(H256, H256)>> {
+        let leaves = self.leaves.iter_by_branch(b).collect::<Vec<_>>();
+        for (k, _) in leaves.iter() {
+            self.leaves.remove_by_branch(k, b).c(d!())?;
+        }
+        Ok(leaves)
+    }
+}
+
+#[inline(always)]
+pub fn account_key(addr: &H160) -> H256 {
+    keccak(&[addr.as_bytes()])
+}
+
+/// `keccak256(rlp([nonce, balance, code_hash]))`
+pub fn account_leaf(account: &OvrAccount) -> H256 {
+    let mut s = RlpStream::new_list(3);
+    s.append(&account.nonce);
+    s.append(&account.balance);
+    s.append(&code_hash(&account.code));
+    keccak(&[&s.out()])
+}
+
+#[inline(always)]
+pub fn code_hash(code: &[u8]) -> H256 {
+    keccak(&[code])
+}
+
+#[inline(always)]
+pub fn storage_key(addr: &H160, index: &H256) -> H256 {
+    keccak(&[addr.as_bytes(), index.as_bytes()])
+}
+
+#[inline(always)]
+pub fn storage_leaf(value: &H256) -> H256 {
+    alt!(value.is_zero(), H256::zero(), keccak(&[value.as_bytes()]))
+}
+
+// The other records are keyed by their kinds and their encoded keys,
+// eg. `record_key(b"validator", &id)`.
+#[inline(always)]
+pub fn record_key<K: ValueEn>(kind: &[u8], key: &K) -> H256 {
+    keccak(&[kind, &key.encode_value()])
+}
+
+#[inline(always)]
+pub fn record_leaf<V: ValueEn>(value: &V) -> H256 {
+    keccak(&[&value.encode_value()])
+}
+
+#[inline(always)]
+fn merge(left: &H256, right: &H256) -> H256 {
+    keccak(&[left.as_bytes(), right.as_bytes()])
+}
+
+#[inline(always)]
+fn keccak(contents: &[&[u8]]) -> H256 {
+    H256::from_slice(&hash_keccak_256(contents))
+}
+
+// The key with the lowest `height` bits cleared.
+#[inline(always)]
+fn path(key: &U256, height: usize) -> U256 {
+    alt!(DEPTH <= height, U256::zero(), (*key >> height) << height)
+}
+
+#[inline(always)]
+fn u256_to_h256(n: U256) -> H256 {
+    let mut buf = [0; 32];
+    n.to_big_endian(&mut buf);
+    H256::from(buf)
+}
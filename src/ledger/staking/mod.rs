@@ -2,17 +2,1162 @@
 //! # Data structures of staking
 //!
 
+use crate::{
+    common::{
+        detach_map, detach_value, tm_proposer_to_evm_format, BlockHeight, TmAddress,
+    },
+    ethvm::tx::token::{Erc20Like, DECIMAL},
+    ledger::{
+        smt::{self, ChangeSet},
+        StateBranch,
+    },
+};
+use once_cell::sync::Lazy;
+use primitive_types::{H160, H256, U256};
+use ruc::*;
 use serde::{Deserialize, Serialize};
-use vsdb::Vs;
+use std::collections::BTreeMap;
+use vsdb::{BranchName, MapxVs, OrphanVs, Vs};
+
+/// The max value of a commission rate, aka 100%.
+pub const COMMISSION_RATE_MAX: u16 = 10_000;
+
+/// How many OFUEL units make one unit of tendermint voting power.
+pub static POWER_UNIT: Lazy<U256> = Lazy::new(|| U256::exp10(DECIMAL as usize));
+
+/// The tendermint address of a validator,
+/// derived from its consensus public key.
+pub type ValidatorId = TmAddress;
 
 #[derive(Vs, Default, Clone, Debug, Deserialize, Serialize)]
 pub struct State {
-    // TODO
+    // tendermint address => validator
+    pub validators: MapxVs<ValidatorId, Validator>,
+
+    // (delegator addr, validator id) => delegation
+    pub delegations: MapxVs<(H160, ValidatorId), Delegation>,
+
+    // addr => the self-bond of the validators it operates plus its delegations,
+    // an index of the above two, so the stake of an address can be got directly
+    pub bonded: MapxVs<H160, U256>,
+
+    // mature height => OFUEL waiting to be released
+    pub unbonding: MapxVs<BlockHeight, Vec<Unbonding>>,
+    // mature height => redelegated OFUEL that can still be slashed
+    // for the misbehavior of the source validator
+    pub redelegations: MapxVs<BlockHeight, Vec<Redelegation>>,
+
+    // liveness records of validators
+    pub signing_infos: MapxVs<ValidatorId, SigningInfo>,
+    // (validator id, index in the sliding window) => missed or not,
+    // only the missed ones will be stored
+    pub missed_blocks: MapxVs<(ValidatorId, u64), bool>,
+
+    // fees collected in the current block
+    pub fee_pool: OrphanVs<U256>,
+    // addr => rewards that can be withdrawn
+    pub rewards: MapxVs<H160, U256>,
+
+    pub params: OrphanVs<Params>,
+
+    // the validator set that tendermint currently knows,
+    // validator id => (ed25519 public key, voting power),
+    // the public key is needed to remove a validator from tendermint
+    pub consensus_set: OrphanVs<BTreeMap<ValidatorId, (Vec<u8>, u64)>>,
+
+    // the changed validators, delegations, unbonding queues and rewards,
+    // for the state root
+    pub changes: ChangeSet,
 }
 
-// impl State {
-//     pub fn new() -> Self {
-//         // TODO
-//         Self {}
-//     }
-// }
+impl State {
+    #[inline(always)]
+    pub fn params(&self, b: BranchName) -> Params {
+        self.params.get_value_by_branch(b).unwrap_or_default()
+    }
+
+    // See `ledger::State::keep`.
+    pub(crate) fn detach(&self, b: BranchName) -> Result<()> {
+        detach_map(&self.validators, b).c(d!())?;
+        detach_map(&self.delegations, b).c(d!())?;
+        detach_map(&self.bonded, b).c(d!())?;
+        detach_map(&self.unbonding, b).c(d!())?;
+        detach_map(&self.redelegations, b).c(d!())?;
+        detach_map(&self.signing_infos, b).c(d!())?;
+        detach_map(&self.missed_blocks, b).c(d!())?;
+        detach_value(&self.fee_pool, b).c(d!())?;
+        detach_map(&self.rewards, b).c(d!())?;
+        detach_value(&self.params, b).c(d!())?;
+        detach_value(&self.consensus_set, b).c(d!())
+    }
+
+    // Register the validators from the genesis of tendermint,
+    // their evm-format coinbase addresses will be used as the operators.
+    //
+    // The self-bonded OFUEL are issued here.
+    pub(crate) fn init_genesis(
+        &self,
+        token: &Erc20Like,
+        validators: Vec<(Vec<u8>, u64)>,
+        b: BranchName,
+    ) -> Result<()> {
+        let mut set = BTreeMap::new();
+        for (pubkey, power) in validators.into_iter() {
+            let id = pubkey_to_validator_id(&pubkey).c(d!())?;
+            let self_bond = POWER_UNIT.saturating_mul(power.into());
+            token.issue(self_bond, b).c(d!())?;
+            let v = Validator {
+                pubkey: pubkey.clone(),
+                operator: tm_proposer_to_evm_format(&id),
+                self_bond,
+                delegated: U256::zero(),
+                commission: 0,
+                ..Default::default()
+            };
+            self.validator_set(id.clone(), v, b).c(d!())?;
+            set.insert(id, (pubkey, power));
+        }
+        self.consensus_set
+            .set_value_by_branch(set, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    // Calculate the new consensus set,
+    // and return the differences against the old one.
+    pub(crate) fn update_consensus_set(
+        &self,
+        b: BranchName,
+    ) -> Result<Vec<ValidatorUpdate>> {
+        let max_validators = self.params(b).max_validators as usize;
+
+        let mut candidates = self
+            .validators
+            .iter_by_branch(b)
+            .map(|(id, v)| (v.power(), id, v.pubkey))
+            .filter(|(power, _, _)| 0 < *power)
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        candidates.truncate(max_validators);
+
+        let new_set = candidates
+            .into_iter()
+            .map(|(power, id, pubkey)| (id, (pubkey, power)))
+            .collect::<BTreeMap<_, _>>();
+        let old_set = self
+            .consensus_set
+            .get_value_by_branch(b)
+            .unwrap_or_default();
+
+        // tendermint will halt with an empty validator set,
+        // keep the old one until some validators come back
+        if new_set.is_empty() && !old_set.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut updates = vec![];
+        for (id, (pubkey, power)) in new_set.iter() {
+            if old_set.get(id) != Some(&(pubkey.clone(), *power)) {
+                updates.push(ValidatorUpdate {
+                    pubkey: pubkey.clone(),
+                    power: *power,
+                });
+            }
+        }
+        // use the recorded public keys,
+        // the removed validators may not exist in the state any more
+        for (id, (pubkey, _)) in old_set.iter() {
+            if !new_set.contains_key(id) {
+                updates.push(ValidatorUpdate {
+                    pubkey: pubkey.clone(),
+                    power: 0,
+                });
+            }
+        }
+
+        if !updates.is_empty() {
+            self.consensus_set.set_value_by_branch(new_set, b).c(d!())?;
+        }
+
+        Ok(updates)
+    }
+
+    // Bond some OFUEL of the delegator to a validator,
+    // the amount will be debited from the evm balance of the delegator.
+    pub(crate) fn delegate(
+        &self,
+        token: &Erc20Like,
+        delegator: H160,
+        validator: ValidatorId,
+        amount: U256,
+        b: BranchName,
+    ) -> Result<()> {
+        token.debit(delegator, amount, b).c(d!())?;
+        self.bond(delegator, validator, amount, b).c(d!())
+    }
+
+    // Same as `delegate`, but the OFUEL has been removed
+    // from the evm balance of the delegator in advance.
+    pub(crate) fn bond(
+        &self,
+        delegator: H160,
+        validator: ValidatorId,
+        amount: U256,
+        b: BranchName,
+    ) -> Result<()> {
+        if amount.is_zero() {
+            return Err(eg!("Amount can not be zero"));
+        }
+
+        let mut v = self
+            .validator_get(&validator, b)
+            .c(d!("Validator not found"))?;
+
+        v.delegated = v.delegated.checked_add(amount).c(d!())?;
+        self.validator_set(validator.clone(), v, b).c(d!())?;
+
+        let mut d = self
+            .delegation_get(delegator, &validator, b)
+            .unwrap_or_default();
+        d.amount = d.amount.checked_add(amount).c(d!())?;
+        self.delegation_set(delegator, validator, d, b).c(d!())
+    }
+
+    // Unbond some OFUEL from a validator,
+    // it will be released to the delegator at the `mature_height`.
+    pub(crate) fn undelegate(
+        &self,
+        delegator: H160,
+        validator: ValidatorId,
+        amount: U256,
+        mature_height: BlockHeight,
+        b: BranchName,
+    ) -> Result<()> {
+        self.unbond(delegator, &validator, amount, b).c(d!())?;
+
+        let mut queue = self
+            .unbonding
+            .get_by_branch(&mature_height, b)
+            .unwrap_or_default();
+        queue.push(Unbonding {
+            delegator,
+            validator,
+            amount,
+        });
+        self.unbonding_set(mature_height, queue, b).c(d!())
+    }
+
+    // Move some delegated OFUEL from one validator to another one immediately,
+    // it is still responsible for the source validator until the `mature_height`.
+    pub(crate) fn redelegate(
+        &self,
+        delegator: H160,
+        from: ValidatorId,
+        to: ValidatorId,
+        amount: U256,
+        mature_height: BlockHeight,
+        b: BranchName,
+    ) -> Result<()> {
+        if from == to {
+            return Err(eg!("Can not redelegate to the same validator"));
+        }
+
+        let mut v = self.validator_get(&to, b).c(d!("Validator not found"))?;
+        self.unbond(delegator, &from, amount, b).c(d!())?;
+
+        v.delegated = v.delegated.checked_add(amount).c(d!())?;
+        self.validator_set(to.clone(), v, b).c(d!())?;
+
+        let mut d = self.delegation_get(delegator, &to, b).unwrap_or_default();
+        d.amount = d.amount.checked_add(amount).c(d!())?;
+        self.delegation_set(delegator, to.clone(), d, b).c(d!())?;
+
+        let mut queue = self
+            .redelegations
+            .get_by_branch(&mature_height, b)
+            .unwrap_or_default();
+        queue.push(Redelegation {
+            delegator,
+            from,
+            to,
+            amount,
+        });
+        self.redelegations_set(mature_height, queue, b).c(d!())
+    }
+
+    fn unbond(
+        &self,
+        delegator: H160,
+        validator: &ValidatorId,
+        amount: U256,
+        b: BranchName,
+    ) -> Result<()> {
+        if amount.is_zero() {
+            return Err(eg!("Amount can not be zero"));
+        }
+
+        let mut d = self
+            .delegation_get(delegator, validator, b)
+            .c(d!("Delegation not found"))?;
+        d.amount = d
+            .amount
+            .checked_sub(amount)
+            .c(d!("Insufficient delegation"))?;
+        self.delegation_set(delegator, validator.clone(), d, b)
+            .c(d!())?;
+
+        let mut v = self
+            .validator_get(validator, b)
+            .c(d!("Validator not found"))?;
+        v.delegated = v.delegated.saturating_sub(amount);
+        self.validator_set(validator.clone(), v, b).c(d!())
+    }
+
+    // Release all the unbonding OFUEL that matures at this height,
+    // the redelegations that mature at this height can not be slashed any more.
+    pub(crate) fn release_unbonding(
+        &self,
+        token: &Erc20Like,
+        height: BlockHeight,
+        b: BranchName,
+    ) -> Result<()> {
+        if let Some(queue) = self.unbonding.get_by_branch(&height, b) {
+            for u in queue.into_iter() {
+                token.credit(u.delegator, u.amount, b).c(d!())?;
+            }
+            self.unbonding_set(height, vec![], b).c(d!())?;
+        }
+        if self.redelegations.contains_key_by_branch(&height, b) {
+            self.redelegations_set(height, vec![], b).c(d!())?;
+        }
+        Ok(())
+    }
+
+    // Slash a double-signing validator,
+    // and put it into the jail permanently.
+    //
+    // Return the total amount of slashed OFUEL,
+    // which has been removed from the state and the total supply.
+    pub(crate) fn slash_double_sign(
+        &self,
+        token: &Erc20Like,
+        validator: &ValidatorId,
+        b: BranchName,
+    ) -> Result<U256> {
+        let mut v = if let Some(v) = self.validator_get(validator, b) {
+            v
+        } else {
+            return Ok(U256::zero());
+        };
+        alt!(v.tombstoned, return Ok(U256::zero()));
+
+        let fraction = self.params(b).slash_fraction_double_sign;
+        let mut slashed = U256::zero();
+
+        let s = calculate_fraction(v.self_bond, fraction);
+        v.self_bond -= s;
+        slashed += s;
+
+        let delegations = self
+            .delegations
+            .iter_by_branch(b)
+            .filter(|((_, id), _)| id == validator)
+            .collect::<Vec<_>>();
+        let mut delegated_slashed = U256::zero();
+        for ((delegator, id), mut d) in delegations.into_iter() {
+            let s = calculate_fraction(d.amount, fraction);
+            d.amount -= s;
+            delegated_slashed += s;
+            self.delegation_set(delegator, id, d, b).c(d!())?;
+        }
+        v.delegated = v.delegated.saturating_sub(delegated_slashed);
+        slashed += delegated_slashed;
+
+        // the unbonding ones are still responsible for the misbehavior
+        let queues = self.unbonding.iter_by_branch(b).collect::<Vec<_>>();
+        for (h, mut queue) in queues.into_iter() {
+            let mut changed = false;
+            for u in queue.iter_mut().filter(|u| &u.validator == validator) {
+                let s = calculate_fraction(u.amount, fraction);
+                u.amount -= s;
+                slashed += s;
+                changed = true;
+            }
+            if changed {
+                self.unbonding_set(h, queue, b).c(d!())?;
+            }
+        }
+
+        // so are the redelegated ones, which are bonded to other validators now
+        let queues = self.redelegations.iter_by_branch(b).collect::<Vec<_>>();
+        for (h, mut queue) in queues.into_iter() {
+            let mut changed = false;
+            for r in queue.iter_mut().filter(|r| &r.from == validator) {
+                let s = calculate_fraction(r.amount, fraction);
+                let s = self.slash_delegation(r.delegator, &r.to, s, b).c(d!())?;
+                r.amount -= s;
+                slashed += s;
+                changed = true;
+            }
+            if changed {
+                self.redelegations_set(h, queue, b).c(d!())?;
+            }
+        }
+
+        v.jailed = true;
+        v.tombstoned = true;
+        self.validator_set(validator.clone(), v, b).c(d!())?;
+
+        token.destroy(slashed, b).c(d!()).map(|_| slashed)
+    }
+
+    // Slash at most `amount` from a delegation, and return the slashed amount,
+    // the delegation may have been partly undelegated after the redelegation.
+    fn slash_delegation(
+        &self,
+        delegator: H160,
+        validator: &ValidatorId,
+        amount: U256,
+        b: BranchName,
+    ) -> Result<U256> {
+        let mut d = if let Some(d) = self.delegation_get(delegator, validator, b) {
+            d
+        } else {
+            return Ok(U256::zero());
+        };
+        let s = min!(amount, d.amount);
+        d.amount -= s;
+        self.delegation_set(delegator, validator.clone(), d, b)
+            .c(d!())?;
+
+        if let Some(mut v) = self.validator_get(validator, b) {
+            v.delegated = v.delegated.saturating_sub(s);
+            self.validator_set(validator.clone(), v, b).c(d!())?;
+        }
+
+        Ok(s)
+    }
+
+    // Record the signing status of a validator in the last block,
+    // jail it if it has missed too many blocks in the sliding window.
+    pub(crate) fn handle_vote(
+        &self,
+        validator: &ValidatorId,
+        signed: bool,
+        height: BlockHeight,
+        b: BranchName,
+    ) -> Result<()> {
+        let params = self.params(b);
+        let window = params.signed_blocks_window;
+        alt!(0 == window, return Ok(()));
+
+        let mut info = self
+            .signing_infos
+            .get_by_branch(validator, b)
+            .unwrap_or_default();
+        let idx = info.index_offset % window;
+        info.index_offset += 1;
+
+        let key = (validator.clone(), idx);
+        let prev_missed = self.missed_blocks.contains_key_by_branch(&key, b);
+        match (prev_missed, signed) {
+            (false, false) => {
+                self.missed_blocks.insert_by_branch(key, true, b).c(d!())?;
+                info.missed_cnt += 1;
+            }
+            (true, true) => {
+                self.missed_blocks.remove_by_branch(&key, b).c(d!())?;
+                info.missed_cnt = info.missed_cnt.saturating_sub(1);
+            }
+            _ => {}
+        }
+
+        let max_missed = window.saturating_sub(params.min_signed_per_window);
+        if window <= info.index_offset && max_missed < info.missed_cnt {
+            if let Some(mut v) = self.validator_get(validator, b) {
+                if !v.jailed {
+                    v.jailed = true;
+                    v.jailed_until = height + params.downtime_jail_blocks;
+                    self.validator_set(validator.clone(), v, b).c(d!())?;
+                }
+            }
+
+            // start a new window after being jailed
+            for i in 0..window {
+                self.missed_blocks
+                    .remove_by_branch(&(validator.clone(), i), b)
+                    .c(d!())?;
+            }
+            info = SigningInfo::default();
+        }
+
+        self.signing_infos
+            .insert_by_branch(validator.clone(), info, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    #[inline(always)]
+    pub(crate) fn add_fee(&self, amount: U256, b: BranchName) -> Result<()> {
+        alt!(amount.is_zero(), return Ok(()));
+        let pool = self.fee_pool.get_value_by_branch(b).unwrap_or_default();
+        self.fee_pool
+            .set_value_by_branch(pool.saturating_add(amount), b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    #[inline(always)]
+    pub fn rewards_get(&self, addr: &H160, b: BranchName) -> U256 {
+        self.rewards.get_by_branch(addr, b).unwrap_or_default()
+    }
+
+    #[inline(always)]
+    fn rewards_add(&self, addr: H160, amount: U256, b: BranchName) -> Result<()> {
+        alt!(amount.is_zero(), return Ok(()));
+        let r = self.rewards_get(&addr, b).saturating_add(amount);
+        self.changes
+            .stage(smt::record_key(b"rewards", &addr), smt::record_leaf(&r), b)
+            .c(d!())?;
+        self.rewards
+            .insert_by_branch(addr, r, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    // Move all the pending rewards of an address to its evm balance.
+    pub(crate) fn withdraw_rewards(
+        &self,
+        token: &Erc20Like,
+        addr: H160,
+        b: BranchName,
+    ) -> Result<U256> {
+        let r = self.take_rewards(addr, b).c(d!())?;
+        token.credit(addr, r, b).c(d!()).map(|_| r)
+    }
+
+    // Clear all the pending rewards of an address, and return the amount.
+    pub(crate) fn take_rewards(&self, addr: H160, b: BranchName) -> Result<U256> {
+        let r = self.rewards_get(&addr, b);
+        if r.is_zero() {
+            return Err(eg!("No rewards"));
+        }
+        self.changes
+            .stage(smt::record_key(b"rewards", &addr), H256::zero(), b)
+            .c(d!())?;
+        self.rewards.remove_by_branch(&addr, b).c(d!()).map(|_| r)
+    }
+
+    // Distribute the fee pool and the block reward of this block:
+    // - the proposer gets a bonus
+    // - the left is shared by the consensus set according to the voting power
+    // - the share of a validator is paid to its operator and delegators,
+    // after deducting the commission
+    //
+    // The block reward is newly issued, and will be cut down
+    // when the `issue_cap` of the token is reached.
+    //
+    // The dust caused by rounding will stay in the fee pool.
+    pub(crate) fn distribute_rewards(
+        &self,
+        token: &Erc20Like,
+        proposer: &ValidatorId,
+        b: BranchName,
+    ) -> Result<()> {
+        let params = self.params(b);
+        let set = self
+            .consensus_set
+            .get_value_by_branch(b)
+            .unwrap_or_default();
+        let total_power = set
+            .values()
+            .map(|(_, p)| U256::from(*p))
+            .fold(U256::zero(), |acc, p| acc + p);
+
+        let block_reward = min!(params.block_reward, token.issuable(b));
+        let mut pool = self
+            .fee_pool
+            .get_value_by_branch(b)
+            .unwrap_or_default()
+            .saturating_add(block_reward);
+        if pool.is_zero() || total_power.is_zero() {
+            return Ok(());
+        }
+        token.issue(block_reward, b).c(d!())?;
+
+        if let Some(v) = self.validator_get(proposer, b) {
+            let bonus = calculate_fraction(pool, params.proposer_bonus);
+            self.rewards_add(v.operator, bonus, b).c(d!())?;
+            pool -= bonus;
+        }
+
+        let mut paid = U256::zero();
+
+        // validator id => (validator, rewards for the delegators)
+        let mut shares = BTreeMap::new();
+        for (id, (_, power)) in set.iter() {
+            let v = if let Some(v) = self.validator_get(id, b) {
+                v
+            } else {
+                continue;
+            };
+
+            let share = pool.saturating_mul((*power).into()) / total_power;
+            let commission = calculate_fraction(share, v.commission);
+            let left = share - commission;
+
+            let total_bonded = v.total_bonded();
+            alt!(total_bonded.is_zero(), continue);
+
+            let self_reward = left.saturating_mul(v.self_bond) / total_bonded;
+            self.rewards_add(v.operator, commission + self_reward, b)
+                .c(d!())?;
+            paid += commission + self_reward;
+
+            shares.insert(id.clone(), (total_bonded, left));
+        }
+
+        let delegations = self
+            .delegations
+            .iter_by_branch(b)
+            .filter(|((_, id), _)| shares.contains_key(id))
+            .collect::<Vec<_>>();
+        for ((delegator, id), d) in delegations.into_iter() {
+            let (total_bonded, left) = shares[&id];
+            let r = left.saturating_mul(d.amount) / total_bonded;
+            self.rewards_add(delegator, r, b).c(d!())?;
+            paid += r;
+        }
+
+        self.fee_pool
+            .set_value_by_branch(pool.saturating_sub(paid), b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    // The bonded amount of an address,
+    // including the self-bond of the validators it operates.
+    #[inline(always)]
+    pub fn stake_of(&self, addr: H160, b: BranchName) -> U256 {
+        self.bonded.get_by_branch(&addr, b).unwrap_or_default()
+    }
+
+    // The bonded amount of all validators.
+    pub fn total_bonded(&self, b: BranchName) -> U256 {
+        self.validators
+            .iter_by_branch(b)
+            .fold(U256::zero(), |acc, (_, v)| {
+                acc.saturating_add(v.total_bonded())
+            })
+    }
+
+    // Keep the `bonded` index in sync with a changed bond.
+    fn bonded_update(
+        &self,
+        addr: H160,
+        old: U256,
+        new: U256,
+        b: BranchName,
+    ) -> Result<()> {
+        alt!(old == new, return Ok(()));
+        let stake = self
+            .stake_of(addr, b)
+            .saturating_sub(old)
+            .saturating_add(new);
+        if stake.is_zero() {
+            self.bonded.remove_by_branch(&addr, b).c(d!()).map(|_| ())
+        } else {
+            self.bonded
+                .insert_by_branch(addr, stake, b)
+                .c(d!())
+                .map(|_| ())
+        }
+    }
+
+    // The amount of OFUEL held by the staking module,
+    // which is a part of the total supply but not in any evm balance.
+    pub fn locked_sum(&self, b: BranchName) -> U256 {
+        let bonded = self.total_bonded(b);
+        let unbonding = self
+            .unbonding
+            .iter_by_branch(b)
+            .flat_map(|(_, queue)| queue.into_iter())
+            .fold(U256::zero(), |acc, u| acc.saturating_add(u.amount));
+        let rewards = self
+            .rewards
+            .iter_by_branch(b)
+            .fold(U256::zero(), |acc, (_, r)| acc.saturating_add(r));
+        let pool = self.fee_pool.get_value_by_branch(b).unwrap_or_default();
+
+        bonded
+            .saturating_add(unbonding)
+            .saturating_add(rewards)
+            .saturating_add(pool)
+    }
+
+    #[inline(always)]
+    pub fn validator_get(&self, id: &ValidatorId, b: BranchName) -> Option<Validator> {
+        self.validators.get_by_branch(id, b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn validator_set(
+        &self,
+        id: ValidatorId,
+        v: Validator,
+        b: BranchName,
+    ) -> Result<()> {
+        self.changes
+            .stage(smt::record_key(b"validator", &id), smt::record_leaf(&v), b)
+            .c(d!())?;
+
+        let (old_operator, old_bond) = self
+            .validator_get(&id, b)
+            .map(|old| (old.operator, old.self_bond))
+            .unwrap_or((v.operator, U256::zero()));
+        if old_operator == v.operator {
+            self.bonded_update(v.operator, old_bond, v.self_bond, b)
+                .c(d!())?;
+        } else {
+            self.bonded_update(old_operator, old_bond, U256::zero(), b)
+                .c(d!())?;
+            self.bonded_update(v.operator, U256::zero(), v.self_bond, b)
+                .c(d!())?;
+        }
+
+        self.validators
+            .insert_by_branch(id, v, b)
+            .c(d!())
+            .map(|_| ())
+    }
+
+    #[inline(always)]
+    pub fn delegation_get(
+        &self,
+        delegator: H160,
+        validator: &ValidatorId,
+        b: BranchName,
+    ) -> Option<Delegation> {
+        self.delegations
+            .get_by_branch(&(delegator, validator.clone()), b)
+    }
+
+    #[inline(always)]
+    pub(crate) fn delegation_set(
+        &self,
+        delegator: H160,
+        validator: ValidatorId,
+        d: Delegation,
+        b: BranchName,
+    ) -> Result<()> {
+        let k = smt::record_key(b"delegation", &(delegator, validator.clone()));
+        let leaf = alt!(d.amount.is_zero(), H256::zero(), smt::record_leaf(&d));
+        self.changes.stage(k, leaf, b).c(d!())?;
+
+        let old = self
+            .delegation_get(delegator, &validator, b)
+            .map(|d| d.amount)
+            .unwrap_or_default();
+        self.bonded_update(delegator, old, d.amount, b).c(d!())?;
+
+        if d.amount.is_zero() {
+            self.delegations
+                .remove_by_branch(&(delegator, validator), b)
+                .c(d!())
+                .map(|_| ())
+        } else {
+            self.delegations
+                .insert_by_branch((delegator, validator), d, b)
+                .c(d!())
+                .map(|_| ())
+        }
+    }
+
+    // An empty queue will be removed.
+    #[inline(always)]
+    fn unbonding_set(
+        &self,
+        height: BlockHeight,
+        queue: Vec<Unbonding>,
+        b: BranchName,
+    ) -> Result<()> {
+        let k = smt::record_key(b"unbonding", &height);
+        if queue.is_empty() {
+            self.changes.stage(k, H256::zero(), b).c(d!())?;
+            self.unbonding
+                .remove_by_branch(&height, b)
+                .c(d!())
+                .map(|_| ())
+        } else {
+            self.changes.stage(k, smt::record_leaf(&queue), b).c(d!())?;
+            self.unbonding
+                .insert_by_branch(height, queue, b)
+                .c(d!())
+                .map(|_| ())
+        }
+    }
+
+    // An empty queue will be removed.
+    #[inline(always)]
+    fn redelegations_set(
+        &self,
+        height: BlockHeight,
+        queue: Vec<Redelegation>,
+        b: BranchName,
+    ) -> Result<()> {
+        let k = smt::record_key(b"redelegation", &height);
+        if queue.is_empty() {
+            self.changes.stage(k, H256::zero(), b).c(d!())?;
+            self.redelegations
+                .remove_by_branch(&height, b)
+                .c(d!())
+                .map(|_| ())
+        } else {
+            self.changes.stage(k, smt::record_leaf(&queue), b).c(d!())?;
+            self.redelegations
+                .insert_by_branch(height, queue, b)
+                .c(d!())
+                .map(|_| ())
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Validator {
+    // the ed25519 consensus public key used by tendermint
+    pub pubkey: Vec<u8>,
+    // the evm address that manages this validator
+    pub operator: H160,
+    // the amount of OFUEL bonded by the operator itself
+    pub self_bond: U256,
+    // the sum of all delegations from others
+    pub delegated: U256,
+    // in the unit of `1 / COMMISSION_RATE_MAX`
+    pub commission: u16,
+    // a jailed validator will be removed from the consensus set
+    pub jailed: bool,
+    // can not be unjailed before this height
+    pub jailed_until: BlockHeight,
+    // jailed forever because of double signing
+    pub tombstoned: bool,
+}
+
+impl Validator {
+    #[inline(always)]
+    pub fn total_bonded(&self) -> U256 {
+        self.self_bond.saturating_add(self.delegated)
+    }
+
+    /// The voting power in tendermint, jailed validators have no power.
+    #[inline(always)]
+    pub fn power(&self) -> u64 {
+        if self.jailed {
+            0
+        } else {
+            let power = self.total_bonded() / *POWER_UNIT;
+            alt!(power > u64::MAX.into(), u64::MAX, power.as_u64())
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Params {
+    // the max number of validators in the consensus set
+    pub max_validators: u64,
+    // how many blocks the unbonding OFUEL will be locked for
+    pub unbonding_blocks: u64,
+    // in the unit of `1 / COMMISSION_RATE_MAX`
+    pub slash_fraction_double_sign: u16,
+    // the size of the sliding window for checking the liveness
+    pub signed_blocks_window: u64,
+    // the min number of signed blocks in a window
+    pub min_signed_per_window: u64,
+    // how long a validator will be jailed for being offline
+    pub downtime_jail_blocks: u64,
+    // newly issued OFUEL for each block
+    pub block_reward: U256,
+    // the extra share of the proposer, in the unit of `1 / COMMISSION_RATE_MAX`
+    pub proposer_bonus: u16,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            max_validators: 100,
+            unbonding_blocks: 100_000,
+            slash_fraction_double_sign: 500,
+            signed_blocks_window: 10_000,
+            min_signed_per_window: 5_000,
+            downtime_jail_blocks: 600,
+            block_reward: U256::zero(),
+            proposer_bonus: 500,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ValidatorUpdate {
+    // ed25519 public key
+    pub pubkey: Vec<u8>,
+    // zero means removing the validator
+    pub power: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Delegation {
+    pub amount: U256,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SigningInfo {
+    // how many blocks have been checked in the current window
+    pub index_offset: u64,
+    pub missed_cnt: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Unbonding {
+    pub delegator: H160,
+    // where the OFUEL is unbonded from
+    pub validator: ValidatorId,
+    pub amount: U256,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Redelegation {
+    pub delegator: H160,
+    // the source validator, which can still slash this amount
+    pub from: ValidatorId,
+    // where the OFUEL is bonded now
+    pub to: ValidatorId,
+    pub amount: U256,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Op {
+    // register a new validator with some self-bonded OFUEL,
+    // the signer of the transaction will be the operator
+    CreateValidator {
+        pubkey: Vec<u8>,
+        commission: u16,
+        self_bond: U256,
+    },
+    // only the operator can do this
+    EditValidator {
+        validator: ValidatorId,
+        commission: u16,
+    },
+    Delegate {
+        validator: ValidatorId,
+        amount: U256,
+    },
+    // the OFUEL will be locked for `Params.unbonding_blocks` blocks
+    Undelegate {
+        validator: ValidatorId,
+        amount: U256,
+    },
+    Redelegate {
+        from: ValidatorId,
+        to: ValidatorId,
+        amount: U256,
+    },
+    // only the operator can do this
+    Unjail {
+        validator: ValidatorId,
+    },
+    // move all the pending rewards to the evm balance
+    WithdrawRewards,
+}
+
+impl Op {
+    pub(crate) fn exec(
+        self,
+        caller: H160,
+        sb: &StateBranch,
+        b: BranchName,
+    ) -> Result<String> {
+        let staking = &sb.state.staking;
+        match self {
+            Op::CreateValidator {
+                pubkey,
+                commission,
+                self_bond,
+            } => {
+                check_commission(commission).c(d!())?;
+                if self_bond.is_zero() {
+                    return Err(eg!("Self-bond can not be zero"));
+                }
+
+                let id = pubkey_to_validator_id(&pubkey).c(d!())?;
+                if staking.validator_get(&id, b).is_some() {
+                    return Err(eg!("Validator already exists"));
+                }
+
+                sb.state.evm.OFUEL.debit(caller, self_bond, b).c(d!())?;
+
+                let v = Validator {
+                    pubkey,
+                    operator: caller,
+                    self_bond,
+                    delegated: U256::zero(),
+                    commission,
+                    ..Default::default()
+                };
+                staking.validator_set(id.clone(), v, b).c(d!())?;
+
+                Ok(format!("validator created: {}", hex::encode(id)))
+            }
+            Op::EditValidator {
+                validator,
+                commission,
+            } => {
+                check_commission(commission).c(d!())?;
+
+                let mut v = staking.validator_get(&validator, b).c(d!())?;
+                if v.operator != caller {
+                    return Err(eg!("Permission denied"));
+                }
+                v.commission = commission;
+                staking.validator_set(validator.clone(), v, b).c(d!())?;
+
+                Ok(format!("validator edited: {}", hex::encode(validator)))
+            }
+            Op::Delegate { validator, amount } => {
+                let log = format!("delegate {} to {}", amount, hex::encode(&validator));
+                staking
+                    .delegate(&sb.state.evm.OFUEL, caller, validator, amount, b)
+                    .c(d!())
+                    .map(|_| log)
+            }
+            Op::Undelegate { validator, amount } => {
+                let mature_height = unbonding_mature_height(sb, b);
+                let log = format!(
+                    "undelegate {} from {}, mature at height {}",
+                    amount,
+                    hex::encode(&validator),
+                    mature_height
+                );
+                staking
+                    .undelegate(caller, validator, amount, mature_height, b)
+                    .c(d!())
+                    .map(|_| log)
+            }
+            Op::Redelegate { from, to, amount } => {
+                let mature_height = unbonding_mature_height(sb, b);
+                let log = format!(
+                    "redelegate {} from {} to {}",
+                    amount,
+                    hex::encode(&from),
+                    hex::encode(&to)
+                );
+                staking
+                    .redelegate(caller, from, to, amount, mature_height, b)
+                    .c(d!())
+                    .map(|_| log)
+            }
+            Op::Unjail { validator } => {
+                let mut v = staking.validator_get(&validator, b).c(d!())?;
+                if v.operator != caller {
+                    return Err(eg!("Permission denied"));
+                }
+                if !v.jailed {
+                    return Err(eg!("Validator is not jailed"));
+                }
+                if v.tombstoned {
+                    return Err(eg!("Validator has been tombstoned"));
+                }
+                let h = sb.block_in_process.header.height;
+                if h < v.jailed_until {
+                    return Err(eg!("Still jailed until height {}", v.jailed_until));
+                }
+                v.jailed = false;
+                staking.validator_set(validator.clone(), v, b).c(d!())?;
+
+                Ok(format!("validator unjailed: {}", hex::encode(validator)))
+            }
+            Op::WithdrawRewards => staking
+                .withdraw_rewards(&sb.state.evm.OFUEL, caller, b)
+                .c(d!())
+                .map(|r| format!("rewards withdrawn: {}", r)),
+        }
+    }
+}
+
+// Staking operations from the evm precompile,
+// they are recorded during the execution, and applied after it succeeds.
+#[derive(Clone, Debug)]
+pub(crate) enum EvmOp {
+    // the OFUEL has been debited from the evm balance of the delegator
+    Delegate {
+        delegator: H160,
+        validator: ValidatorId,
+        amount: U256,
+    },
+    Undelegate {
+        delegator: H160,
+        validator: ValidatorId,
+        amount: U256,
+    },
+    // the rewards have been credited to the evm balance
+    WithdrawRewards {
+        addr: H160,
+        amount: U256,
+    },
+}
+
+impl EvmOp {
+    pub(crate) fn apply(self, sb: &StateBranch, b: BranchName) -> Result<()> {
+        let staking = &sb.state.staking;
+        match self {
+            EvmOp::Delegate {
+                delegator,
+                validator,
+                amount,
+            } => staking.bond(delegator, validator, amount, b).c(d!()),
+            EvmOp::Undelegate {
+                delegator,
+                validator,
+                amount,
+            } => {
+                let mature_height = unbonding_mature_height(sb, b);
+                staking
+                    .undelegate(delegator, validator, amount, mature_height, b)
+                    .c(d!())
+            }
+            EvmOp::WithdrawRewards { addr, amount } => {
+                let r = staking.take_rewards(addr, b).c(d!())?;
+                if r != amount {
+                    return Err(eg!("Rewards mismatch: {}, expected: {}", r, amount));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn unbonding_mature_height(sb: &StateBranch, b: BranchName) -> BlockHeight {
+    sb.block_in_process.header.height + sb.state.staking.params(b).unbonding_blocks
+}
+
+#[inline(always)]
+fn check_commission(commission: u16) -> Result<()> {
+    if COMMISSION_RATE_MAX < commission {
+        Err(eg!("Invalid commission: {}", commission))
+    } else {
+        Ok(())
+    }
+}
+
+// `amount * fraction / COMMISSION_RATE_MAX`
+#[inline(always)]
+pub(crate) fn calculate_fraction(amount: U256, fraction: u16) -> U256 {
+    amount.saturating_mul(fraction.into()) / U256::from(COMMISSION_RATE_MAX)
+}
+
+/// ed25519 public key ==> tendermint address
+pub fn pubkey_to_validator_id(pubkey: &[u8]) -> Result<ValidatorId> {
+    tendermint::PublicKey::from_raw_ed25519(pubkey)
+        .c(d!("Invalid ed25519 public key"))
+        .map(|pk| tendermint::account::Id::from(pk).as_bytes().to_vec())
+}
@@ -0,0 +1,69 @@
+//!
+//! # Scheduled software upgrades
+//!
+//! An upgrade plan is approved by governance,
+//! the node halts at the target height if the running binary does not know it,
+//! and the new binary migrates the state before processing the block.
+//!
+
+use crate::{common::BlockHeight, ledger::State};
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use vsdb::BranchName;
+
+/// A state migration, it runs only once, in the 'BeginBlock' of the upgrade height.
+pub type Migration = fn(&State, BranchName) -> Result<()>;
+
+// Migrations known by this binary, keyed by the upgrade name.
+//
+// NOTE:
+// - Register a new one for each release that contains an upgrade,
+// use a no-op function if no migration is needed
+// - Never remove the old ones
+const MIGRATIONS: &[(&str, Migration)] = &[];
+
+// Migrations registered at runtime, eg. by the programs embedding the ledger.
+static REGISTERED: Lazy<RwLock<Vec<(String, Migration)>>> =
+    Lazy::new(|| RwLock::new(vec![]));
+
+/// Register a migration out of the built-in ones,
+/// the built-in one wins if the names conflict.
+pub fn register_migration(name: &str, m: Migration) {
+    REGISTERED.write().push((name.to_owned(), m));
+}
+
+#[inline(always)]
+pub fn migration(name: &str) -> Option<Migration> {
+    MIGRATIONS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, m)| *m)
+        .or_else(|| {
+            REGISTERED
+                .read()
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, m)| *m)
+        })
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UpgradePlan {
+    // also used as the new `chain_version`
+    pub name: String,
+    // the old binary halts in the 'BeginBlock' of this height
+    pub height: BlockHeight,
+    // eg: where to download the new binary
+    pub info: String,
+}
+
+impl UpgradePlan {
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(eg!("Upgrade name can not be empty"));
+        }
+        Ok(())
+    }
+}
@@ -9,6 +9,14 @@ pub fn no_impl() -> Error {
     }
 }
 
+pub fn pruned(data: Value) -> Error {
+    Error {
+        code: ErrorCode::ServerError(40003),
+        message: String::from("Historical state has been pruned."),
+        data: Some(data),
+    }
+}
+
 pub fn new_jsonrpc_error(msg: &str, data: Value) -> Error {
     Error {
         code: ErrorCode::ServerError(40002),
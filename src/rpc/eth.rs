@@ -1,14 +1,16 @@
 use crate::{
     common::{
-        block_hash_to_evm_format, block_number_to_height, rollback_to_height,
-        tm_proposer_to_evm_format, HashValue,
+        block_hash_to_evm_format, block_number_to_height, rollback_to_height, HashValue,
     },
-    ledger::{State, MAIN_BRANCH_NAME},
+    ledger::{smt, State, MAIN_BRANCH_NAME},
     rpc::{
         error::new_jsonrpc_error,
+        ext::{
+            EthProofApi, EthReceiptApi, OvrApi, TransactionProof, TransactionReceipt,
+        },
         utils::{
-            filter_block_logs, remove_branch_by_name, rollback_by_height, tx_to_web3_tx,
-            txs_to_web3_txs,
+            block_to_web3_block, filter_block_logs, remove_branch_by_name,
+            rollback_by_height, tx_to_web3_tx, txs_to_web3_txs,
         },
     },
     tx::Tx,
@@ -21,24 +23,72 @@ use jsonrpc_core::{BoxFuture, Result};
 use rlp::{Decodable, Rlp};
 use serde_json::Value;
 use std::result::Result::Err;
+use vsdb::BranchName;
 use web3_rpc_core::{
     types::{
-        Block, BlockNumber, BlockTransactions, Bytes, CallRequest, Filter,
-        FilteredParams, Index, Log, Receipt, RichBlock, SyncInfo, SyncStatus,
-        Transaction, TransactionRequest, Work,
+        BlockNumber, BlockTransactions, Bytes, CallRequest, EthAccount, Filter,
+        FilteredParams, Index, Log, Receipt, RichBlock, StorageProof, SyncInfo,
+        SyncStatus, Transaction, TransactionRequest, Work,
     },
     EthApi,
 };
 
 use super::error;
 
-const BASE_GAS: u64 = 21_000;
-
+#[derive(Clone)]
 pub(crate) struct EthApiImpl {
     pub upstream: String,
     pub state: State,
 }
 
+impl EthApiImpl {
+    fn receipt(&self, tx_hash: H256) -> Option<TransactionReceipt> {
+        let hash = HashValue::from(tx_hash.as_bytes());
+        let (height, block, _) = self.state.block_by_tx_hash(&hash)?;
+        let block_hash = block_hash_to_evm_format(&block.header_hash);
+        let r = block.header.receipts.get(&hash)?;
+
+        let mut logs = vec![];
+
+        for l in r.logs.iter() {
+            logs.push(Log {
+                address: l.address,
+                topics: l.topics.clone(),
+                data: Bytes::new(l.data.clone()),
+                block_hash: Some(block_hash),
+                block_number: Some(U256::from(height)),
+                transaction_hash: Some(tx_hash),
+                transaction_index: Some(U256::from(l.tx_index)),
+                log_index: Some(U256::from(l.log_index_in_block)),
+                transaction_log_index: Some(U256::from(l.log_index_in_tx)),
+                removed: false,
+            });
+        }
+
+        let receipt = Receipt {
+            transaction_hash: Some(tx_hash),
+            transaction_index: Some(U256::from(r.tx_index)),
+            block_hash: Some(block_hash),
+            from: r.from,
+            to: r.to,
+            block_number: Some(U256::from(height)),
+            cumulative_gas_used: r.cumulative_gas_used,
+            gas_used: Some(r.tx_gas_used),
+            contract_address: r.contract_addr,
+            logs,
+            state_root: None,
+            logs_bloom: Bloom::from_slice(r.logs_bloom.as_slice()),
+            status_code: Some(U64::from(r.status_code as u8)),
+        };
+
+        Some(TransactionReceipt {
+            receipt,
+            effective_gas_price: r.effective_gas_price,
+            transaction_type: U64::from(r.tx_type),
+        })
+    }
+}
+
 impl EthApi for EthApiImpl {
     fn protocol_version(&self) -> BoxFuture<Result<u64>> {
         Box::pin(async move { Ok(65) })
@@ -94,7 +144,7 @@ impl EthApi for EthApiImpl {
         let resp = self
             .state
             .evm
-            .call_contract(MAIN_BRANCH_NAME, req, bn)
+            .call_contract(&self.state.staking, MAIN_BRANCH_NAME, req, bn)
             .map_err(|e| {
                 error::new_jsonrpc_error(
                     "call contract failed",
@@ -186,7 +236,9 @@ impl EthApi for EthApiImpl {
     }
 
     fn gas_price(&self) -> BoxFuture<Result<U256>> {
-        let gas_price = self.state.evm.gas_price.get_value();
+        // a legacy transaction with this price will be accepted in the next block,
+        // unless the base fee rises
+        let gas_price = self.state.evm.base_fee(MAIN_BRANCH_NAME);
 
         Box::pin(async move { Ok(gas_price) })
     }
@@ -241,90 +293,24 @@ impl EthApi for EthApiImpl {
         is_complete: bool,
     ) -> BoxFuture<Result<Option<RichBlock>>> {
         let mut op_rb = None;
-        for (height, block) in self.state.blocks.iter() {
+        if let Some((height, block)) = self.state.block_by_hash(&block_hash) {
             let chain_id = self.state.chain_id.get_value();
             let web3_txs = match txs_to_web3_txs(&block, chain_id, height) {
                 Ok(v) => v,
                 Err(e) => return Box::pin(async { Err(e) }),
             };
 
-            let tx_hashes = web3_txs.iter().map(|t| t.hash).collect::<Vec<H256>>();
-            let mut b = Block {
-                hash: None,
-                parent_hash: Default::default(),
-                uncles_hash: Default::default(),
-                author: Default::default(),
-                miner: Default::default(),
-                state_root: Default::default(),
-                transactions_root: Default::default(),
-                receipts_root: Default::default(),
-                number: None,
-                gas_used: Default::default(),
-                gas_limit: Default::default(),
-                extra_data: Default::default(),
-                logs_bloom: None,
-                timestamp: Default::default(),
-                difficulty: Default::default(),
-                total_difficulty: Default::default(),
-                seal_fields: vec![],
-                uncles: vec![],
-                transactions: BlockTransactions::Hashes(tx_hashes),
-                size: None,
+            // Determine if you want to return all block information
+            let txs = if is_complete {
+                BlockTransactions::Full(web3_txs)
+            } else {
+                BlockTransactions::Hashes(web3_txs.iter().map(|t| t.hash).collect())
             };
 
-            if block.header_hash.as_slice() == block_hash.as_bytes() {
-                // Determine if you want to return all block information
-                if is_complete {
-                    let proposer = tm_proposer_to_evm_format(&block.header.proposer);
-
-                    let receipt = if let Some((_, receipt)) =
-                        block.header.receipts.iter().last()
-                    {
-                        receipt
-                    } else {
-                        return Box::pin(async {
-                            Err(new_jsonrpc_error("this block no receipt!", Value::Null))
-                        });
-                    };
-
-                    // prev is null if block is 1
-                    let parent_hash = if block.header.prev_hash.is_empty() {
-                        H256::default()
-                    } else {
-                        block_hash_to_evm_format(&block.header.prev_hash)
-                    };
-
-                    b = Block {
-                        hash: Some(block_hash_to_evm_format(&block.header_hash)),
-                        parent_hash,
-                        uncles_hash: Default::default(),
-                        author: proposer,
-                        miner: proposer,
-                        state_root: Default::default(),
-                        transactions_root: block_hash_to_evm_format(
-                            &block.header.tx_merkle.root_hash,
-                        ),
-                        receipts_root: Default::default(),
-                        number: Some(U256::from(height)),
-                        gas_used: receipt.block_gas_used,
-                        gas_limit: self.state.evm.block_gas_limit.get_value(),
-                        extra_data: Default::default(),
-                        logs_bloom: Some(Bloom::from_slice(block.bloom.as_slice())),
-                        timestamp: U256::from(block.header.timestamp),
-                        difficulty: Default::default(),
-                        total_difficulty: Default::default(),
-                        seal_fields: vec![],
-                        uncles: vec![],
-                        transactions: BlockTransactions::Full(web3_txs),
-                        size: None, //missing data
-                    };
-                }
-
-                op_rb.replace(RichBlock {
-                    inner: b,
-                    extra_info: Default::default(),
-                });
-            }
+            op_rb.replace(RichBlock {
+                inner: block_to_web3_block(&block, txs),
+                extra_info: Default::default(),
+            });
         }
 
         Box::pin(async { Ok(op_rb) })
@@ -355,15 +341,6 @@ impl EthApi for EthApiImpl {
         };
 
         let op = if let Some(block) = self.state.blocks.get(&height) {
-            let proposer = tm_proposer_to_evm_format(&block.header.proposer);
-
-            // prev is null if block is 1
-            let parent_hash = if block.header.prev_hash.is_empty() {
-                H256::default()
-            } else {
-                block_hash_to_evm_format(&block.header.prev_hash)
-            };
-
             let chain_id = self.state.chain_id.get_value();
             let web3_txs = match txs_to_web3_txs(&block, chain_id, height) {
                 Ok(v) => v,
@@ -383,56 +360,12 @@ impl EthApi for EthApiImpl {
                 }
             };
 
-            let b = if is_complete {
-                Block {
-                    hash: Some(block_hash_to_evm_format(&block.header_hash)),
-                    parent_hash,
-                    uncles_hash: Default::default(),
-                    author: proposer,
-                    miner: proposer,
-                    state_root: Default::default(),
-                    transactions_root: block_hash_to_evm_format(
-                        &block.header.tx_merkle.root_hash,
-                    ),
-                    receipts_root: Default::default(),
-                    number: Some(U256::from(height)),
-                    gas_used: Default::default(),
-                    gas_limit: self.state.evm.block_gas_limit.get_value(),
-                    extra_data: Default::default(),
-                    logs_bloom: None,
-                    timestamp: U256::from(block.header.timestamp),
-                    difficulty: Default::default(),
-                    total_difficulty: Default::default(),
-                    seal_fields: vec![],
-                    uncles: vec![],
-                    transactions: BlockTransactions::Full(web3_txs),
-                    size: None,
-                }
+            let txs = if is_complete {
+                BlockTransactions::Full(web3_txs)
             } else {
-                let tx_hashes = web3_txs.iter().map(|t| t.hash).collect::<Vec<H256>>();
-                Block {
-                    hash: None,
-                    parent_hash: Default::default(),
-                    uncles_hash: Default::default(),
-                    author: Default::default(),
-                    miner: Default::default(),
-                    state_root: Default::default(),
-                    transactions_root: Default::default(),
-                    receipts_root: Default::default(),
-                    number: None,
-                    gas_used: Default::default(),
-                    gas_limit: Default::default(),
-                    extra_data: Default::default(),
-                    logs_bloom: None,
-                    timestamp: Default::default(),
-                    difficulty: Default::default(),
-                    total_difficulty: Default::default(),
-                    seal_fields: vec![],
-                    uncles: vec![],
-                    transactions: BlockTransactions::Hashes(tx_hashes),
-                    size: None,
-                }
+                BlockTransactions::Hashes(web3_txs.iter().map(|t| t.hash).collect())
             };
+            let b = block_to_web3_block(&block, txs);
 
             Some(RichBlock {
                 inner: b,
@@ -493,10 +426,8 @@ impl EthApi for EthApiImpl {
     ) -> BoxFuture<Result<Option<U256>>> {
         let mut tx_count = 0;
 
-        for (_, block) in self.state.blocks.iter() {
-            if block.header_hash == block_hash.as_bytes() {
-                tx_count = block.txs.len();
-            }
+        if let Some((_, block)) = self.state.block_by_hash(&block_hash) {
+            tx_count = block.txs.len();
         }
 
         Box::pin(async move { Ok(Some(U256::from(tx_count))) })
@@ -642,7 +573,7 @@ impl EthApi for EthApiImpl {
         let resp = self
             .state
             .evm
-            .call_contract(MAIN_BRANCH_NAME, req, bn)
+            .call_contract(&self.state.staking, MAIN_BRANCH_NAME, req, bn)
             .map_err(|e| {
                 error::new_jsonrpc_error(
                     "call contract failed",
@@ -655,7 +586,7 @@ impl EthApi for EthApiImpl {
         if let Err(e) = resp {
             r = Err(e)
         } else if let Ok(resp) = resp {
-            let gas_used = U256::from(resp.gas_used + BASE_GAS);
+            let gas_used = U256::from(resp.gas_used);
             r = Ok(gas_used)
         } else {
             r = Err(new_jsonrpc_error("call contract resp none", Value::Null));
@@ -670,23 +601,22 @@ impl EthApi for EthApiImpl {
     ) -> BoxFuture<Result<Option<Transaction>>> {
         let mut transaction = None;
 
-        'outer: for (height, block) in self.state.blocks.iter() {
-            for (index, tx) in block.txs.iter().enumerate() {
-                if tx.hash() == tx_hash.as_bytes() {
-                    match tx_to_web3_tx(
-                        &tx,
-                        &block,
-                        height,
-                        index,
-                        self.state.chain_id.get_value(),
-                    ) {
-                        Ok(op) => {
-                            transaction = op;
-                            break 'outer;
-                        }
-                        Err(e) => {
-                            return Box::pin(async { Err(e) });
-                        }
+        if let Some((height, block, index)) =
+            self.state.block_by_tx_hash(tx_hash.as_bytes())
+        {
+            if let Some(tx) = block.txs.get(index as usize) {
+                match tx_to_web3_tx(
+                    &tx,
+                    &block,
+                    height,
+                    index as usize,
+                    self.state.chain_id.get_value(),
+                ) {
+                    Ok(op) => {
+                        transaction = op;
+                    }
+                    Err(e) => {
+                        return Box::pin(async { Err(e) });
                     }
                 }
             }
@@ -702,23 +632,20 @@ impl EthApi for EthApiImpl {
     ) -> BoxFuture<Result<Option<Transaction>>> {
         let mut transaction = None;
 
-        for (height, block) in self.state.blocks.iter() {
-            if block.header_hash == block_hash.as_bytes() {
-                if let Some(tx) = block.txs.get(index.value()) {
-                    match tx_to_web3_tx(
-                        &tx,
-                        &block,
-                        height,
-                        index.value(),
-                        self.state.chain_id.get_value(),
-                    ) {
-                        Ok(op) => {
-                            transaction = op;
-                            break;
-                        }
-                        Err(e) => {
-                            return Box::pin(async { Err(e) });
-                        }
+        if let Some((height, block)) = self.state.block_by_hash(&block_hash) {
+            if let Some(tx) = block.txs.get(index.value()) {
+                match tx_to_web3_tx(
+                    &tx,
+                    &block,
+                    height,
+                    index.value(),
+                    self.state.chain_id.get_value(),
+                ) {
+                    Ok(op) => {
+                        transaction = op;
+                    }
+                    Err(e) => {
+                        return Box::pin(async { Err(e) });
                     }
                 }
             }
@@ -780,48 +707,9 @@ impl EthApi for EthApiImpl {
         Box::pin(async { Ok(transaction) })
     }
 
+    // not registered, see `EthReceiptApi::transaction_receipt`
     fn transaction_receipt(&self, tx_hash: H256) -> BoxFuture<Result<Option<Receipt>>> {
-        let mut op = None;
-
-        for (height, block) in self.state.blocks.iter() {
-            let hash = HashValue::from(tx_hash.as_bytes());
-            let block_hash = block_hash_to_evm_format(&block.header_hash);
-
-            if let Some(r) = block.header.receipts.get(&hash) {
-                let mut logs = vec![];
-
-                for l in r.logs.iter() {
-                    logs.push(Log {
-                        address: l.address,
-                        topics: l.topics.clone(),
-                        data: Bytes::new(l.data.clone()),
-                        block_hash: Some(block_hash),
-                        block_number: Some(U256::from(height)),
-                        transaction_hash: Some(tx_hash),
-                        transaction_index: Some(U256::from(l.tx_index)),
-                        log_index: Some(U256::from(l.log_index_in_block)),
-                        transaction_log_index: Some(U256::from(l.log_index_in_tx)),
-                        removed: false,
-                    });
-                }
-
-                op.replace(Receipt {
-                    transaction_hash: Some(tx_hash),
-                    transaction_index: Some(U256::from(r.tx_index)),
-                    block_hash: Some(block_hash),
-                    from: r.from,
-                    to: r.to,
-                    block_number: Some(U256::from(height)),
-                    cumulative_gas_used: r.block_gas_used,
-                    gas_used: Some(r.tx_gas_used),
-                    contract_address: r.contract_addr,
-                    logs,
-                    state_root: None,
-                    logs_bloom: Default::default(),
-                    status_code: None,
-                });
-            }
-        }
+        let op = self.receipt(tx_hash).map(|r| r.receipt);
 
         Box::pin(async { Ok(op) })
     }
@@ -830,11 +718,8 @@ impl EthApi for EthApiImpl {
         let mut logs = vec![];
 
         if let Some(hash) = filter.block_hash {
-            for (height, block) in self.state.blocks.iter() {
-                if block.header_hash == hash.as_bytes() {
-                    logs.append(&mut filter_block_logs(&block, &filter, height));
-                    break;
-                }
+            if let Some((height, block)) = self.state.block_by_hash(&hash) {
+                logs.append(&mut filter_block_logs(&block, &filter, height));
             }
         } else {
             let (current_height, _) = self.state.blocks.last().unwrap_or_default();
@@ -867,7 +752,7 @@ impl EthApi for EthApiImpl {
 
             for height in from..=to {
                 if let Some(block) = self.state.blocks.get(&height) {
-                    let b = Bloom::from_slice(block.bloom.as_slice());
+                    let b = Bloom::from_slice(block.header.logs_bloom.as_slice());
                     if FilteredParams::address_in_bloom(b, &address_bloom_filter)
                         && FilteredParams::topics_in_bloom(b, &topic_bloom_filters)
                     {
@@ -925,3 +810,103 @@ impl EthApi for EthApiImpl {
         Ok(vec![])
     }
 }
+
+impl EthProofApi for EthApiImpl {
+    fn proof(
+        &self,
+        address: H160,
+        storage_keys: Vec<H256>,
+        bn: Option<BlockNumber>,
+    ) -> BoxFuture<Result<EthAccount>> {
+        let new_branch_name =
+            match rollback_by_height(bn, Some(&self.state), None, "proof") {
+                Ok(name) => name,
+                Err(e) => {
+                    return Box::pin(async { Err(e) });
+                }
+            };
+
+        let b = BranchName::from(new_branch_name.as_str());
+        let tree = &self.state.smt;
+        let token = &self.state.evm.OFUEL;
+        let to_bytes = |proof: Vec<H256>| {
+            proof
+                .into_iter()
+                .map(|h| Bytes::new(h.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        };
+
+        let storage_proof = storage_keys
+            .into_iter()
+            .map(|k| {
+                let value = token
+                    .storages
+                    .get_by_branch(&(&address, &k), b)
+                    .unwrap_or_default();
+                StorageProof {
+                    key: U256::from_big_endian(k.as_bytes()),
+                    value: U256::from_big_endian(value.as_bytes()),
+                    proof: to_bytes(tree.proof(smt::storage_key(&address, &k), b)),
+                }
+            })
+            .collect();
+
+        // the leaf of a non-existent account is zero
+        let account = token
+            .accounts
+            .get_by_branch(&address, b)
+            .unwrap_or_default();
+        let resp = EthAccount {
+            address,
+            balance: account.balance,
+            code_hash: smt::code_hash(&account.code),
+            nonce: account.nonce,
+            // there is only one tree for all the accounts and storages,
+            // so this is the state root instead of the root of a storage trie
+            storage_hash: tree.root(b),
+            account_proof: to_bytes(tree.proof(smt::account_key(&address), b)),
+            storage_proof,
+        };
+
+        if let Err(e) = remove_branch_by_name(new_branch_name, Some(&self.state), None) {
+            return Box::pin(async { Err(e) });
+        }
+
+        Box::pin(async move { Ok(resp) })
+    }
+}
+
+impl OvrApi for EthApiImpl {
+    fn transaction_proof(
+        &self,
+        tx_hash: H256,
+    ) -> BoxFuture<Result<Option<TransactionProof>>> {
+        let resp = self.state.block_by_tx_hash(tx_hash.as_bytes()).and_then(
+            |(height, block, idx)| {
+                block.tx_proof(idx).map(|p| TransactionProof {
+                    transaction_hash: tx_hash,
+                    transaction_index: U256::from(p.tx_index),
+                    block_hash: block_hash_to_evm_format(&block.header_hash),
+                    block_number: U256::from(height),
+                    transactions_root: block_hash_to_evm_format(
+                        &block.header.tx_merkle.root_hash,
+                    ),
+                    proof: p.path.iter().map(block_hash_to_evm_format).collect(),
+                })
+            },
+        );
+
+        Box::pin(async { Ok(resp) })
+    }
+}
+
+impl EthReceiptApi for EthApiImpl {
+    fn transaction_receipt(
+        &self,
+        tx_hash: H256,
+    ) -> BoxFuture<Result<Option<TransactionReceipt>>> {
+        let op = self.receipt(tx_hash);
+
+        Box::pin(async { Ok(op) })
+    }
+}
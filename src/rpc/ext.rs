@@ -0,0 +1,74 @@
+//!
+//! # APIs that are not included in `web3_rpc_core`
+//!
+
+use ethereum_types::{H160, H256, U256, U64};
+use jsonrpc_core::{BoxFuture, Result};
+use jsonrpc_derive::rpc;
+use serde::{Deserialize, Serialize};
+use web3_rpc_core::types::{BlockNumber, EthAccount, Receipt};
+
+#[rpc(server)]
+pub trait EthProofApi {
+    /// EIP-1186, the proofs can be checked against the state root of the block,
+    /// the storage proofs included, see `ledger::smt::verify`.
+    ///
+    /// Unlike ethereum, all the accounts and storages are in one sparse merkle tree,
+    /// so `storageHash` is the state root rather than the root of a storage trie,
+    /// and the proofs are not RLP-encoded trie nodes.
+    #[rpc(name = "eth_getProof")]
+    fn proof(
+        &self,
+        address: H160,
+        storage_keys: Vec<H256>,
+        number: Option<BlockNumber>,
+    ) -> BoxFuture<Result<EthAccount>>;
+}
+
+// The `eth_getTransactionReceipt` of `EthApi` is not registered,
+// this one is used instead.
+pub(crate) const RECEIPT_METHOD: &str = "eth_getTransactionReceipt";
+
+#[rpc(server)]
+pub trait EthReceiptApi {
+    /// The fields added by EIP-1559 and EIP-2718 are included,
+    /// they are used by the fee calculations of the web3 tools.
+    #[rpc(name = "eth_getTransactionReceipt")]
+    fn transaction_receipt(
+        &self,
+        tx_hash: H256,
+    ) -> BoxFuture<Result<Option<TransactionReceipt>>>;
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionReceipt {
+    #[serde(flatten)]
+    pub receipt: Receipt,
+    pub effective_gas_price: U256,
+    #[serde(rename = "type")]
+    pub transaction_type: U64,
+}
+
+#[rpc(server)]
+pub trait OvrApi {
+    /// The merkle path of a transaction in the `transactionsRoot` of its block,
+    /// can be checked by `ledger::verify_tx_proof`.
+    #[rpc(name = "ovr_getTransactionProof")]
+    fn transaction_proof(
+        &self,
+        tx_hash: H256,
+    ) -> BoxFuture<Result<Option<TransactionProof>>>;
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionProof {
+    pub transaction_hash: H256,
+    pub transaction_index: U256,
+    pub block_hash: H256,
+    pub block_number: U256,
+    pub transactions_root: H256,
+    // siblings from the leaf up to the root
+    pub proof: Vec<H256>,
+}
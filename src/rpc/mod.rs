@@ -7,6 +7,7 @@ pub use server::*;
 
 mod error;
 mod eth;
+mod ext;
 mod net;
 mod utils;
 mod web3;
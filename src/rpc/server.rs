@@ -2,9 +2,27 @@ use std::{net::SocketAddr, thread};
 
 use crate::ledger::State;
 
-use super::{eth::EthApiImpl, net::NetApiImpl, web3::Web3ApiImpl};
+use super::{
+    eth::EthApiImpl,
+    ext::{EthProofApi, EthReceiptApi, OvrApi, RECEIPT_METHOD},
+    net::NetApiImpl,
+    web3::Web3ApiImpl,
+};
 use web3_rpc_core::{EthApi, NetApi, Web3Api};
 
+// Register the eth methods, `eth_getTransactionReceipt` is served by `EthReceiptApi`,
+// the one of `EthApi` lacks the fields added by EIP-1559 and EIP-2718.
+fn extend_with_eth(io: &mut jsonrpc_core::IoHandler, eth: EthApiImpl) {
+    io.extend_with(EthProofApi::to_delegate(eth.clone()));
+    io.extend_with(OvrApi::to_delegate(eth.clone()));
+    io.extend_with(
+        EthApi::to_delegate(eth.clone())
+            .into_iter()
+            .filter(|(name, _)| name != RECEIPT_METHOD),
+    );
+    io.extend_with(EthReceiptApi::to_delegate(eth));
+}
+
 pub struct Web3ServerBuilder {
     pub upstream: Vec<SocketAddr>,
     pub http: Vec<SocketAddr>,
@@ -31,7 +49,7 @@ impl Web3ServerBuilder {
 
             let web3 = Web3ApiImpl {};
 
-            io.extend_with(eth.to_delegate());
+            extend_with_eth(&mut io, eth);
             io.extend_with(net.to_delegate());
             io.extend_with(web3.to_delegate());
 
@@ -62,7 +80,7 @@ impl Web3ServerBuilder {
 
             let web3 = Web3ApiImpl {};
 
-            io.extend_with(eth.to_delegate());
+            extend_with_eth(&mut io, eth);
             io.extend_with(net.to_delegate());
             io.extend_with(web3.to_delegate());
 
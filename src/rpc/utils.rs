@@ -1,17 +1,21 @@
 use crate::{
-    common::{block_number_to_height, rollback_to_height, BlockHeight},
-    ledger::Block,
-    rpc::error::new_jsonrpc_error,
+    common::{
+        block_hash_to_evm_format, block_number_to_height, check_pruned,
+        rollback_to_height, tm_proposer_to_evm_format, BlockHeight,
+    },
+    ledger::{Block, EMPTY_OMMERS_HASH},
+    rpc::error::{new_jsonrpc_error, pruned},
     tx::Tx,
     {ethvm::State as EvmState, ledger::State as LedgerState},
 };
-use ethereum_types::{H256, U256, U64};
+use ethereum_types::{Bloom, H256, U256, U64};
 use primitive_types::H512;
 use rustc_hex::ToHex;
 use serde_json::Value;
 use vsdb::{BranchName, VsMgmt};
 use web3_rpc_core::types::{
-    BlockNumber, Bytes, Filter, FilteredParams, Log as Web3Log, Transaction,
+    Block as Web3Block, BlockNumber, BlockTransactions, Bytes, Filter, FilteredParams,
+    Log as Web3Log, Transaction,
 };
 
 pub fn rollback_by_height(
@@ -21,6 +25,10 @@ pub fn rollback_by_height(
     prefix: &str,
 ) -> jsonrpc_core::Result<String> {
     let height = block_number_to_height(bn, ledger_state, evm_state);
+    if let Some(s) = evm_state.or_else(|| ledger_state.map(|s| &s.evm)) {
+        check_pruned(height, s).map_err(|e| pruned(Value::String(e.to_string())))?;
+    }
+
     let new_branch_name = rollback_to_height(height, ledger_state, evm_state, prefix)
         .map_err(|e| {
             new_jsonrpc_error("rollback by height error", Value::String(e.to_string()))
@@ -60,6 +68,37 @@ pub fn remove_branch_by_name(
     Ok(())
 }
 
+// The header fields are the same as the ones hashed in `BlockHeader::hash`,
+// so the block hash can be verified by the clients.
+pub fn block_to_web3_block(block: &Block, transactions: BlockTransactions) -> Web3Block {
+    let header = &block.header;
+    let proposer = tm_proposer_to_evm_format(&header.proposer);
+
+    Web3Block {
+        hash: Some(block_hash_to_evm_format(&block.header_hash)),
+        // zero if the block is the first one
+        parent_hash: block_hash_to_evm_format(&header.prev_hash),
+        uncles_hash: *EMPTY_OMMERS_HASH,
+        author: proposer,
+        miner: proposer,
+        state_root: block_hash_to_evm_format(&header.state_root),
+        transactions_root: block_hash_to_evm_format(&header.tx_merkle.root_hash),
+        receipts_root: block_hash_to_evm_format(&header.receipts_root),
+        number: Some(U256::from(header.height)),
+        gas_used: header.gas_used,
+        gas_limit: header.gas_limit,
+        extra_data: Default::default(),
+        logs_bloom: Some(Bloom::from_slice(header.logs_bloom.as_slice())),
+        timestamp: U256::from(header.timestamp),
+        difficulty: Default::default(),
+        total_difficulty: Default::default(),
+        seal_fields: vec![],
+        uncles: vec![],
+        transactions,
+        size: None,
+    }
+}
+
 pub fn txs_to_web3_txs(
     block: &Block,
     chain_id: u64,
@@ -163,7 +202,11 @@ pub fn filter_block_logs(
 
     let params = FilteredParams::new(Some(filter.clone()));
 
-    for (tx_hash, receipt) in block.header.receipts.iter() {
+    // in the order of execution, the receipts are keyed by tx hashes
+    let mut receipts = block.header.receipts.iter().collect::<Vec<_>>();
+    receipts.sort_by_key(|(_, r)| r.tx_index);
+
+    for (tx_hash, receipt) in receipts.into_iter() {
         for l in receipt.logs.iter() {
             let log = Web3Log {
                 address: l.address,
@@ -8,6 +8,7 @@ use crate::{
     common::{hash_sha3_256, HashValue},
     ethvm,
 };
+use primitive_types::U256;
 use ruc::*;
 use serde::{Deserialize, Serialize};
 use vsdb::ValueEn;
@@ -19,9 +20,14 @@ pub enum Tx {
 }
 
 impl Tx {
+    // The hash of an evm transaction is the same as ethereum,
+    // the keccak256 of its (typed) RLP envelope.
     #[inline(always)]
     pub(crate) fn hash(&self) -> HashValue {
-        hash_sha3_256(&[&self.encode_value()])
+        match self {
+            Tx::Evm(tx) => tx.tx.hash().as_bytes().to_vec(),
+            Tx::Native(_) => hash_sha3_256(&[&self.encode_value()]),
+        }
     }
 
     #[inline(always)]
@@ -29,6 +35,16 @@ impl Tx {
         serde_json::from_slice(bytes).c(d!())
     }
 
+    // The max amount of gas that can be consumed,
+    // reported to tendermint as the `gas_wanted`.
+    #[inline(always)]
+    pub(crate) fn gas_limit(&self) -> U256 {
+        match self {
+            Tx::Evm(tx) => tx.gas_limit(),
+            Tx::Native(tx) => tx.body.op.gas().into(),
+        }
+    }
+
     // TODO
     #[inline(always)]
     pub(crate) fn valid_in_abci(&self) -> bool {
@@ -3,29 +3,225 @@
 //!
 //! eg:
 //! - Staking
+//! - Governance
 //! - System management
 //!
 
-use crate::ledger::StateBranch;
-use primitive_types::{H160, U256};
+use crate::ledger::{gov, staking, StateBranch};
+use primitive_types::{H160, H256, U256};
+use ruc::*;
 use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
 use std::{fmt, result::Result as StdResult};
-use vsdb::BranchName;
+use vsdb::{BranchName, ValueEn};
+
+/// The fixed amount of gas consumed by a OFUEL transfer,
+/// keep it the same as a plain evm transfer.
+pub const GAS_TRANSFER: u64 = 21_000;
+
+/// The fixed amount of gas consumed by any staking operation.
+pub const GAS_STAKING: u64 = 50_000;
+
+/// The fixed amount of gas consumed by any governance operation.
+pub const GAS_GOV: u64 = 50_000;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Tx {
-    mock: [[u8; 16]; 16], // TODO
+    pub body: TxBody,
+    // `r(32 bytes) + s(32 bytes) + v(1 byte)`,
+    // a secp256k1 signature of `TxBody::hash`
+    pub sig: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TxBody {
+    pub chain_id: u64,
+    // must be equal to the current nonce of the signer
+    pub nonce: U256,
+    // fee = gas_price * op.gas()
+    pub gas_price: U256,
+    pub op: Op,
+}
+
+impl TxBody {
+    /// The message to be signed.
+    #[inline(always)]
+    pub fn hash(&self) -> H256 {
+        H256::from_slice(Keccak256::digest(&self.encode_value()).as_slice())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Op {
+    Transfer { to: H160, amount: U256 },
+    Staking(staking::Op),
+    Gov(gov::Op),
+}
+
+impl Op {
+    #[inline(always)]
+    pub(crate) fn gas(&self) -> u64 {
+        match self {
+            Op::Transfer { .. } => GAS_TRANSFER,
+            Op::Staking(_) => GAS_STAKING,
+            Op::Gov(_) => GAS_GOV,
+        }
+    }
+
+    fn exec(self, caller: H160, sb: &StateBranch, b: BranchName) -> Result<String> {
+        match self {
+            Op::Transfer { to, amount } => {
+                transfer(sb, caller, to, amount, b).c(d!())?;
+                Ok(format!("transfer {} from {:?} to {:?}", amount, caller, to))
+            }
+            Op::Staking(op) => op.exec(caller, sb, b).c(d!()),
+            Op::Gov(op) => op.exec(caller, sb, b).c(d!()),
+        }
+    }
 }
 
 impl Tx {
+    #[inline(always)]
+    pub fn new(body: TxBody, sig: Vec<u8>) -> Self {
+        Self { body, sig }
+    }
+
     pub(crate) fn apply(
         self,
-        _sb: &mut StateBranch,
-        _b: BranchName,
+        sb: &mut StateBranch,
+        b: BranchName,
     ) -> StdResult<ExecRet, Option<ExecRet>> {
-        // TODO
-        Err(None)
+        let (caller, fee) = info!(self.pre_exec(sb, b)).map_err(|_| None)?;
+
+        let ret = |success, log| ExecRet {
+            success,
+            caller,
+            fee_used: fee,
+            log,
+        };
+
+        // the nonce of the caller must be refreshed before
+        // any balance changes of the same account
+        let mut account = sb
+            .state
+            .evm
+            .OFUEL
+            .accounts
+            .get_by_branch(&caller, b)
+            .unwrap_or_default();
+        account.nonce = account.nonce.saturating_add(U256::one());
+        sb.state
+            .evm
+            .OFUEL
+            .account_set(caller, account, b)
+            .c(d!())
+            .map_err(|e| Some(ret(false, e.to_string())))?;
+
+        // the fee must be paid before the operation,
+        // or it can be dodged by spending the whole balance
+        sb.state
+            .evm
+            .OFUEL
+            .debit(caller, fee, b)
+            .c(d!())
+            .map_err(|e| Some(ret(false, e.to_string())))?;
+
+        match self.body.op.exec(caller, sb, b) {
+            Ok(log) => Ok(ret(true, log)),
+            Err(e) => Err(Some(ret(false, e.to_string()))),
+        }
     }
+
+    // 0. ensure the chain id is correct
+    // 1. verify the transaction signature
+    // 2. ensure the transaction nonce is equal to the current nonce
+    // 3. ensure the given gas price is big enough
+    // 4. ensure the balance of OFUEL is enough to pay the fee
+    fn pre_exec(&self, sb: &StateBranch, b: BranchName) -> Result<(H160, U256)> {
+        // {0.}
+        let chain_id = sb.state.chain_id.get_value_by_branch(b).c(d!())?;
+        if chain_id != self.body.chain_id {
+            return Err(eg!(
+                "Invalid chain id: {}, should be: {}",
+                self.body.chain_id,
+                chain_id
+            ));
+        }
+
+        // {1.}
+        let caller = self.recover_signer().c(d!("Invalid signature"))?;
+
+        let account = sb
+            .state
+            .evm
+            .OFUEL
+            .accounts
+            .get_by_branch(&caller, b)
+            .unwrap_or_default();
+
+        // {2.}
+        if account.nonce != self.body.nonce {
+            return Err(eg!(
+                "Invalid nonce: {}, should be: {}",
+                self.body.nonce,
+                account.nonce
+            ));
+        }
+
+        // {3.}
+        let gas_price_min = sb
+            .state
+            .evm
+            .gas_price
+            .get_value_by_branch(b)
+            .unwrap_or_default();
+        if self.body.gas_price < gas_price_min {
+            return Err(eg!("Gas price is too low"));
+        }
+
+        // {4.}
+        let fee = self
+            .body
+            .gas_price
+            .checked_mul(U256::from(self.body.op.gas()))
+            .c(d!("Fee overflow"))?;
+        if account.balance < fee {
+            return Err(eg!(
+                "Insufficient balance, needed: {}, total: {}",
+                fee,
+                account.balance
+            ));
+        }
+
+        Ok((caller, fee))
+    }
+
+    // if success, the transaction signature is valid.
+    pub fn recover_signer(&self) -> Option<H160> {
+        let sig = <[u8; 65]>::try_from(self.sig.as_slice()).ok()?;
+        let msg = self.body.hash();
+        let pubkey =
+            sp_io::crypto::secp256k1_ecdsa_recover(&sig, msg.as_fixed_bytes()).ok()?;
+        Some(H160::from(H256::from_slice(
+            Keccak256::digest(&pubkey).as_slice(),
+        )))
+    }
+}
+
+/// Move some OFUEL from one account to another one.
+pub(crate) fn transfer(
+    sb: &StateBranch,
+    from: H160,
+    to: H160,
+    amount: U256,
+    b: BranchName,
+) -> Result<()> {
+    alt!(from == to, return Ok(()));
+    let token = &sb.state.evm.OFUEL;
+    token
+        .debit(from, amount, b)
+        .c(d!())
+        .and_then(|_| token.credit(to, amount, b).c(d!()))
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
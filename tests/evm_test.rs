@@ -43,7 +43,8 @@ fn init_state() -> Ledger {
         String::from("TEST"),
         String::from("1"),
         Some(10000000000),
-        Some(3000000),
+        // big enough for the pre-signed transactions below
+        Some(6000000),
         Some(1),
     )
     .unwrap();
@@ -59,6 +60,18 @@ fn init_state() -> Ledger {
         .OFUEL
         .accounts
         .insert_by_branch(addr2, acc2, BranchName(b"Main"));
+    // keep the total supply consistent with the balances above,
+    // the burned fees will be deducted from it
+    ledger
+        .state
+        .evm
+        .OFUEL
+        .total_supply
+        .set_value_by_branch(
+            U256::from(2000000000000000000000000u128),
+            BranchName(b"Main"),
+        )
+        .unwrap();
     ledger
 }
 
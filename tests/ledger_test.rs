@@ -0,0 +1,1943 @@
+#![allow(warnings)]
+
+use ethereum::{
+    LegacyTransaction, LegacyTransactionMessage, TransactionAction, TransactionAny,
+    TransactionSignature,
+};
+use evm::ExitReason;
+use libsecp256k1::{Message, PublicKey, SecretKey};
+use ovr::{
+    ethvm::{CallContractResp, Hardfork, OvrAccount},
+    ledger::{
+        gov::{self, ParamChange, ProposalStatus, VoteOption},
+        smt,
+        staking::{self, Delegation, Params, Validator, ValidatorId, POWER_UNIT},
+        upgrade::{self, UpgradePlan},
+        verify_tx_proof, EndBlockResp, Ledger, Pruning, Receipt, StateBranch, VsVersion,
+        MAIN_BRANCH_NAME,
+    },
+    tx::{
+        native::{Op, TxBody, GAS_STAKING, GAS_TRANSFER},
+        Tx,
+    },
+    EvmTx, NativeTx,
+};
+use primitive_types::{H160, H256, U256};
+use sha3::{Digest, Keccak256};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+use vsdb::{ParentBranchName, ValueEnDe, VsMgmt};
+use web3_rpc_core::types::{BlockNumber, Bytes, CallRequest};
+
+const CHAIN_ID: u64 = 1234;
+const GAS_PRICE: u128 = 10;
+
+fn init_ledger() -> Ledger {
+    Ledger::new(
+        CHAIN_ID,
+        String::from("TEST"),
+        String::from("1"),
+        Some(GAS_PRICE),
+        Some(3000000),
+        None,
+    )
+    .unwrap()
+}
+
+// All ledgers write their metadata to the same file on commit.
+static COMMIT_LOCK: Mutex<()> = Mutex::new(());
+
+fn commit_lock() -> MutexGuard<'static, ()> {
+    COMMIT_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Run a block through the same steps as the ABCI workflow.
+fn new_block(
+    ledger: &Ledger,
+    txs: Vec<Tx>,
+    votes: Vec<(ValidatorId, bool)>,
+) -> EndBlockResp {
+    let h = ledger.main.read().last_block().map(|b| b.header.height);
+    let ts = 1 + h.unwrap_or_default();
+    ledger.consensus_refresh(validator_id(0), ts).unwrap();
+    ledger.slash_and_jail(vec![], votes).unwrap();
+    for tx in txs.into_iter() {
+        let _ = ledger.deliver_tx.write().apply_tx(tx);
+    }
+    let resp = ledger.end_block().unwrap();
+    ledger.commit().unwrap();
+    resp
+}
+
+fn validator_id(seed: u8) -> ValidatorId {
+    vec![seed; 20]
+}
+
+fn add_validator(ledger: &Ledger, id: ValidatorId, power: u64) {
+    let v = Validator {
+        pubkey: vec![id[0]; 32],
+        operator: H160::from_slice(&id),
+        self_bond: POWER_UNIT.saturating_mul(power.into()),
+        ..Default::default()
+    };
+    adjust_supply(ledger, v.self_bond, U256::zero());
+    bond(ledger, v.operator, v.self_bond, U256::zero());
+    ledger
+        .state
+        .staking
+        .validators
+        .insert_by_branch(id, v, MAIN_BRANCH_NAME)
+        .unwrap();
+}
+
+// Keep the bonded index consistent with the validators set by hand.
+fn bond(ledger: &Ledger, addr: H160, add: U256, sub: U256) {
+    let staking = &ledger.state.staking;
+    let stake = staking.stake_of(addr, MAIN_BRANCH_NAME) + add - sub;
+    staking
+        .bonded
+        .insert_by_branch(addr, stake, MAIN_BRANCH_NAME)
+        .unwrap();
+}
+
+fn set_operator(ledger: &Ledger, id: &ValidatorId, operator: H160) {
+    let staking = &ledger.state.staking;
+    let mut v = staking.validator_get(id, MAIN_BRANCH_NAME).unwrap();
+    bond(ledger, v.operator, U256::zero(), v.self_bond);
+    bond(ledger, operator, v.self_bond, U256::zero());
+    v.operator = operator;
+    staking
+        .validators
+        .insert_by_branch(id.clone(), v, MAIN_BRANCH_NAME)
+        .unwrap();
+}
+
+fn secret_key(seed: u8) -> SecretKey {
+    SecretKey::parse(&[seed; 32]).unwrap()
+}
+
+fn addr_of(sk: &SecretKey) -> H160 {
+    let pubkey = PublicKey::from_secret_key(sk).serialize();
+    H160::from(H256::from_slice(Keccak256::digest(&pubkey[1..]).as_slice()))
+}
+
+// Keep the total supply consistent with the balances and bonds set by hand.
+fn adjust_supply(ledger: &Ledger, add: U256, sub: U256) {
+    let token = &ledger.state.evm.OFUEL;
+    let supply = token.total_supply(MAIN_BRANCH_NAME) + add - sub;
+    token
+        .total_supply
+        .set_value_by_branch(supply, MAIN_BRANCH_NAME)
+        .unwrap();
+}
+
+fn set_balance(ledger: &Ledger, addr: H160, balance: U256) {
+    adjust_supply(ledger, balance, balance_of(ledger, addr));
+    let account = OvrAccount {
+        balance,
+        ..Default::default()
+    };
+    ledger
+        .state
+        .evm
+        .OFUEL
+        .accounts
+        .insert_by_branch(addr, account, MAIN_BRANCH_NAME)
+        .unwrap();
+}
+
+fn balance_of(ledger: &Ledger, addr: H160) -> U256 {
+    ledger
+        .state
+        .evm
+        .OFUEL
+        .accounts
+        .get_by_branch(&addr, MAIN_BRANCH_NAME)
+        .unwrap_or_default()
+        .balance
+}
+
+fn native_tx(sk: &SecretKey, nonce: u64, op: Op) -> Tx {
+    let body = TxBody {
+        chain_id: CHAIN_ID,
+        nonce: nonce.into(),
+        gas_price: GAS_PRICE.into(),
+        op,
+    };
+    sign_native(sk, body)
+}
+
+fn sign_native(sk: &SecretKey, body: TxBody) -> Tx {
+    let (sig, recid) =
+        libsecp256k1::sign(&Message::parse(body.hash().as_fixed_bytes()), sk);
+    let mut sig = sig.serialize().to_vec();
+    sig.push(recid.serialize());
+    Tx::Native(NativeTx::new(body, sig))
+}
+
+fn evm_tx(
+    sk: &SecretKey,
+    nonce: u64,
+    action: TransactionAction,
+    value: U256,
+    gas_price: u64,
+    gas_limit: u64,
+    input: Vec<u8>,
+) -> Tx {
+    let msg = LegacyTransactionMessage {
+        nonce: nonce.into(),
+        gas_price: gas_price.into(),
+        gas_limit: gas_limit.into(),
+        action,
+        value,
+        input,
+        chain_id: Some(CHAIN_ID),
+    };
+    sign_legacy(sk, msg)
+}
+
+// Sign without a chain id if `msg.chain_id` is none.
+fn sign_legacy(sk: &SecretKey, msg: LegacyTransactionMessage) -> Tx {
+    let (sig, recid) =
+        libsecp256k1::sign(&Message::parse(msg.hash().as_fixed_bytes()), sk);
+    let sig = sig.serialize();
+    let v = match msg.chain_id {
+        // EIP-155
+        Some(id) => recid.serialize() as u64 + 35 + 2 * id,
+        None => recid.serialize() as u64 + 27,
+    };
+    let signature = TransactionSignature::new(
+        v,
+        H256::from_slice(&sig[..32]),
+        H256::from_slice(&sig[32..]),
+    )
+    .unwrap();
+    let tx = LegacyTransaction {
+        nonce: msg.nonce,
+        gas_price: msg.gas_price,
+        gas_limit: msg.gas_limit,
+        action: msg.action,
+        value: msg.value,
+        input: msg.input,
+        signature,
+    };
+    Tx::Evm(EvmTx {
+        tx: TransactionAny::Legacy(tx),
+    })
+}
+
+fn evm_transfer(sk: &SecretKey, nonce: u64, to: H160, value: u64) -> Tx {
+    let action = TransactionAction::Call(to);
+    evm_tx(sk, nonce, action, value.into(), 15, 21_000, vec![])
+}
+
+#[test]
+fn test_native_fee_paid_before_exec() {
+    let ledger = init_ledger();
+    let mut sb = StateBranch::new(&ledger.state, MAIN_BRANCH_NAME).unwrap();
+
+    let sk = secret_key(1);
+    let from = addr_of(&sk);
+    let to = addr_of(&secret_key(2));
+    let balance = U256::from(1_000_000_000u64);
+    set_balance(&ledger, from, balance);
+
+    // spend the whole balance, nothing is left for the fee
+    let tx = native_tx(
+        &sk,
+        0,
+        Op::Transfer {
+            to,
+            amount: balance,
+        },
+    );
+    assert!(sb.apply_tx(tx).is_err());
+
+    let fee = U256::from(GAS_PRICE) * U256::from(GAS_TRANSFER);
+    assert_eq!(balance - fee, balance_of(&ledger, from));
+    assert_eq!(U256::zero(), balance_of(&ledger, to));
+
+    // the rest can be transferred with the fee paid
+    let amount = balance - fee - fee;
+    let tx = native_tx(&sk, 1, Op::Transfer { to, amount });
+    sb.apply_tx(tx).unwrap();
+
+    assert_eq!(U256::zero(), balance_of(&ledger, from));
+    assert_eq!(amount, balance_of(&ledger, to));
+}
+
+#[test]
+fn test_keep_the_last_validator() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let id = validator_id(1);
+    add_validator(&ledger, id.clone(), 10);
+
+    let resp = new_block(&ledger, vec![], vec![]);
+    assert_eq!(1, resp.validator_updates.len());
+    assert_eq!(10, resp.validator_updates[0].power);
+
+    let mut v = staking.validator_get(&id, MAIN_BRANCH_NAME).unwrap();
+    v.jailed = true;
+    staking
+        .validators
+        .insert_by_branch(id.clone(), v, MAIN_BRANCH_NAME)
+        .unwrap();
+
+    // removing the only validator would halt tendermint
+    let resp = new_block(&ledger, vec![], vec![]);
+    assert!(resp.validator_updates.is_empty());
+    let set = staking
+        .consensus_set
+        .get_value_by_branch(MAIN_BRANCH_NAME)
+        .unwrap();
+    assert_eq!(Some(&10), set.get(&id).map(|(_, power)| power));
+}
+
+#[test]
+fn test_remove_deleted_validator() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let (id, other) = (validator_id(1), validator_id(2));
+    add_validator(&ledger, id.clone(), 10);
+    add_validator(&ledger, other.clone(), 10);
+    let resp = new_block(&ledger, vec![], vec![]);
+    assert_eq!(2, resp.validator_updates.len());
+
+    let pubkey = staking.validator_get(&id, MAIN_BRANCH_NAME).unwrap().pubkey;
+    staking
+        .validators
+        .remove_by_branch(&id, MAIN_BRANCH_NAME)
+        .unwrap();
+    adjust_supply(&ledger, U256::zero(), POWER_UNIT.saturating_mul(10.into()));
+
+    // the public key is still known after the deletion
+    let resp = new_block(&ledger, vec![], vec![]);
+    assert_eq!(1, resp.validator_updates.len());
+    assert_eq!(pubkey, resp.validator_updates[0].pubkey);
+    assert_eq!(0, resp.validator_updates[0].power);
+
+    let set = staking
+        .consensus_set
+        .get_value_by_branch(MAIN_BRANCH_NAME)
+        .unwrap();
+    assert!(!set.contains_key(&id) && set.contains_key(&other));
+}
+
+#[test]
+fn test_jail_for_downtime() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let params = Params {
+        signed_blocks_window: 4,
+        min_signed_per_window: 2,
+        downtime_jail_blocks: 10,
+        ..Default::default()
+    };
+    staking
+        .params
+        .set_value_by_branch(params, MAIN_BRANCH_NAME)
+        .unwrap();
+
+    let (id, other) = (validator_id(1), validator_id(2));
+    add_validator(&ledger, id.clone(), 10);
+    add_validator(&ledger, other.clone(), 10);
+    new_block(&ledger, vec![], vec![]);
+
+    let is_jailed = || staking.validator_get(&id, MAIN_BRANCH_NAME).unwrap().jailed;
+
+    // 2 of 4 missed, just at the threshold
+    for signed in [false, false, true, true, false, false] {
+        new_block(&ledger, vec![], vec![(id.clone(), signed)]);
+        assert!(!is_jailed());
+    }
+
+    // 3 of the last 4 missed
+    let resp = new_block(&ledger, vec![], vec![(id.clone(), false)]);
+    assert!(is_jailed());
+
+    let v = staking.validator_get(&id, MAIN_BRANCH_NAME).unwrap();
+    assert_eq!(8 + 10, v.jailed_until);
+    assert!(!v.tombstoned);
+    assert_eq!(1, resp.validator_updates.len());
+    assert_eq!(0, resp.validator_updates[0].power);
+}
+
+#[test]
+fn test_slash_double_sign() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let params = Params {
+        unbonding_blocks: 3,
+        slash_fraction_double_sign: 500,
+        ..Default::default()
+    };
+    staking
+        .params
+        .set_value_by_branch(params, MAIN_BRANCH_NAME)
+        .unwrap();
+
+    let (id, other) = (validator_id(1), validator_id(2));
+    add_validator(&ledger, id.clone(), 100);
+    add_validator(&ledger, other.clone(), 100);
+    new_block(&ledger, vec![], vec![]);
+
+    let supply = ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME);
+
+    ledger.consensus_refresh(validator_id(0), 100).unwrap();
+    ledger
+        .slash_and_jail(vec![(id.clone(), 1)], vec![])
+        .unwrap();
+    ledger.end_block().unwrap();
+    ledger.commit().unwrap();
+
+    // 5% of the bond is burned
+    let slashed = POWER_UNIT.saturating_mul(5.into());
+    let v = staking.validator_get(&id, MAIN_BRANCH_NAME).unwrap();
+    assert!(v.jailed && v.tombstoned);
+    assert_eq!(POWER_UNIT.saturating_mul(95.into()), v.self_bond);
+    assert_eq!(
+        supply - slashed,
+        ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME)
+    );
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+
+    // height 5, the evidence of height 2 is out of the unbonding window
+    new_block(&ledger, vec![], vec![]);
+    new_block(&ledger, vec![], vec![]);
+    ledger.consensus_refresh(validator_id(0), 100).unwrap();
+    ledger
+        .slash_and_jail(vec![(other.clone(), 2)], vec![])
+        .unwrap();
+    ledger.end_block().unwrap();
+    ledger.commit().unwrap();
+
+    let v = staking.validator_get(&other, MAIN_BRANCH_NAME).unwrap();
+    assert!(!v.jailed);
+    assert_eq!(POWER_UNIT.saturating_mul(100.into()), v.self_bond);
+}
+
+#[test]
+fn test_slash_redelegated() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let params = Params {
+        unbonding_blocks: 3,
+        slash_fraction_double_sign: 500,
+        ..Default::default()
+    };
+    staking
+        .params
+        .set_value_by_branch(params, MAIN_BRANCH_NAME)
+        .unwrap();
+
+    let (id, other) = (validator_id(1), validator_id(2));
+    add_validator(&ledger, id.clone(), 100);
+    add_validator(&ledger, other.clone(), 100);
+
+    let sk = secret_key(14);
+    let delegator = addr_of(&sk);
+    let units = |n: u64| POWER_UNIT.saturating_mul(n.into());
+    set_balance(&ledger, delegator, units(100));
+    new_block(&ledger, vec![], vec![]);
+
+    // height 2
+    let op = staking::Op::Delegate {
+        validator: id.clone(),
+        amount: units(40),
+    };
+    new_block(&ledger, vec![native_tx(&sk, 0, Op::Staking(op))], vec![]);
+
+    // height 3, can be slashed for `id` until height 6
+    let op = staking::Op::Redelegate {
+        from: id.clone(),
+        to: other.clone(),
+        amount: units(40),
+    };
+    new_block(&ledger, vec![native_tx(&sk, 1, Op::Staking(op))], vec![]);
+    assert!(
+        staking
+            .delegation_get(delegator, &id, MAIN_BRANCH_NAME)
+            .is_none()
+    );
+
+    let supply = ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME);
+
+    // height 4, the source validator double signed
+    ledger.consensus_refresh(validator_id(0), 4).unwrap();
+    ledger
+        .slash_and_jail(vec![(id.clone(), 3)], vec![])
+        .unwrap();
+    ledger.end_block().unwrap();
+    ledger.commit().unwrap();
+
+    // 5% of the self-bond and of the redelegated OFUEL
+    let d = staking
+        .delegation_get(delegator, &other, MAIN_BRANCH_NAME)
+        .unwrap();
+    assert_eq!(units(38), d.amount);
+    let v = staking.validator_get(&other, MAIN_BRANCH_NAME).unwrap();
+    assert_eq!(units(38), v.delegated);
+    assert!(!v.jailed);
+    assert_eq!(
+        supply - units(5) - units(2),
+        ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME)
+    );
+    let queue = staking
+        .redelegations
+        .get_by_branch(&6, MAIN_BRANCH_NAME)
+        .unwrap();
+    assert_eq!(units(38), queue[0].amount);
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+
+    // heights 5 and 6, matured
+    new_block(&ledger, vec![], vec![]);
+    new_block(&ledger, vec![], vec![]);
+    assert!(
+        staking
+            .redelegations
+            .get_by_branch(&6, MAIN_BRANCH_NAME)
+            .is_none()
+    );
+}
+
+#[test]
+fn test_delegate_and_unbond() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let params = Params {
+        unbonding_blocks: 2,
+        ..Default::default()
+    };
+    staking
+        .params
+        .set_value_by_branch(params, MAIN_BRANCH_NAME)
+        .unwrap();
+
+    let id = validator_id(1);
+    add_validator(&ledger, id.clone(), 10);
+
+    let sk = secret_key(3);
+    let delegator = addr_of(&sk);
+    let balance = POWER_UNIT.saturating_mul(100.into());
+    set_balance(&ledger, delegator, balance);
+    new_block(&ledger, vec![], vec![]);
+
+    let units = |n: u64| POWER_UNIT.saturating_mul(n.into());
+    let fee = U256::from(GAS_PRICE) * U256::from(GAS_STAKING);
+
+    // height 2
+    let tx = native_tx(
+        &sk,
+        0,
+        Op::Staking(staking::Op::Delegate {
+            validator: id.clone(),
+            amount: units(30),
+        }),
+    );
+    let resp = new_block(&ledger, vec![tx], vec![]);
+    assert_eq!(1, resp.validator_updates.len());
+    assert_eq!(40, resp.validator_updates[0].power);
+    assert_eq!(balance - units(30) - fee, balance_of(&ledger, delegator));
+    let d = staking
+        .delegation_get(delegator, &id, MAIN_BRANCH_NAME)
+        .unwrap();
+    assert_eq!(units(30), d.amount);
+
+    // height 3, mature at height 5
+    let tx = native_tx(
+        &sk,
+        1,
+        Op::Staking(staking::Op::Undelegate {
+            validator: id.clone(),
+            amount: units(10),
+        }),
+    );
+    let resp = new_block(&ledger, vec![tx], vec![]);
+    assert_eq!(30, resp.validator_updates[0].power);
+
+    let locked = balance - units(30) - fee - fee;
+    new_block(&ledger, vec![], vec![]);
+    assert_eq!(locked, balance_of(&ledger, delegator));
+
+    new_block(&ledger, vec![], vec![]);
+    assert_eq!(locked + units(10), balance_of(&ledger, delegator));
+
+    let d = staking
+        .delegation_get(delegator, &id, MAIN_BRANCH_NAME)
+        .unwrap();
+    assert_eq!(units(20), d.amount);
+    assert_eq!(units(20), staking.stake_of(delegator, MAIN_BRANCH_NAME));
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+}
+
+#[test]
+fn test_distribute_rewards() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let id = validator_id(1);
+    add_validator(&ledger, id.clone(), 10);
+
+    // delegate as much as the self-bond
+    let delegator = H160::repeat_byte(0x33);
+    let amount = POWER_UNIT.saturating_mul(10.into());
+    let mut v = staking.validator_get(&id, MAIN_BRANCH_NAME).unwrap();
+    v.commission = 1000;
+    v.delegated = amount;
+    staking
+        .validators
+        .insert_by_branch(id.clone(), v.clone(), MAIN_BRANCH_NAME)
+        .unwrap();
+    bond(&ledger, delegator, amount, U256::zero());
+    staking
+        .delegations
+        .insert_by_branch(
+            (delegator, id.clone()),
+            Delegation { amount },
+            MAIN_BRANCH_NAME,
+        )
+        .unwrap();
+    adjust_supply(&ledger, amount, U256::zero());
+
+    // enter the consensus set
+    new_block(&ledger, vec![], vec![]);
+
+    let params = Params {
+        block_reward: 10_000.into(),
+        ..Default::default()
+    };
+    staking
+        .params
+        .set_value_by_branch(params, MAIN_BRANCH_NAME)
+        .unwrap();
+    let supply = ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME);
+    new_block(&ledger, vec![], vec![]);
+
+    // 10% commission, and the rest is shared by the bonds
+    assert_eq!(
+        U256::from(1_000 + 4_500),
+        staking.rewards_get(&v.operator, MAIN_BRANCH_NAME)
+    );
+    assert_eq!(
+        U256::from(4_500),
+        staking.rewards_get(&delegator, MAIN_BRANCH_NAME)
+    );
+    assert_eq!(
+        supply + 10_000,
+        ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME)
+    );
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+
+    // only 4_000 can still be issued
+    let cap = supply + 10_000 + 4_000;
+    ledger.main.write().state.evm.OFUEL.issue_cap = Some(cap);
+    for _ in 0..2 {
+        new_block(&ledger, vec![], vec![]);
+        assert_eq!(cap, ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME));
+    }
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+}
+
+#[test]
+fn test_mint_over_issue_cap() {
+    let ledger = init_ledger();
+    let mut token = ledger.state.evm.OFUEL.clone();
+    let supply = token.total_supply(MAIN_BRANCH_NAME);
+    token.issue_cap = Some(supply + 100);
+
+    let addr = H160::repeat_byte(0x77);
+    assert!(token.mint(addr, 101.into(), MAIN_BRANCH_NAME).is_err());
+    assert_eq!(supply, token.total_supply(MAIN_BRANCH_NAME));
+    assert_eq!(U256::zero(), balance_of(&ledger, addr));
+
+    token.mint(addr, 100.into(), MAIN_BRANCH_NAME).unwrap();
+    assert_eq!(U256::zero(), token.issuable(MAIN_BRANCH_NAME));
+    assert!(token.mint(addr, 1.into(), MAIN_BRANCH_NAME).is_err());
+    assert_eq!(supply + 100, token.total_supply(MAIN_BRANCH_NAME));
+    assert_eq!(U256::from(100), balance_of(&ledger, addr));
+}
+
+#[test]
+fn test_gov_tally() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let gov = &ledger.state.gov;
+
+    let params = gov::Params {
+        voting_blocks: 2,
+        min_proposer_stake: POWER_UNIT.saturating_mul(50.into()),
+        ..Default::default()
+    };
+    gov.params
+        .set_value_by_branch(params, MAIN_BRANCH_NAME)
+        .unwrap();
+
+    // bonded 60 and 40
+    let (sk_a, sk_b) = (secret_key(1), secret_key(2));
+    for (sk, seed, power) in [(&sk_a, 1, 60), (&sk_b, 2, 40)] {
+        let id = validator_id(seed);
+        add_validator(&ledger, id.clone(), power);
+        set_operator(&ledger, &id, addr_of(sk));
+        set_balance(&ledger, addr_of(sk), *POWER_UNIT);
+    }
+    new_block(&ledger, vec![], vec![]);
+
+    let submit = |sk, nonce, change| {
+        native_tx(
+            sk,
+            nonce,
+            Op::Gov(gov::Op::SubmitProposal {
+                changes: vec![change],
+                memo: String::new(),
+                apply_height: 5,
+            }),
+        )
+    };
+    let vote = |sk, nonce, proposal_id, option| {
+        native_tx(
+            sk,
+            nonce,
+            Op::Gov(gov::Op::Vote {
+                proposal_id,
+                option,
+            }),
+        )
+    };
+
+    // height 2, voting ends at height 4
+    let txs = vec![
+        submit(&sk_a, 0, ParamChange::GasPrice(20.into())),
+        submit(&sk_a, 1, ParamChange::BlockMaxBytes(1000)),
+    ];
+    new_block(&ledger, txs, vec![]);
+
+    // height 3, nobody votes on the second one
+    let txs = vec![
+        vote(&sk_a, 2, 0, VoteOption::Yes),
+        vote(&sk_b, 0, 0, VoteOption::No),
+    ];
+    new_block(&ledger, txs, vec![]);
+
+    // height 4, bonded less than the min proposer stake
+    let tx = submit(&sk_b, 1, ParamChange::GasPrice(30.into()));
+    new_block(&ledger, vec![tx], vec![]);
+    assert!(gov.proposal_get(2, MAIN_BRANCH_NAME).is_none());
+
+    let p = gov.proposal_get(0, MAIN_BRANCH_NAME).unwrap();
+    assert_eq!(ProposalStatus::Passed, p.status);
+    assert_eq!(POWER_UNIT.saturating_mul(60.into()), p.tally.yes);
+    assert_eq!(POWER_UNIT.saturating_mul(40.into()), p.tally.no);
+    assert!(gov.votes.get_by_branch(&0, MAIN_BRANCH_NAME).is_none());
+
+    // not enough votes for the quorum
+    let p = gov.proposal_get(1, MAIN_BRANCH_NAME).unwrap();
+    assert_eq!(ProposalStatus::Rejected, p.status);
+
+    // height 5
+    new_block(&ledger, vec![], vec![]);
+
+    let p = gov.proposal_get(0, MAIN_BRANCH_NAME).unwrap();
+    assert_eq!(ProposalStatus::Applied, p.status);
+    assert_eq!(
+        Some(U256::from(20)),
+        ledger
+            .state
+            .evm
+            .gas_price
+            .get_value_by_branch(MAIN_BRANCH_NAME)
+    );
+    assert_ne!(
+        Some(1000),
+        ledger
+            .state
+            .block_max_bytes
+            .get_value_by_branch(MAIN_BRANCH_NAME)
+    );
+}
+
+#[test]
+fn test_halt_for_unknown_upgrade() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let plan = UpgradePlan {
+        name: String::from("v2"),
+        height: 3,
+        info: String::new(),
+    };
+    ledger
+        .state
+        .upgrade_plan
+        .set_value_by_branch(Some(plan), MAIN_BRANCH_NAME)
+        .unwrap();
+    new_block(&ledger, vec![], vec![]);
+
+    ledger.consensus_refresh(validator_id(0), 2).unwrap();
+    assert!(ledger.upgrade().unwrap().is_none());
+    ledger.end_block().unwrap();
+    ledger.commit().unwrap();
+
+    // no migration is registered for it in this binary
+    ledger.consensus_refresh(validator_id(0), 3).unwrap();
+    let plan = ledger.upgrade().unwrap().unwrap();
+    assert_eq!("v2", plan.name);
+    assert_eq!(2, ledger.main.read().last_block().unwrap().header.height);
+}
+
+#[test]
+fn test_burn_base_fee_and_tip_proposer() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    // the proposer of all blocks
+    let proposer = validator_id(0);
+    add_validator(&ledger, proposer.clone(), 10);
+    let operator = H160::from_slice(&proposer);
+
+    let sk = secret_key(5);
+    let from = addr_of(&sk);
+    let to = H160::repeat_byte(0x55);
+    let balance = *POWER_UNIT;
+    set_balance(&ledger, from, balance);
+    new_block(&ledger, vec![], vec![]);
+
+    let supply = ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME);
+    new_block(&ledger, vec![evm_transfer(&sk, 0, to, 1000)], vec![]);
+
+    // gas price 15, and the base fee is 10
+    let gas = U256::from(21_000);
+    assert_eq!(balance - 1000 - gas * 15, balance_of(&ledger, from));
+    assert_eq!(U256::from(1000), balance_of(&ledger, to));
+    assert_eq!(gas * 5, balance_of(&ledger, operator));
+    assert_eq!(
+        supply - gas * 10,
+        ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME)
+    );
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+}
+
+#[test]
+fn test_reject_tx_over_block_gas_limit() {
+    let ledger = init_ledger();
+    let mut sb = StateBranch::new(&ledger.state, MAIN_BRANCH_NAME).unwrap();
+
+    let sk = secret_key(6);
+    let from = addr_of(&sk);
+    let to = H160::repeat_byte(0x66);
+    let balance = *POWER_UNIT;
+    set_balance(&ledger, from, balance);
+
+    let transfer = |nonce, gas_limit| {
+        let action = TransactionAction::Call(to);
+        evm_tx(&sk, nonce, action, 1000.into(), 15, gas_limit, vec![])
+    };
+
+    // the block gas limit is 3000000
+    let e = sb.apply_tx(transfer(0, 3_000_001)).unwrap_err();
+    assert!(e.to_string().contains("exceeds the block gas limit"));
+    assert_eq!(balance, balance_of(&ledger, from));
+    assert_eq!(U256::zero(), balance_of(&ledger, to));
+
+    sb.apply_tx(transfer(0, 3_000_000)).unwrap();
+    assert_eq!(U256::from(1000), balance_of(&ledger, to));
+}
+
+#[test]
+fn test_refund_unused_gas() {
+    let ledger = init_ledger();
+    let mut sb = StateBranch::new(&ledger.state, MAIN_BRANCH_NAME).unwrap();
+
+    let sk = secret_key(7);
+    let from = addr_of(&sk);
+    let to = H160::repeat_byte(0x77);
+    let balance = *POWER_UNIT;
+    set_balance(&ledger, from, balance);
+
+    let transfer = |nonce, gas_limit| {
+        let action = TransactionAction::Call(to);
+        evm_tx(&sk, nonce, action, 1000.into(), 15, gas_limit, vec![])
+    };
+
+    // below the intrinsic gas of a plain transfer
+    assert!(sb.apply_tx(transfer(0, 20_999)).is_err());
+    assert_eq!(balance, balance_of(&ledger, from));
+
+    // only the used 21000 is paid
+    sb.apply_tx(transfer(0, 100_000)).unwrap();
+    assert_eq!(
+        balance - 1000 - U256::from(21_000 * 15),
+        balance_of(&ledger, from)
+    );
+}
+
+// Runtime: `sstore(0, calldataload(0))`
+const STORE_CODE: &str = "6007600c60003960076000f360003560005500";
+
+fn store_value(sk: &SecretKey, nonce: u64, contract: H160, value: u64) -> Tx {
+    let action = TransactionAction::Call(contract);
+    let input = H256::from_low_u64_be(value).as_bytes().to_vec();
+    evm_tx(sk, nonce, action, U256::zero(), 15, 100_000, input)
+}
+
+fn last_state_root(ledger: &Ledger) -> H256 {
+    H256::from_slice(&ledger.main.read().last_state_root())
+}
+
+#[test]
+fn test_state_root_proofs() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(8);
+    let from = addr_of(&sk);
+    set_balance(&ledger, from, *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+
+    let code = hex::decode(STORE_CODE).unwrap();
+    let tx = evm_tx(
+        &sk,
+        0,
+        TransactionAction::Create,
+        U256::zero(),
+        15,
+        200_000,
+        code,
+    );
+    new_block(&ledger, vec![tx], vec![]);
+    let contract = ledger
+        .main
+        .read()
+        .last_block()
+        .unwrap()
+        .header
+        .receipts
+        .values()
+        .next()
+        .unwrap()
+        .contract_addr
+        .unwrap();
+
+    new_block(&ledger, vec![store_value(&sk, 1, contract, 7)], vec![]);
+
+    let root = last_state_root(&ledger);
+    let key = smt::storage_key(&contract, &H256::zero());
+    let leaf = smt::storage_leaf(&H256::from_low_u64_be(7));
+    let proof = ledger.state.smt.proof(key, MAIN_BRANCH_NAME);
+    assert!(smt::verify(&root, &key, &leaf, &proof));
+    assert!(!smt::verify(&root, &key, &H256::zero(), &proof));
+
+    let account = ledger
+        .state
+        .evm
+        .OFUEL
+        .accounts
+        .get_by_branch(&from, MAIN_BRANCH_NAME)
+        .unwrap();
+    let key = smt::account_key(&from);
+    let proof = ledger.state.smt.proof(key, MAIN_BRANCH_NAME);
+    assert!(smt::verify(
+        &root,
+        &key,
+        &smt::account_leaf(&account),
+        &proof
+    ));
+
+    // clear the slot, it must be absent from the new root
+    new_block(&ledger, vec![store_value(&sk, 2, contract, 0)], vec![]);
+
+    let root = last_state_root(&ledger);
+    let key = smt::storage_key(&contract, &H256::zero());
+    let proof = ledger.state.smt.proof(key, MAIN_BRANCH_NAME);
+    assert!(smt::verify(&root, &key, &H256::zero(), &proof));
+    assert!(
+        ledger
+            .state
+            .evm
+            .OFUEL
+            .storages
+            .get_by_branch(&(&contract, &H256::zero()), MAIN_BRANCH_NAME)
+            .is_none()
+    );
+}
+
+// Fork from the state at the end of `height`, the same as the RPC queries.
+fn branch_at(ledger: &Ledger, height: u64, name: &str) {
+    let ver = VsVersion::block_end(height).encode_value();
+    ledger
+        .state
+        .branch_create_by_base_branch_version(
+            name.into(),
+            ParentBranchName::from(MAIN_BRANCH_NAME.0),
+            ver.as_ref().into(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn test_historical_proofs() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(22);
+    set_balance(&ledger, addr_of(&sk), *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+    let contract = deploy(&ledger, &sk, 0, STORE_CODE);
+
+    new_block(&ledger, vec![store_value(&sk, 1, contract, 7)], vec![]);
+    let height = ledger.main.read().last_block().unwrap().header.height;
+    new_block(&ledger, vec![store_value(&sk, 2, contract, 9)], vec![]);
+
+    let header = ledger.state.blocks.get(&height).unwrap().header;
+    let root = H256::from_slice(&header.state_root);
+    assert_ne!(root, last_state_root(&ledger));
+
+    branch_at(&ledger, height, "historical_proofs");
+    let b = vsdb::BranchName::from("historical_proofs");
+    assert_eq!(root, ledger.state.smt.root(b));
+
+    let key = smt::storage_key(&contract, &H256::zero());
+    let value = ledger
+        .state
+        .evm
+        .OFUEL
+        .storages
+        .get_by_branch(&(&contract, &H256::zero()), b)
+        .unwrap();
+    assert_eq!(H256::from_low_u64_be(7), value);
+    let proof = ledger.state.smt.proof(key, b);
+    assert!(smt::verify(&root, &key, &smt::storage_leaf(&value), &proof));
+    let leaf = smt::storage_leaf(&H256::from_low_u64_be(9));
+    assert!(!smt::verify(&root, &key, &leaf, &proof));
+
+    let account = ledger
+        .state
+        .evm
+        .OFUEL
+        .accounts
+        .get_by_branch(&addr_of(&sk), b)
+        .unwrap();
+    assert_eq!(U256::from(2), account.nonce);
+    let key = smt::account_key(&addr_of(&sk));
+    let proof = ledger.state.smt.proof(key, b);
+    assert!(smt::verify(
+        &root,
+        &key,
+        &smt::account_leaf(&account),
+        &proof
+    ));
+
+    ledger.state.branch_remove(b).unwrap();
+}
+
+#[test]
+fn test_tx_proofs() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(9);
+    let from = addr_of(&sk);
+    let to = H160::repeat_byte(0x99);
+    set_balance(&ledger, from, *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+
+    let txs = (0..3).map(|n| evm_transfer(&sk, n, to, 1000)).collect();
+    new_block(&ledger, txs, vec![]);
+
+    let block = ledger.main.read().last_block().unwrap();
+    let root = block.header.tx_merkle.root_hash.clone();
+    for (i, tx_hash) in block.header.tx_merkle.leaves[..3].iter().enumerate() {
+        let (height, _, idx) = ledger.state.block_by_tx_hash(tx_hash).unwrap();
+        assert_eq!(block.header.height, height);
+        assert_eq!(i as u64, idx);
+
+        let mut proof = block.tx_proof(idx).unwrap();
+        assert_eq!(tx_hash, &proof.tx_hash);
+        assert!(verify_tx_proof(&root, &proof));
+
+        proof.tx_index ^= 1;
+        assert!(!verify_tx_proof(&root, &proof));
+    }
+
+    // the sentinel is not a transaction
+    assert!(block.tx_proof(3).is_none());
+}
+
+#[test]
+fn test_receipts() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(10);
+    let from = addr_of(&sk);
+    let to = H160::repeat_byte(0xaa);
+    set_balance(&ledger, from, *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+
+    let txs = (0..2).map(|n| evm_transfer(&sk, n, to, 1000)).collect();
+    new_block(&ledger, txs, vec![]);
+
+    let block = ledger.main.read().last_block().unwrap();
+    for (i, tx_hash) in block.header.tx_merkle.leaves[..2].iter().enumerate() {
+        let r = block.header.receipts.get(tx_hash).unwrap();
+        assert!(r.status_code);
+        assert_eq!(i as u64, r.tx_index);
+        assert_eq!(Some(from), r.from);
+        assert_eq!(Some(to), r.to);
+        assert_eq!(U256::from(21_000), r.tx_gas_used);
+        assert_eq!(U256::from(21_000 * (1 + i)), r.cumulative_gas_used);
+        // a legacy transaction pays its gas price
+        assert_eq!(U256::from(15), r.effective_gas_price);
+        assert_eq!(0, r.tx_type);
+    }
+}
+
+// Deploy a contract in a new block, return its address.
+fn deploy(ledger: &Ledger, sk: &SecretKey, nonce: u64, code: &str) -> H160 {
+    let code = hex::decode(code).unwrap();
+    let action = TransactionAction::Create;
+    let tx = evm_tx(sk, nonce, action, U256::zero(), 15, 200_000, code);
+    new_block(ledger, vec![tx], vec![]);
+    let block = ledger.main.read().last_block().unwrap();
+    block
+        .header
+        .receipts
+        .values()
+        .next()
+        .unwrap()
+        .contract_addr
+        .unwrap()
+}
+
+// Runtime: `log0(0, 0)` twice
+const LOG_CODE: &str = "600b600c600039600b6000f360006000a060006000a000";
+
+#[test]
+fn test_log_indexes() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(11);
+    set_balance(&ledger, addr_of(&sk), *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+
+    let contract = deploy(&ledger, &sk, 0, LOG_CODE);
+    let txs = (1..3)
+        .map(|n| {
+            let action = TransactionAction::Call(contract);
+            evm_tx(&sk, n, action, U256::zero(), 15, 100_000, vec![])
+        })
+        .collect();
+    new_block(&ledger, txs, vec![]);
+
+    let block = ledger.main.read().last_block().unwrap();
+    let mut log_index = 0;
+    for (i, tx_hash) in block.header.tx_merkle.leaves[..2].iter().enumerate() {
+        let r = block.header.receipts.get(tx_hash).unwrap();
+        assert_eq!(2, r.logs.len());
+        for (j, l) in r.logs.iter().enumerate() {
+            assert_eq!(contract, l.address);
+            assert_eq!(i as u64, l.tx_index);
+            assert_eq!(j as u64, l.log_index_in_tx);
+            // continues across the transactions of a block
+            assert_eq!(log_index, l.log_index_in_block);
+            log_index += 1;
+        }
+    }
+}
+
+#[test]
+fn test_hash_indexes() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(12);
+    let to = H160::repeat_byte(0xcc);
+    set_balance(&ledger, addr_of(&sk), *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+    new_block(&ledger, vec![evm_transfer(&sk, 0, to, 1000)], vec![]);
+    new_block(&ledger, vec![evm_transfer(&sk, 1, to, 1000)], vec![]);
+
+    // each of the last two blocks has one transaction
+    for height in 2..=3 {
+        let block = ledger.state.blocks.get(&height).unwrap();
+
+        let hash = ledger.state.evm.block_hashes.get(&height).unwrap();
+        let (h, b) = ledger.state.block_by_hash(&hash).unwrap();
+        assert_eq!(height, h);
+        assert_eq!(block.header_hash, b.header_hash);
+
+        let tx_hash = &block.header.tx_merkle.leaves[0];
+        let (h, b, idx) = ledger.state.block_by_tx_hash(tx_hash).unwrap();
+        assert_eq!(height, h);
+        assert_eq!(block.header_hash, b.header_hash);
+        assert_eq!(0, idx);
+    }
+
+    assert!(ledger.state.block_by_hash(&H256::zero()).is_none());
+    assert!(ledger.state.block_by_tx_hash(&[0; 32]).is_none());
+}
+
+#[test]
+fn test_load_from_snapshot() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let path = format!(
+        "{}/overeality/ledger/ledger.json",
+        vsdb::vsdb_get_custom_dir()
+    );
+
+    new_block(&ledger, vec![], vec![]);
+    let stale = std::fs::read(&path).unwrap();
+    new_block(&ledger, vec![], vec![]);
+    let latest = std::fs::read(&path).unwrap();
+
+    // the metadata is behind the data in vsdb
+    std::fs::write(&path, &stale).unwrap();
+    assert!(Ledger::load_from_snapshot().is_err());
+
+    std::fs::write(&path, &latest[..latest.len() / 2]).unwrap();
+    assert!(Ledger::load_from_snapshot().is_err());
+
+    std::fs::write(&path, &latest).unwrap();
+    let last = ledger.main.read().last_block().unwrap();
+    let loaded = Ledger::load_from_snapshot().unwrap().unwrap();
+    let block = loaded.main.read().last_block().unwrap();
+    assert_eq!(last.header.height, block.header.height);
+    assert_eq!(last.header_hash, block.header_hash);
+    assert_eq!(last_state_root(&ledger), last_state_root(&loaded));
+
+    // the loaded one goes on from the last block
+    new_block(&loaded, vec![], vec![]);
+    let block = loaded.main.read().last_block().unwrap();
+    assert_eq!(1 + last.header.height, block.header.height);
+    assert_eq!(last.header_hash, block.header.prev_hash);
+}
+
+#[test]
+fn test_pruning_from_str() {
+    assert_eq!(Ok(Pruning::Archive), Pruning::from_str("archive"));
+    assert_eq!(Ok(Pruning::KeepLast(3)), Pruning::from_str("keep-last:3"));
+    assert_eq!(Ok(Pruning::KeepEvery(5)), Pruning::from_str("keep-every:5"));
+
+    for p in [
+        Pruning::Archive,
+        Pruning::KeepLast(3),
+        Pruning::KeepEvery(5),
+    ] {
+        assert_eq!(Ok(p), Pruning::from_str(&p.to_string()));
+    }
+
+    for s in [
+        "",
+        "archive:1",
+        "keep-last",
+        "keep-last:",
+        "keep-last:0",
+        "keep-last:-1",
+        "keep-every:0",
+        "prune-every:5",
+    ] {
+        assert!(Pruning::from_str(s).is_err());
+    }
+}
+
+// Query the state at `height` in the same way as the RPC server.
+fn call_at(ledger: &Ledger, height: u64) -> ruc::Result<()> {
+    ledger
+        .state
+        .evm
+        .call_contract(
+            &ledger.state.staking,
+            MAIN_BRANCH_NAME,
+            CallRequest::default(),
+            Some(BlockNumber::Num(height)),
+        )
+        .map(|_| ())
+}
+
+#[test]
+fn test_keep_last_blocks() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    ledger.set_pruning(Pruning::KeepLast(3));
+
+    let sk = secret_key(13);
+    let to = H160::repeat_byte(0xdd);
+    set_balance(&ledger, addr_of(&sk), *POWER_UNIT);
+    for n in 0..6 {
+        new_block(&ledger, vec![evm_transfer(&sk, n, to, 1000)], vec![]);
+    }
+
+    // the state of the heights 4, 5 and 6
+    assert_eq!(4, ledger.state.evm.earliest_height.get_value());
+    for h in 4..=6 {
+        call_at(&ledger, h).unwrap();
+
+        // the first version of each kept block is not squashed
+        let ver = VsVersion::new(h, 0).encode_value();
+        assert!(
+            ledger
+                .state
+                .version_exists_on_branch(ver.as_ref().into(), MAIN_BRANCH_NAME)
+        );
+    }
+    for h in 1..4 {
+        let e = call_at(&ledger, h).unwrap_err();
+        assert!(e.to_string().contains("has been pruned"));
+    }
+
+    // the blocks and the latest state are intact
+    for h in 1..=6 {
+        assert!(ledger.state.blocks.get(&h).is_some());
+    }
+    assert_eq!(U256::from(6000), balance_of(&ledger, to));
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+}
+
+#[test]
+fn test_keep_every_blocks() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    ledger.set_pruning(Pruning::KeepEvery(3));
+
+    let sk = secret_key(23);
+    let to = H160::repeat_byte(0xde);
+    set_balance(&ledger, addr_of(&sk), *POWER_UNIT);
+    for n in 0..7 {
+        new_block(&ledger, vec![evm_transfer(&sk, n, to, 1000)], vec![]);
+    }
+
+    // the state of the heights 3, 6 and the latest one
+    assert_eq!(7, ledger.state.evm.earliest_height.get_value());
+    for h in [3, 6, 7] {
+        call_at(&ledger, h).unwrap();
+    }
+    for h in [1, 2, 4, 5] {
+        let e = call_at(&ledger, h).unwrap_err();
+        assert!(e.to_string().contains("has been pruned"));
+    }
+
+    // the kept states are intact after the pruning of the 'main' branch
+    for h in [3, 6] {
+        let name = ovr::ledger::kept_branch_name(h);
+        let b = vsdb::BranchName::from(name.as_str());
+        let header = ledger.state.blocks.get(&h).unwrap().header;
+        assert_eq!(
+            H256::from_slice(&header.state_root),
+            ledger.state.smt.root(b)
+        );
+
+        let account = ledger.state.evm.OFUEL.accounts.get_by_branch(&to, b);
+        assert_eq!(U256::from(1000 * h), account.unwrap().balance);
+        ledger.state.check_supply(b).unwrap();
+    }
+
+    // the versions in between have been squashed
+    let ver = VsVersion::new(5, 0).encode_value();
+    assert!(!ledger
+        .state
+        .version_exists_on_branch(ver.as_ref().into(), MAIN_BRANCH_NAME));
+    assert_eq!(U256::from(7000), balance_of(&ledger, to));
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+}
+
+// Encode a call of the precompiled contracts, all arguments are static words.
+fn abi_call(selector: u32, args: &[H256]) -> Vec<u8> {
+    let mut input = selector.to_be_bytes().to_vec();
+    args.iter()
+        .for_each(|a| input.extend_from_slice(a.as_bytes()));
+    input
+}
+
+fn word(n: u64) -> H256 {
+    H256::from_low_u64_be(n)
+}
+
+fn ofuel_call(ledger: &Ledger, sk: &SecretKey, nonce: u64, input: Vec<u8>) -> Tx {
+    let action = TransactionAction::Call(ledger.state.evm.OFUEL.contract_addr);
+    evm_tx(sk, nonce, action, U256::zero(), 15, 100_000, input)
+}
+
+// The receipt of the only transaction in the last block.
+fn last_receipt(ledger: &Ledger) -> Option<Receipt> {
+    let block = ledger.main.read().last_block().unwrap();
+    block.header.receipts.values().next().cloned()
+}
+
+fn allowance_of(ledger: &Ledger, owner: H160, spender: H160) -> U256 {
+    ledger
+        .state
+        .evm
+        .OFUEL
+        .allowances
+        .get_by_branch(&(owner, spender), MAIN_BRANCH_NAME)
+        .unwrap_or_default()
+}
+
+fn event(sig: &[u8]) -> H256 {
+    H256::from_slice(Keccak256::digest(sig).as_slice())
+}
+
+#[test]
+fn test_ofuel_transfer_and_approve() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let contract = ledger.state.evm.OFUEL.contract_addr;
+
+    let owner_sk = secret_key(20);
+    let owner = addr_of(&owner_sk);
+    let spender_sk = secret_key(21);
+    let spender = addr_of(&spender_sk);
+    let to = H160::repeat_byte(0xee);
+    set_balance(&ledger, owner, *POWER_UNIT);
+    set_balance(&ledger, spender, *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+
+    // transfer(address,uint256)
+    let input = abi_call(0xa9059cbb, &[to.into(), word(1000)]);
+    new_block(
+        &ledger,
+        vec![ofuel_call(&ledger, &owner_sk, 0, input)],
+        vec![],
+    );
+    let r = last_receipt(&ledger).unwrap();
+    assert!(r.status_code);
+    assert_eq!(U256::from(1000), balance_of(&ledger, to));
+    assert_eq!(
+        *POWER_UNIT - 1000 - r.tx_gas_used * 15,
+        balance_of(&ledger, owner)
+    );
+    assert_eq!(1, r.logs.len());
+    assert_eq!(contract, r.logs[0].address);
+    assert_eq!(
+        vec![
+            event(b"Transfer(address,address,uint256)"),
+            owner.into(),
+            to.into()
+        ],
+        r.logs[0].topics
+    );
+    assert_eq!(word(1000).as_bytes(), r.logs[0].data.as_slice());
+
+    // approve(address,uint256)
+    let input = abi_call(0x095ea7b3, &[spender.into(), word(500)]);
+    new_block(
+        &ledger,
+        vec![ofuel_call(&ledger, &owner_sk, 1, input)],
+        vec![],
+    );
+    let r = last_receipt(&ledger).unwrap();
+    assert!(r.status_code);
+    assert_eq!(U256::from(500), allowance_of(&ledger, owner, spender));
+    assert_eq!(
+        vec![
+            event(b"Approval(address,address,uint256)"),
+            owner.into(),
+            spender.into()
+        ],
+        r.logs[0].topics
+    );
+    assert_eq!(word(500).as_bytes(), r.logs[0].data.as_slice());
+
+    // transferFrom(address,address,uint256)
+    let input = abi_call(0x23b872dd, &[owner.into(), to.into(), word(300)]);
+    let tx = ofuel_call(&ledger, &spender_sk, 0, input.clone());
+    new_block(&ledger, vec![tx], vec![]);
+    let r = last_receipt(&ledger).unwrap();
+    assert!(r.status_code);
+    assert_eq!(U256::from(1300), balance_of(&ledger, to));
+    assert_eq!(U256::from(200), allowance_of(&ledger, owner, spender));
+    assert_eq!(
+        vec![
+            event(b"Transfer(address,address,uint256)"),
+            owner.into(),
+            to.into()
+        ],
+        r.logs[0].topics
+    );
+    assert_eq!(word(300).as_bytes(), r.logs[0].data.as_slice());
+
+    // over the remaining allowance, reverted
+    let tx = ofuel_call(&ledger, &spender_sk, 1, input);
+    new_block(&ledger, vec![tx], vec![]);
+    assert!(last_receipt(&ledger).is_none());
+    assert_eq!(U256::from(1300), balance_of(&ledger, to));
+    assert_eq!(U256::from(200), allowance_of(&ledger, owner, spender));
+}
+
+// Runtime: `sstore(0, staticcall(gas(), 0x1000, 0, calldatasize(), 0, 0))`
+// with the calldata copied to the memory
+const STATIC_CALL_CODE: &str =
+    "6016600c60003960166000f3366000600037600060003660006110005afa60005500";
+
+#[test]
+fn test_ofuel_static_call_and_dirty_address() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(22);
+    let owner = addr_of(&sk);
+    let to = H160::repeat_byte(0xef);
+    set_balance(&ledger, owner, *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+
+    let contract = deploy(&ledger, &sk, 0, STATIC_CALL_CODE);
+    let input = abi_call(0xa9059cbb, &[contract.into(), word(1000)]);
+    new_block(&ledger, vec![ofuel_call(&ledger, &sk, 1, input)], vec![]);
+    let success = |ledger: &Ledger| {
+        ledger
+            .state
+            .evm
+            .OFUEL
+            .storages
+            .get_by_branch(&(&contract, &H256::zero()), MAIN_BRANCH_NAME)
+            .unwrap_or_default()
+    };
+    let call = |nonce, input| {
+        let action = TransactionAction::Call(contract);
+        evm_tx(&sk, nonce, action, U256::zero(), 15, 200_000, input)
+    };
+
+    // balanceOf(address), queries are allowed in a static call
+    let input = abi_call(0x70a08231, &[contract.into()]);
+    new_block(&ledger, vec![call(2, input)], vec![]);
+    assert_eq!(word(1), success(&ledger));
+
+    // transfer(address,uint256), writes are rejected in a static call
+    let input = abi_call(0xa9059cbb, &[to.into(), word(100)]);
+    new_block(&ledger, vec![call(3, input)], vec![]);
+    assert!(last_receipt(&ledger).unwrap().status_code);
+    assert_eq!(H256::zero(), success(&ledger));
+    assert_eq!(U256::from(1000), balance_of(&ledger, contract));
+    assert!(balance_of(&ledger, to).is_zero());
+
+    // an address with dirty high bytes is reverted instead of being truncated
+    let mut dirty = H256::from(to);
+    dirty.0[0] = 0xff;
+    let input = abi_call(0xa9059cbb, &[dirty, word(100)]);
+    new_block(&ledger, vec![ofuel_call(&ledger, &sk, 4, input)], vec![]);
+    assert!(last_receipt(&ledger).is_none());
+    assert!(balance_of(&ledger, to).is_zero());
+}
+
+fn staking_call(sk: &SecretKey, nonce: u64, value: u64, input: Vec<u8>) -> Tx {
+    let action = TransactionAction::Call(H160::from_low_u64_be(0x1001));
+    evm_tx(sk, nonce, action, value.into(), 15, 200_000, input)
+}
+
+// Runtime: `call(gas(), 0x1001, 0, 0, calldatasize(), 0, 0)` with the calldata
+// copied to the memory, then revert if `callvalue()` is not zero
+const STAKING_REVERT_CODE: &str =
+    "6021600c60003960216000f3366000600037600060003660006000611001\
+     5af1503415601f5760006000fd5b00";
+
+#[test]
+fn test_staking_precompile_delegate() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let staking = &ledger.state.staking;
+
+    let id = validator_id(1);
+    let validator = H160::from_slice(&id);
+    add_validator(&ledger, id.clone(), 10);
+
+    let sk = secret_key(23);
+    let delegator = addr_of(&sk);
+    let balance = POWER_UNIT.saturating_mul(100.into());
+    set_balance(&ledger, delegator, balance);
+    new_block(&ledger, vec![], vec![]);
+
+    let units = |n: u64| POWER_UNIT.saturating_mul(n.into());
+    let unit_word = |n: u64| {
+        let mut w = [0; 32];
+        units(n).to_big_endian(&mut w);
+        H256::from(w)
+    };
+    let delegation = |addr| {
+        staking
+            .delegation_get(addr, &id, MAIN_BRANCH_NAME)
+            .map(|d| d.amount)
+            .unwrap_or_default()
+    };
+
+    // delegate(address,uint256)
+    let input = abi_call(0x026e402b, &[validator.into(), unit_word(30)]);
+    let resp = new_block(&ledger, vec![staking_call(&sk, 0, 0, input)], vec![]);
+    let r = last_receipt(&ledger).unwrap();
+    assert!(r.status_code);
+    assert_eq!(40, resp.validator_updates[0].power);
+    assert_eq!(units(30), delegation(delegator));
+    assert_eq!(
+        balance - units(30) - r.tx_gas_used * 15,
+        balance_of(&ledger, delegator)
+    );
+
+    // undelegate(address,uint256)
+    let input = abi_call(0x4d99dd16, &[validator.into(), unit_word(10)]);
+    let resp = new_block(&ledger, vec![staking_call(&sk, 1, 0, input)], vec![]);
+    assert!(last_receipt(&ledger).unwrap().status_code);
+    assert_eq!(30, resp.validator_updates[0].power);
+    assert_eq!(units(20), delegation(delegator));
+
+    // more than the delegated amount
+    let input = abi_call(0x4d99dd16, &[validator.into(), unit_word(30)]);
+    new_block(&ledger, vec![staking_call(&sk, 2, 0, input)], vec![]);
+    assert!(last_receipt(&ledger).is_none());
+    assert_eq!(units(20), delegation(delegator));
+
+    // delegate through a contract
+    let contract = deploy(&ledger, &sk, 3, STAKING_REVERT_CODE);
+    let input = abi_call(0xa9059cbb, &[contract.into(), unit_word(10)]);
+    new_block(&ledger, vec![ofuel_call(&ledger, &sk, 4, input)], vec![]);
+
+    let call = |nonce, value| {
+        let input = abi_call(0x026e402b, &[validator.into(), unit_word(5)]);
+        let action = TransactionAction::Call(contract);
+        evm_tx(&sk, nonce, action, U256::from(value), 15, 200_000, input)
+    };
+    new_block(&ledger, vec![call(5, 0)], vec![]);
+    assert!(last_receipt(&ledger).unwrap().status_code);
+    assert_eq!(units(5), delegation(contract));
+    assert_eq!(units(5), balance_of(&ledger, contract));
+
+    // the staking operations are discarded along with the reverted call
+    new_block(&ledger, vec![call(6, 1)], vec![]);
+    assert!(last_receipt(&ledger).is_none());
+    assert_eq!(units(5), delegation(contract));
+    assert_eq!(units(5), balance_of(&ledger, contract));
+
+    ledger.state.check_supply(MAIN_BRANCH_NAME).unwrap();
+}
+
+// Call a precompiled contract at the latest height.
+fn query(ledger: &Ledger, to: u64, gas: u64, input: Vec<u8>) -> CallContractResp {
+    let req = CallRequest {
+        to: Some(H160::from_low_u64_be(to)),
+        gas: Some(gas.into()),
+        data: Some(Bytes::new(input)),
+        ..Default::default()
+    };
+    ledger
+        .state
+        .evm
+        .call_contract(&ledger.state.staking, MAIN_BRANCH_NAME, req, None)
+        .unwrap()
+}
+
+#[test]
+fn test_staking_precompile_queries() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    for n in 1..=3 {
+        add_validator(&ledger, validator_id(n), 10);
+    }
+    let sk = secret_key(24);
+    let delegator = addr_of(&sk);
+    let validator = H160::from_slice(&validator_id(2));
+    set_balance(&ledger, delegator, *POWER_UNIT);
+    let tx = native_tx(
+        &sk,
+        0,
+        Op::Staking(staking::Op::Delegate {
+            validator: validator_id(2),
+            amount: 1000.into(),
+        }),
+    );
+    new_block(&ledger, vec![tx], vec![]);
+
+    // getValidators(): offset, length, then the left-padded ids
+    let resp = query(&ledger, 0x1001, 100_000, abi_call(0xb7ab4db5, &[]));
+    assert!(matches!(resp.evm_resp, ExitReason::Succeed(_)));
+    let mut expected = vec![word(32), word(3)];
+    for n in 1..=3 {
+        expected.push(H256::from(H160::from_slice(&validator_id(n))));
+    }
+    let expected = expected
+        .iter()
+        .flat_map(|w| w.as_bytes().to_vec())
+        .collect::<Vec<_>>();
+    assert_eq!(expected, resp.data);
+
+    // charged per returned validator: 21000 + 4 * 16 + 1000 + 3 * 500
+    let gas = 21_000 + 64 + 1000 + 1500;
+    let resp = query(&ledger, 0x1001, gas, abi_call(0xb7ab4db5, &[]));
+    assert!(matches!(resp.evm_resp, ExitReason::Succeed(_)));
+    let resp = query(&ledger, 0x1001, gas - 1, abi_call(0xb7ab4db5, &[]));
+    assert!(!matches!(resp.evm_resp, ExitReason::Succeed(_)));
+
+    // getDelegation(address,address)
+    let input = abi_call(0x15049a5a, &[delegator.into(), validator.into()]);
+    let resp = query(&ledger, 0x1001, 100_000, input);
+    assert_eq!(word(1000).as_bytes(), resp.data.as_slice());
+
+    // getValidator(address) with dirty high bytes
+    let mut dirty = H256::from(validator);
+    dirty.0[11] = 1;
+    let resp = query(&ledger, 0x1001, 100_000, abi_call(0x1904bb2e, &[dirty]));
+    assert!(matches!(resp.evm_resp, ExitReason::Revert(_)));
+}
+
+static MIGRATED: AtomicUsize = AtomicUsize::new(0);
+
+fn migrate_v3(state: &ovr::ledger::State, b: vsdb::BranchName) -> ruc::Result<()> {
+    MIGRATED.fetch_add(1, Ordering::SeqCst);
+    state
+        .block_max_bytes
+        .set_value_by_branch(2000, b)
+        .map(|_| ())
+}
+
+#[test]
+fn test_run_registered_migration() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    upgrade::register_migration("v3", migrate_v3);
+
+    let plan = UpgradePlan {
+        name: String::from("v3"),
+        height: 3,
+        info: String::new(),
+    };
+    ledger
+        .state
+        .upgrade_plan
+        .set_value_by_branch(Some(plan), MAIN_BRANCH_NAME)
+        .unwrap();
+
+    // migrated in the 'BeginBlock' of height 3 instead of halting
+    for h in 1..=5 {
+        ledger.consensus_refresh(validator_id(0), h).unwrap();
+        assert!(ledger.upgrade().unwrap().is_none());
+        ledger.end_block().unwrap();
+        ledger.commit().unwrap();
+        assert_eq!(usize::from(3 <= h), MIGRATED.load(Ordering::SeqCst));
+    }
+
+    let state = &ledger.state;
+    assert_eq!(
+        Some(String::from("v3")),
+        state.chain_version.get_value_by_branch(MAIN_BRANCH_NAME)
+    );
+    assert_eq!(
+        Some(2000),
+        state.block_max_bytes.get_value_by_branch(MAIN_BRANCH_NAME)
+    );
+    assert!(
+        state
+            .upgrade_plan
+            .get_value_by_branch(MAIN_BRANCH_NAME)
+            .flatten()
+            .is_none()
+    );
+}
+
+// Runtime: `sstore(0, basefee())`
+const BASEFEE_CODE: &str = "6005600c60003960056000f34860005500";
+
+#[test]
+fn test_hardfork_schedule() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+    let evm = &ledger.state.evm;
+
+    let forks = [(4, Hardfork::London)].into_iter().collect();
+    evm.hardforks
+        .set_value_by_branch(forks, MAIN_BRANCH_NAME)
+        .unwrap();
+    assert_eq!(Hardfork::Istanbul, evm.hardfork(3, MAIN_BRANCH_NAME));
+    assert_eq!(Hardfork::London, evm.hardfork(4, MAIN_BRANCH_NAME));
+    assert_eq!(Hardfork::London, evm.hardfork(100, MAIN_BRANCH_NAME));
+
+    let sk = secret_key(25);
+    set_balance(&ledger, addr_of(&sk), *POWER_UNIT);
+    new_block(&ledger, vec![], vec![]);
+
+    // height 2
+    let contract = deploy(&ledger, &sk, 0, BASEFEE_CODE);
+    let call = |nonce| {
+        let action = TransactionAction::Call(contract);
+        evm_tx(&sk, nonce, action, U256::zero(), 15, 100_000, vec![])
+    };
+    let slot = || {
+        evm.OFUEL
+            .storages
+            .get_by_branch(&(&contract, &H256::zero()), MAIN_BRANCH_NAME)
+    };
+
+    // height 3, `BASEFEE` is an invalid opcode before London
+    new_block(&ledger, vec![call(1)], vec![]);
+    assert!(last_receipt(&ledger).is_none());
+    assert!(slot().is_none());
+
+    // height 4, London is activated
+    let base_fee = evm.base_fee(MAIN_BRANCH_NAME);
+    new_block(&ledger, vec![call(2)], vec![]);
+    assert!(last_receipt(&ledger).unwrap().status_code);
+    let mut w = [0; 32];
+    base_fee.to_big_endian(&mut w);
+    assert_eq!(Some(H256::from(w)), slot());
+}
+
+#[test]
+fn test_check_chain_id() {
+    let ledger = init_ledger();
+    let mut sb = StateBranch::new(&ledger.state, MAIN_BRANCH_NAME).unwrap();
+
+    let sk = secret_key(26);
+    let to = H160::repeat_byte(0xab);
+    set_balance(&ledger, addr_of(&sk), *POWER_UNIT);
+
+    let msg = |nonce: u64, chain_id| LegacyTransactionMessage {
+        nonce: nonce.into(),
+        gas_price: 15.into(),
+        gas_limit: 21_000.into(),
+        action: TransactionAction::Call(to),
+        value: 1000.into(),
+        input: vec![],
+        chain_id,
+    };
+    let reject_unprotected = |v| {
+        ledger
+            .state
+            .evm
+            .reject_unprotected_txs
+            .set_value_by_branch(v, MAIN_BRANCH_NAME)
+            .unwrap();
+    };
+
+    // signed for another chain
+    let tx = sign_legacy(&sk, msg(0, Some(CHAIN_ID + 1)));
+    assert!(sb.apply_tx(tx).is_err());
+    assert!(balance_of(&ledger, to).is_zero());
+
+    // the unprotected ones are accepted unless rejected explicitly
+    reject_unprotected(false);
+    sb.apply_tx(sign_legacy(&sk, msg(0, None))).unwrap();
+    assert_eq!(U256::from(1000), balance_of(&ledger, to));
+
+    reject_unprotected(true);
+    assert!(sb.apply_tx(sign_legacy(&sk, msg(1, None))).is_err());
+    assert_eq!(U256::from(1000), balance_of(&ledger, to));
+    sb.apply_tx(sign_legacy(&sk, msg(1, Some(CHAIN_ID))))
+        .unwrap();
+    assert_eq!(U256::from(2000), balance_of(&ledger, to));
+
+    // native transactions
+    let body = |chain_id| TxBody {
+        chain_id,
+        nonce: 2.into(),
+        gas_price: GAS_PRICE.into(),
+        op: Op::Transfer {
+            to,
+            amount: 1000.into(),
+        },
+    };
+    assert!(sb.apply_tx(sign_native(&sk, body(CHAIN_ID + 1))).is_err());
+    assert_eq!(U256::from(2000), balance_of(&ledger, to));
+    sb.apply_tx(sign_native(&sk, body(CHAIN_ID))).unwrap();
+    assert_eq!(U256::from(3000), balance_of(&ledger, to));
+}
+
+// The signed transaction in the example of EIP-155.
+const EIP155_TX: &str =
+    "f86c098504a817c80082520894353535353535353535353535353535353535353588\
+     0de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620\
+     aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+const EIP155_TX_HASH: &str =
+    "33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788";
+
+#[test]
+fn test_evm_tx_hash() {
+    let _lock = commit_lock();
+    let ledger = Ledger::new(
+        1,
+        String::from("TEST"),
+        String::from("1"),
+        Some(GAS_PRICE),
+        Some(3000000),
+        None,
+    )
+    .unwrap();
+
+    // signed by the key `0x4646..46`
+    let sk = secret_key(0x46);
+    set_balance(&ledger, addr_of(&sk), POWER_UNIT.saturating_mul(2.into()));
+    new_block(&ledger, vec![], vec![]);
+
+    let raw = hex::decode(EIP155_TX).unwrap();
+    let tx = rlp::decode::<LegacyTransaction>(&raw).unwrap();
+    let tx = Tx::Evm(EvmTx {
+        tx: TransactionAny::Legacy(tx),
+    });
+    new_block(&ledger, vec![tx], vec![]);
+
+    // keccak256 of the RLP encoding, the same as ethereum
+    let block = ledger.main.read().last_block().unwrap();
+    let expected = hex::decode(EIP155_TX_HASH).unwrap();
+    assert_eq!(expected, block.header.tx_merkle.leaves[0]);
+    let r = block.header.receipts.get(&expected).unwrap();
+    assert!(r.status_code);
+    assert_eq!(expected, r.tx_hash);
+    assert_eq!(Some(H160::repeat_byte(0x35)), r.to);
+}
+
+#[test]
+fn test_state_root_covers_params_and_allowances() {
+    let _lock = commit_lock();
+    let ledger = init_ledger();
+
+    let sk = secret_key(27);
+    let owner = addr_of(&sk);
+    let spender = H160::repeat_byte(0xac);
+    set_balance(&ledger, owner, *POWER_UNIT);
+
+    // approve(address,uint256)
+    let input = abi_call(0x095ea7b3, &[spender.into(), word(500)]);
+    new_block(&ledger, vec![ofuel_call(&ledger, &sk, 0, input)], vec![]);
+    assert!(last_receipt(&ledger).unwrap().status_code);
+
+    ledger
+        .state
+        .evm
+        .gas_price
+        .set_value_by_branch(20.into(), MAIN_BRANCH_NAME)
+        .unwrap();
+    new_block(&ledger, vec![], vec![]);
+
+    let root = last_state_root(&ledger);
+    let verify = |key, leaf| {
+        let proof = ledger.state.smt.proof(key, MAIN_BRANCH_NAME);
+        smt::verify(&root, &key, &leaf, &proof)
+    };
+
+    let key = smt::record_key(b"allowance", &(owner, spender));
+    assert!(verify(key, smt::record_leaf(&U256::from(500))));
+
+    let key = smt::record_key(b"param", &"gas_price");
+    assert!(verify(key, smt::record_leaf(&Some(U256::from(20)))));
+    assert!(!verify(key, smt::record_leaf(&Some(U256::from(10)))));
+
+    let supply = ledger.state.evm.OFUEL.total_supply(MAIN_BRANCH_NAME);
+    let key = smt::record_key(b"param", &"total_supply");
+    assert!(verify(key, smt::record_leaf(&Some(supply))));
+}